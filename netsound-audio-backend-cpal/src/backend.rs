@@ -9,7 +9,9 @@ use netsound_core::{audio_backend, log::no_scopes::debug};
 #[derivative(Debug)]
 pub struct Backend {
     pub(super) error_rx: control::Receiver<(&'static str, cpal::StreamError)>,
-    pub(super) drop_tx: futures::channel::oneshot::Sender<()>,
+    pub(super) drop_tx: Option<futures::channel::oneshot::Sender<()>>,
+    #[derivative(Debug = "ignore")]
+    pub(super) control_thread: Option<std::thread::JoinHandle<()>>,
     pub(super) logger: Logger,
 }
 
@@ -29,4 +31,19 @@ impl audio_backend::Backend for Backend {
             debug!(logger, "cpal backend errors stream closed");
         }
     }
+
+    fn stop(&mut self) {
+        let logger = &mut self.logger;
+
+        if let Some(drop_tx) = self.drop_tx.take() {
+            // The control thread is gone if it fails, nothing to stop then.
+            let _ = drop_tx.send(());
+        }
+        if let Some(control_thread) = self.control_thread.take() {
+            if control_thread.join().is_err() {
+                crit!(logger, "cpal control thread panicked");
+            }
+        }
+        debug!(logger, "cpal streams stopped");
+    }
 }
@@ -156,7 +156,7 @@ where
 
         let logger_clone = logger.clone();
 
-        std::thread::Builder::new()
+        let control_thread = std::thread::Builder::new()
             .name("netsound-audio-driver-cpal-control".into())
             .spawn(move || {
                 let logger = logger_clone;
@@ -219,7 +219,8 @@ where
 
         let backend = Backend {
             error_rx,
-            drop_tx,
+            drop_tx: Some(drop_tx),
+            control_thread: Some(control_thread),
             logger,
         };
         Ok(backend)
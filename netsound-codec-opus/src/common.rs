@@ -1,16 +1,16 @@
 use audiopus::TryFrom as AudiopusTryFrom;
 use netsound_core::pcm;
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 
 use crate::error;
 
+/// The sample rate of the `stream_config`, if opus supports it, and
+/// the amount of channels, which the coders check on their own.
 pub fn convert_params(
     stream_config: pcm::StreamConfig<f32>,
-) -> Result<(audiopus::SampleRate, audiopus::Channels), error::Init> {
+) -> Result<(audiopus::SampleRate, pcm::Channels), error::Init> {
     let sample_rate: usize = stream_config.sample_rate().into();
     #[allow(unstable_name_collisions)]
     let sample_rate = AudiopusTryFrom::try_from(i32::try_from(sample_rate)?)?;
-    let channels: i32 = stream_config.channels().try_into()?;
-    let channels = AudiopusTryFrom::try_from(channels)?;
-    Ok((sample_rate, channels))
+    Ok((sample_rate, stream_config.channels()))
 }
@@ -1,6 +1,5 @@
-use crate::{common::convert_params, error};
+use crate::{common::convert_params, error, multistream};
 use async_trait::async_trait;
-use audiopus::coder::Decoder as OpusDecoder;
 use netsound_core::io::{AsyncWriteItems, AsyncWriteItemsExt, WaitMode};
 use netsound_core::log::trace;
 use netsound_core::pcm;
@@ -8,9 +7,8 @@ use netsound_core::pcm;
 /// Opus decoder.
 #[derive(Debug)]
 pub struct Decoder {
-    pub(super) opus: OpusDecoder,
+    pub(super) opus: multistream::Decoder,
     pub(super) buf: Box<[f32]>,
-    pub(super) fec: bool,
     pub(super) channels: usize,
 }
 
@@ -26,18 +24,17 @@ impl Decoder {
         buf: Box<[f32]>,
     ) -> Result<Self, error::Init> {
         let (sample_rate, channels) = convert_params(stream_config)?;
-        let dec = audiopus::coder::Decoder::new(sample_rate, channels)?;
+        let dec = multistream::Decoder::new(sample_rate, channels)?;
         Ok(Self {
             opus: dec,
             buf,
-            fec: false,
-            channels: (channels as usize),
+            channels,
         })
     }
 
     async fn decode_float<T>(
         &mut self,
-        input: &[u8],
+        input: Option<&[u8]>,
         output: &mut T,
         fec: bool,
     ) -> Result<usize, error::Op>
@@ -45,9 +42,22 @@ impl Decoder {
         T: AsyncWriteItems<f32> + Unpin,
     {
         let audiosize = {
-            let buf = &mut self.buf[..];
+            let buf = if input.is_some() && !fec {
+                &mut self.buf[..]
+            } else {
+                // When concealing or recovering the lost packet, opus decides
+                // how much audio to produce by the size of the output, so we
+                // ask for as much as the last packet had.
+                let last_packet_samples =
+                    self.opus.last_packet_duration()? as usize * self.channels;
+                let size = std::cmp::min(last_packet_samples, self.buf.len());
+                &mut self.buf[..size]
+            };
+            if buf.is_empty() {
+                return Ok(0);
+            }
             trace!("opus: decoding buf {}", buf.len());
-            self.opus.decode_float(Some(input), buf, fec)?
+            self.opus.decode_float(input, buf, fec)?
         };
         let bufsize = audiosize * self.channels;
         let size = output
@@ -67,7 +77,30 @@ where
         input: &[u8],
         output: &mut T,
     ) -> Result<usize, netsound_core::codec::error::Decoding> {
-        self.decode_float(input, output, self.fec)
+        self.decode_float(Some(input), output, false)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Conceal the lost packet with the opus packet loss concealment.
+    async fn conceal(
+        &mut self,
+        output: &mut T,
+    ) -> Result<usize, netsound_core::codec::error::Decoding> {
+        self.decode_float(None, output, false)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Recover the lost packet from the in-band forward error correction
+    /// data of the next packet. If the next packet has no such data, opus
+    /// falls back to the packet loss concealment.
+    async fn recover(
+        &mut self,
+        next_input: &[u8],
+        output: &mut T,
+    ) -> Result<usize, netsound_core::codec::error::Decoding> {
+        self.decode_float(Some(next_input), output, true)
             .await
             .map_err(Into::into)
     }
@@ -1,19 +1,23 @@
-use crate::{common::convert_params, error};
+use crate::{common::convert_params, error, meta, multistream, Settings};
 use async_trait::async_trait;
-use audiopus::coder::Encoder as OpusEncoder;
+use audiopus::Bitrate;
+use netsound_core::codec::{Adaptation, Encoded};
 use netsound_core::io::{AsyncReadItems, AsyncReadItemsExt, WaitMode};
 use netsound_core::log::trace;
 use netsound_core::pcm;
+use std::convert::TryFrom;
+use std::time::Duration;
 
 /// Opus encoder.
 #[derive(Debug)]
 pub struct Encoder {
-    pub(super) opus: OpusEncoder,
+    pub(super) opus: multistream::Encoder,
     pub(super) buf: Box<[f32]>,
 }
 
 impl Encoder {
-    /// Create a new [`Encoder`] with the specified params.
+    /// Create a new [`Encoder`] with the specified params and the default
+    /// [`Settings`].
     ///
     /// # Errors
     ///
@@ -22,18 +26,80 @@ impl Encoder {
     pub fn new(
         stream_config: pcm::StreamConfig<f32>,
         buf: Box<[f32]>,
+    ) -> Result<Self, error::Init> {
+        Self::with_settings(stream_config, buf, &Settings::default())
+    }
+
+    /// Create a new [`Encoder`] with the specified params and `settings`.
+    /// The `buf` must hold a single frame of the settings' frame duration,
+    /// if set.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the parameters validation fails, opus doesn't support
+    /// the frame duration, the `buf` doesn't hold a single frame of it, or
+    /// underlying opus codec library returns an error.
+    pub fn with_settings(
+        stream_config: pcm::StreamConfig<f32>,
+        buf: Box<[f32]>,
+        settings: &Settings,
     ) -> Result<Self, error::Init> {
         let (sample_rate, channels) = convert_params(stream_config)?;
-        let enc =
-            audiopus::coder::Encoder::new(sample_rate, channels, audiopus::Application::Audio)?;
+        if let Some(frame_duration) = settings.frame_duration {
+            if !meta::FRAME_DURATIONS_US
+                .iter()
+                .any(|&duration_us| Duration::from_micros(duration_us) == frame_duration)
+            {
+                return Err(error::Init::FrameDuration(frame_duration));
+            }
+            let frame_size =
+                meta::compute_frame_buf_size(channels, stream_config.sample_rate(), frame_duration);
+            if buf.len() != frame_size {
+                return Err(error::Init::FrameSize {
+                    buf_size: buf.len(),
+                    frame_duration,
+                });
+            }
+        }
+        let mut enc =
+            multistream::Encoder::new(sample_rate, channels, settings.application.into())?;
+        settings.apply(&mut enc)?;
         Ok(Self { opus: enc, buf })
     }
 
+    /// Enable the in-band forward error correction, tuned for the specified
+    /// expected packet loss percentage.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the underlying opus codec library returns an error.
+    pub fn enable_fec(&mut self, expected_packet_loss_perc: u8) -> Result<(), error::Init> {
+        self.opus.set_inband_fec(true)?;
+        self.opus.set_packet_loss_perc(expected_packet_loss_perc)?;
+        Ok(())
+    }
+
+    /// Set the target bitrate, the expected packet loss and the forward
+    /// error correction from the `adaptation`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the bitrate is out of range or the underlying opus codec
+    /// library returns an error.
+    pub fn adapt(&mut self, adaptation: &Adaptation) -> Result<(), error::Op> {
+        let bitrate = i32::try_from(adaptation.bitrate)?;
+        self.opus.set_bitrate(Bitrate::BitsPerSecond(bitrate))?;
+        self.opus.set_inband_fec(adaptation.fec)?;
+        self.opus
+            .set_packet_loss_perc(adaptation.expected_packet_loss_perc.min(100))?;
+        Ok(())
+    }
+
     async fn encode_float<T>(
         &mut self,
         input: &mut T,
         output: &mut [u8],
-    ) -> Result<usize, error::Op>
+    ) -> Result<Encoded, error::Op>
     where
         T: AsyncReadItems<f32> + Unpin,
     {
@@ -42,7 +108,10 @@ impl Encoder {
             .await?;
         trace!("opus: encoding buf {}", self.buf.len());
         let bytes_written = self.opus.encode_float(&self.buf, output)?;
-        Ok(bytes_written)
+        Ok(Encoded {
+            bytes: bytes_written,
+            samples: self.buf.len(),
+        })
     }
 }
 
@@ -55,7 +124,15 @@ where
         &mut self,
         input: &mut T,
         output: &mut [u8],
-    ) -> Result<usize, netsound_core::codec::error::Encoding> {
+    ) -> Result<Encoded, netsound_core::codec::error::Encoding> {
         self.encode_float(input, output).await.map_err(Into::into)
     }
+
+    fn adapt(
+        &mut self,
+        adaptation: &Adaptation,
+    ) -> Result<bool, netsound_core::codec::error::Encoding> {
+        Encoder::adapt(self, adaptation)?;
+        Ok(true)
+    }
 }
@@ -11,6 +11,36 @@ pub enum Init {
     /// Underlying opus library error.
     #[error("opus error: {0}")]
     Opus(#[from] audiopus::Error),
+    /// The frame duration opus doesn't support.
+    #[error("unsupported frame duration: {0:?}")]
+    FrameDuration(std::time::Duration),
+    /// The buffer doesn't hold a single frame of the configured duration.
+    #[error("the buffer of {buf_size} samples doesn't hold a single frame of {frame_duration:?}")]
+    FrameSize {
+        /// The size of the buffer passed to the codec.
+        buf_size: usize,
+        /// The configured frame duration.
+        frame_duration: std::time::Duration,
+    },
+}
+
+/// An invalid value of an encoder setting.
+#[derive(Error, Debug)]
+#[error("invalid {setting}: {value:?}")]
+pub struct ParseSetting {
+    /// The name of the setting.
+    pub setting: &'static str,
+    /// The value that failed to parse.
+    pub value: String,
+}
+
+impl ParseSetting {
+    pub(crate) fn new(setting: &'static str, value: &str) -> Self {
+        Self {
+            setting,
+            value: value.to_owned(),
+        }
+    }
 }
 
 /// An error that can occur during codec operation.
@@ -30,6 +60,9 @@ pub enum Op {
     /// IO error while operating with the samples.
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
+    /// Integer conversion failed.
+    #[error("try from int error: {0}")]
+    TryFromInt(#[from] std::num::TryFromIntError),
 }
 
 impl From<Op> for netsound_core::codec::error::Encoding {
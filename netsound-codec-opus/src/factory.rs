@@ -0,0 +1,108 @@
+use crate::{meta, Decoder, Encoder, Settings};
+use netsound_core::codec::registry::{self, Capabilities, DynDecoder, DynEncoder};
+use netsound_core::log::warn;
+use netsound_core::pcm;
+use std::time::Duration;
+
+/// The name of the codec in the handshake.
+pub const NAME: &str = "opus";
+
+/// The opus codec, registered with the encoder `settings`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Factory {
+    /// The encoder settings.
+    pub settings: Settings,
+}
+
+impl Factory {
+    /// Create a new [`Factory`] building the encoders with the `settings`.
+    #[must_use]
+    pub fn new(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    /// The highest bitrate the packets have to fit at.
+    fn max_bitrate(&self, settings: &registry::Settings) -> u32 {
+        settings
+            .max_bitrate
+            .or(self.settings.bitrate)
+            .unwrap_or(meta::MAX_BITRATE)
+    }
+
+    /// The configured frame duration, or the longest one at which
+    /// the frames fit a packet even at the highest bitrate we may go up to.
+    fn frame_duration(&self, settings: &registry::Settings) -> Duration {
+        self.settings.frame_duration.unwrap_or_else(|| {
+            meta::frame_duration_to_fit(self.max_bitrate(settings), settings.max_packet_size)
+        })
+    }
+}
+
+impl registry::Factory for Factory {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            sample_rates: meta::SUPPORTED_SAMPLE_RATES.to_vec(),
+            max_channels: meta::MAX_CHANNELS,
+        }
+    }
+
+    fn supports_fec(&self) -> bool {
+        true
+    }
+
+    fn frame_samples(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        settings: &registry::Settings,
+    ) -> usize {
+        let frame_duration = self.frame_duration(settings);
+        let max_bitrate = self.max_bitrate(settings);
+        if frame_duration > meta::frame_duration_to_fit(max_bitrate, settings.max_packet_size) {
+            warn!(
+                "The opus frames of {:?} may not fit {} bytes at {} bps, \
+                 the encoder lowers the bitrate of the ones that don't",
+                frame_duration, settings.max_packet_size, max_bitrate
+            );
+        }
+        meta::compute_frame_buf_size(
+            stream_config.channels(),
+            stream_config.sample_rate(),
+            frame_duration,
+        )
+    }
+
+    fn encoder(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        settings: &registry::Settings,
+    ) -> Result<Box<DynEncoder>, netsound_core::Error> {
+        let buf_size = meta::compute_frame_buf_size(
+            stream_config.channels(),
+            stream_config.sample_rate(),
+            self.frame_duration(settings),
+        );
+        let mut encoder =
+            Encoder::with_settings(stream_config, vec![0.0; buf_size].into(), &self.settings)?;
+        if let Some(expected_packet_loss) = settings.fec_expected_packet_loss {
+            encoder.enable_fec(expected_packet_loss)?;
+        }
+        Ok(Box::new(encoder))
+    }
+
+    fn decoder(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        _settings: &registry::Settings,
+    ) -> Result<Box<DynDecoder>, netsound_core::Error> {
+        let buf_size =
+            meta::compute_required_buf_size(stream_config.channels(), stream_config.sample_rate());
+        Ok(Box::new(Decoder::new(
+            stream_config,
+            vec![0.0; buf_size].into(),
+        )?))
+    }
+}
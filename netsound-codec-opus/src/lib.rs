@@ -8,9 +8,14 @@ mod common;
 mod decoder;
 mod encoder;
 pub mod error;
+mod factory;
 mod meta;
+mod multistream;
+mod settings;
 
 pub use decoder::Decoder;
 pub use encoder::Encoder;
+pub use factory::{Factory, NAME};
 
 pub use meta::*;
+pub use settings::{parse_frame_duration, Application, Settings, Signal, Vbr};
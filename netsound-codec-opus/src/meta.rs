@@ -1,4 +1,25 @@
 use netsound_core::pcm;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// The sample rates opus can run at, from the best to the worst.
+pub const SUPPORTED_SAMPLE_RATES: [u32; 5] = [48000, 24000, 16000, 12000, 8000];
+
+/// The max amount of channels we carry, in the standard surround layouts
+/// beyond stereo, up to 7.1.
+pub const MAX_CHANNELS: u16 = 8;
+
+/// The lowest bitrate opus can encode at, in bits per second.
+pub const MIN_BITRATE: u32 = 500;
+
+/// The highest bitrate opus can encode at, in bits per second.
+pub const MAX_BITRATE: u32 = 512_000;
+
+/// The frame durations opus can encode, in microseconds, from the longest
+/// to the shortest.
+pub(crate) const FRAME_DURATIONS_US: [u64; 9] = [
+    120_000, 100_000, 80_000, 60_000, 40_000, 20_000, 10_000, 5_000, 2_500,
+];
 
 /// Compute the required buffer size *in samples count* to accomodate
 /// the raw PCM samples under the specified parameters.
@@ -11,3 +32,33 @@ pub fn compute_required_buf_size(
     // We divide by 1000 to account for samples_per_second to ms conversion.
     channels * samples_per_second.as_usize() * 120 / 1000
 }
+
+/// Pick the longest frame duration at which the packets encoded at
+/// the `max_bitrate` still fit `max_packet_size` bytes. If even the shortest
+/// frames don't fit, the encoder lowers the bitrate to make them fit.
+#[must_use]
+pub fn frame_duration_to_fit(max_bitrate: u32, max_packet_size: usize) -> Duration {
+    let fits = |duration_us: u64| {
+        let bytes = u64::from(max_bitrate) * duration_us / 8 / 1_000_000;
+        usize::try_from(bytes).map_or(false, |bytes| bytes <= max_packet_size)
+    };
+    let duration_us = FRAME_DURATIONS_US
+        .iter()
+        .copied()
+        .find(|&duration_us| fits(duration_us))
+        .unwrap_or(FRAME_DURATIONS_US[FRAME_DURATIONS_US.len() - 1]);
+    Duration::from_micros(duration_us)
+}
+
+/// Compute the buffer size *in samples count* to accomodate a single frame
+/// of the specified `duration`.
+#[must_use]
+pub fn compute_frame_buf_size(
+    channels: pcm::Channels,
+    samples_per_second: pcm::SampleRate,
+    duration: Duration,
+) -> usize {
+    #[allow(clippy::cast_possible_truncation)]
+    let duration_us = duration.as_micros() as usize;
+    channels * samples_per_second.as_usize() * duration_us / 1_000_000
+}
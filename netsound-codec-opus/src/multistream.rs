@@ -0,0 +1,507 @@
+//! The multistream opus coders, which carry up to eight channels.
+//!
+//! Up to two channels, the packets are the same as the ones of the plain
+//! opus coders. Beyond that, the channels are coded with the Vorbis channel
+//! mapping, the mapping family 1 of the RFC 7845, which lets the encoder
+//! allocate the bitrate by the surround roles of the channels. The samples
+//! in the pipeline are in the WAVE channel order, the one the audio devices
+//! use, so we reorder them on the way in and out.
+
+use audiopus::{ffi, Error, ErrorCode};
+use std::convert::TryFrom;
+use std::fmt;
+use std::os::raw::c_int;
+use std::ptr::{self, NonNull};
+
+use crate::meta::MAX_CHANNELS;
+
+/// The amount of the streams, the amount of the coupled ones, and the coded
+/// channel of every channel in the Vorbis order, by the amount of channels.
+/// See the RFC 7845, section 5.1.1.2.
+const VORBIS_LAYOUTS: [(c_int, c_int, &[u8]); MAX_CHANNELS as usize] = [
+    (1, 0, &[0]),
+    (1, 1, &[0, 1]),
+    (2, 1, &[0, 2, 1]),
+    (2, 2, &[0, 1, 2, 3]),
+    (3, 2, &[0, 4, 1, 2, 3]),
+    (4, 2, &[0, 4, 1, 2, 3, 5]),
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+];
+
+/// The position in the Vorbis order of every channel in the WAVE order, by
+/// the amount of channels.
+const WAVE_TO_VORBIS: [&[usize]; MAX_CHANNELS as usize] = [
+    &[0],
+    &[0, 1],
+    // L R C
+    &[0, 2, 1],
+    // FL FR BL BR
+    &[0, 1, 2, 3],
+    // FL FR C BL BR
+    &[0, 2, 1, 3, 4],
+    // FL FR C LFE BL BR
+    &[0, 2, 1, 5, 3, 4],
+    // FL FR C LFE BC SL SR
+    &[0, 2, 1, 6, 5, 3, 4],
+    // FL FR C LFE BL BR SL SR
+    &[0, 2, 1, 7, 5, 6, 3, 4],
+];
+
+/// The opus mapping family: the plain one up to stereo, the Vorbis one
+/// beyond.
+fn mapping_family(channels: usize) -> c_int {
+    match channels {
+        1 | 2 => 0,
+        _ => 1,
+    }
+}
+
+fn check_channels(channels: usize) -> Result<c_int, Error> {
+    match c_int::try_from(channels) {
+        Ok(count) if (1..=usize::from(MAX_CHANNELS)).contains(&channels) => Ok(count),
+        _ => Err(Error::InvalidChannels(
+            c_int::try_from(channels).unwrap_or(c_int::MAX),
+        )),
+    }
+}
+
+/// Turn the return code of libopus into the result.
+fn check(code: c_int) -> Result<c_int, Error> {
+    if code < 0 {
+        Err(Error::Opus(ErrorCode::from(code)))
+    } else {
+        Ok(code)
+    }
+}
+
+fn len_c_int(len: usize) -> c_int {
+    c_int::try_from(len).unwrap_or(c_int::MAX)
+}
+
+/// The multistream opus encoder.
+pub(crate) struct Encoder {
+    state: NonNull<ffi::OpusMSEncoder>,
+    channels: usize,
+    /// The frame in the Vorbis order.
+    reordered: Vec<f32>,
+}
+
+// The encoder state is only ever touched through the exclusive reference.
+unsafe impl Send for Encoder {}
+
+impl fmt::Debug for Encoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encoder")
+            .field("channels", &self.channels)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Encoder {
+    pub fn new(
+        sample_rate: audiopus::SampleRate,
+        channels: usize,
+        application: audiopus::Application,
+    ) -> Result<Self, Error> {
+        let channel_count = check_channels(channels)?;
+        let mut streams = 0;
+        let mut coupled_streams = 0;
+        let mut mapping = [0; MAX_CHANNELS as usize];
+        let mut error = 0;
+        // SAFETY: the mapping has room for all the channels, which is what
+        // the encoder writes to it.
+        let state = unsafe {
+            ffi::opus_multistream_surround_encoder_create(
+                sample_rate as i32,
+                channel_count,
+                mapping_family(channels),
+                &mut streams,
+                &mut coupled_streams,
+                mapping.as_mut_ptr(),
+                application as c_int,
+                &mut error,
+            )
+        };
+        check(error)?;
+        let state = NonNull::new(state).ok_or(Error::Opus(ErrorCode::AllocFail))?;
+        Ok(Self {
+            state,
+            channels,
+            reordered: Vec::new(),
+        })
+    }
+
+    fn set(&mut self, request: c_int, value: c_int) -> Result<(), Error> {
+        // SAFETY: all the set requests we make take a single int.
+        check(unsafe { ffi::opus_multistream_encoder_ctl(self.state.as_ptr(), request, value) })?;
+        Ok(())
+    }
+
+    /// Set the bitrate of all the streams together.
+    pub fn set_bitrate(&mut self, bitrate: audiopus::Bitrate) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_BITRATE_REQUEST, bitrate.into())
+    }
+
+    pub fn set_complexity(&mut self, complexity: u8) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_COMPLEXITY_REQUEST, complexity.into())
+    }
+
+    pub fn set_vbr(&mut self, vbr: bool) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_VBR_REQUEST, vbr.into())
+    }
+
+    pub fn set_vbr_constraint(&mut self, constrained: bool) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_VBR_CONSTRAINT_REQUEST, constrained.into())
+    }
+
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_DTX_REQUEST, dtx.into())
+    }
+
+    pub fn set_signal(&mut self, signal: audiopus::Signal) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_SIGNAL_REQUEST, signal as c_int)
+    }
+
+    pub fn set_inband_fec(&mut self, fec: bool) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_INBAND_FEC_REQUEST, fec.into())
+    }
+
+    pub fn set_packet_loss_perc(&mut self, percentage: u8) -> Result<(), Error> {
+        self.set(ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST, percentage.into())
+    }
+
+    /// Encode the interleaved `input` in the WAVE order to the `output`, and
+    /// return the size of the packet.
+    pub fn encode_float(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, Error> {
+        let order = WAVE_TO_VORBIS[self.channels - 1];
+        self.reordered.resize(input.len(), 0.0);
+        for (frame, reordered) in input
+            .chunks_exact(self.channels)
+            .zip(self.reordered.chunks_exact_mut(self.channels))
+        {
+            for (&sample, &position) in frame.iter().zip(order) {
+                reordered[position] = sample;
+            }
+        }
+        // SAFETY: the input holds the given amount of whole frames, and
+        // the output holds the given amount of bytes.
+        let len = check(unsafe {
+            ffi::opus_multistream_encode_float(
+                self.state.as_ptr(),
+                self.reordered.as_ptr(),
+                len_c_int(input.len() / self.channels),
+                output.as_mut_ptr(),
+                len_c_int(output.len()),
+            )
+        })?;
+        Ok(usize::try_from(len).unwrap_or_default())
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        // SAFETY: the state was created by the matching create call, and is
+        // not used after this.
+        unsafe { ffi::opus_multistream_encoder_destroy(self.state.as_ptr()) }
+    }
+}
+
+/// The multistream opus decoder.
+pub(crate) struct Decoder {
+    state: NonNull<ffi::OpusMSDecoder>,
+    channels: usize,
+}
+
+// The decoder state is only ever touched through the exclusive reference.
+unsafe impl Send for Decoder {}
+
+impl fmt::Debug for Decoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Decoder")
+            .field("channels", &self.channels)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Decoder {
+    pub fn new(sample_rate: audiopus::SampleRate, channels: usize) -> Result<Self, Error> {
+        check_channels(channels)?;
+        let vorbis_mapping = VORBIS_LAYOUTS[channels - 1].2;
+        // Decode every channel right into its place in the WAVE order.
+        let mapping: Vec<u8> = WAVE_TO_VORBIS[channels - 1]
+            .iter()
+            .map(|&position| vorbis_mapping[position])
+            .collect();
+        Self::with_mapping(sample_rate, &mapping)
+    }
+
+    /// Create the decoder of the Vorbis layout of as many channels as there
+    /// are in the `mapping`, writing them in the order of their coded
+    /// channels in the `mapping`.
+    fn with_mapping(sample_rate: audiopus::SampleRate, mapping: &[u8]) -> Result<Self, Error> {
+        let channel_count = check_channels(mapping.len())?;
+        let (streams, coupled_streams, _) = VORBIS_LAYOUTS[mapping.len() - 1];
+        let mut error = 0;
+        // SAFETY: the mapping holds a coded channel for every channel.
+        let state = unsafe {
+            ffi::opus_multistream_decoder_create(
+                sample_rate as i32,
+                channel_count,
+                streams,
+                coupled_streams,
+                mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        check(error)?;
+        let state = NonNull::new(state).ok_or(Error::Opus(ErrorCode::AllocFail))?;
+        Ok(Self {
+            state,
+            channels: mapping.len(),
+        })
+    }
+
+    /// Decode the `input` packet to the interleaved `output`, or conceal
+    /// the lost packet if there's no `input`, and return the amount of
+    /// the decoded samples per channel.
+    pub fn decode_float(
+        &mut self,
+        input: Option<&[u8]>,
+        output: &mut [f32],
+        fec: bool,
+    ) -> Result<usize, Error> {
+        let (data, len) = match input {
+            Some([]) => return Err(Error::EmptyPacket),
+            Some(input) => (input.as_ptr(), len_c_int(input.len())),
+            None => (ptr::null(), 0),
+        };
+        // SAFETY: the input holds the given amount of bytes, and the output
+        // holds the given amount of whole frames.
+        let samples = check(unsafe {
+            ffi::opus_multistream_decode_float(
+                self.state.as_ptr(),
+                data,
+                len,
+                output.as_mut_ptr(),
+                len_c_int(output.len() / self.channels),
+                fec.into(),
+            )
+        })?;
+        Ok(usize::try_from(samples).unwrap_or_default())
+    }
+
+    /// The duration of the last decoded packet, in samples per channel.
+    pub fn last_packet_duration(&mut self) -> Result<u32, Error> {
+        let mut duration: c_int = 0;
+        // SAFETY: the request takes the pointer to an int to write to.
+        check(unsafe {
+            ffi::opus_multistream_decoder_ctl(
+                self.state.as_ptr(),
+                ffi::OPUS_GET_LAST_PACKET_DURATION_REQUEST,
+                ptr::addr_of_mut!(duration),
+            )
+        })?;
+        Ok(u32::try_from(duration).unwrap_or_default())
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        // SAFETY: the state was created by the matching create call, and is
+        // not used after this.
+        unsafe { ffi::opus_multistream_decoder_destroy(self.state.as_ptr()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The samples per channel of a 20 ms frame at 48 kHz.
+    const FRAME: usize = 960;
+
+    /// The RMS of every channel of the interleaved `samples`.
+    #[allow(clippy::cast_precision_loss)]
+    fn rms(samples: &[f32], channels: usize) -> Vec<f32> {
+        (0..channels)
+            .map(|channel| {
+                let energy: f32 = samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .map(|sample| sample * sample)
+                    .sum();
+                (energy / (samples.len() / channels) as f32).sqrt()
+            })
+            .collect()
+    }
+
+    /// Encode a second of the `signal` of `channels` channels, telling
+    /// the sample of every channel at every point in time, and return
+    /// the RMS of every channel of the last decoded frame, in the WAVE
+    /// order and in the Vorbis order.
+    fn round_trip(channels: usize, signal: impl Fn(usize, usize) -> f32) -> (Vec<f32>, Vec<f32>) {
+        let sample_rate = audiopus::SampleRate::Hz48000;
+        let mut encoder =
+            Encoder::new(sample_rate, channels, audiopus::Application::Audio).unwrap();
+        let mut decoder = Decoder::new(sample_rate, channels).unwrap();
+        let mut vorbis_decoder =
+            Decoder::with_mapping(sample_rate, VORBIS_LAYOUTS[channels - 1].2).unwrap();
+        let mut input = vec![0.0; FRAME * channels];
+        let mut packet = vec![0; 4000];
+        let mut output = vec![0.0; FRAME * channels];
+        let mut vorbis_output = vec![0.0; FRAME * channels];
+        for frame in 0..50 {
+            for (position, sample) in input.iter_mut().enumerate() {
+                *sample = signal(position % channels, frame * FRAME + position / channels);
+            }
+            let len = encoder.encode_float(&input, &mut packet).unwrap();
+            for (decoder, output) in [
+                (&mut decoder, &mut output),
+                (&mut vorbis_decoder, &mut vorbis_output),
+            ] {
+                let samples = decoder
+                    .decode_float(Some(&packet[..len]), output, false)
+                    .unwrap();
+                assert_eq!(samples, FRAME);
+            }
+        }
+        (rms(&output, channels), rms(&vorbis_output, channels))
+    }
+
+    /// The tone of the `frequency`, at the `sample` at 48 kHz.
+    #[allow(clippy::cast_precision_loss)]
+    fn tone(frequency: f32, sample: usize) -> f32 {
+        (2.0 * std::f32::consts::PI * frequency * sample as f32 / 48000.0).sin()
+    }
+
+    #[test]
+    fn layouts_match_libopus() {
+        for channels in 1..=usize::from(MAX_CHANNELS) {
+            let mut streams = 0;
+            let mut coupled_streams = 0;
+            let mut mapping = [0; MAX_CHANNELS as usize];
+            let mut error = 0;
+            // SAFETY: the mapping has room for all the channels, and
+            // the state is destroyed right away.
+            unsafe {
+                let state = ffi::opus_multistream_surround_encoder_create(
+                    48000,
+                    check_channels(channels).unwrap(),
+                    mapping_family(channels),
+                    &mut streams,
+                    &mut coupled_streams,
+                    mapping.as_mut_ptr(),
+                    audiopus::Application::Audio as c_int,
+                    &mut error,
+                );
+                check(error).unwrap();
+                ffi::opus_multistream_encoder_destroy(state);
+            }
+            let (expected_streams, expected_coupled_streams, expected_mapping) =
+                VORBIS_LAYOUTS[channels - 1];
+            assert_eq!(streams, expected_streams, "{channels} channels");
+            assert_eq!(
+                coupled_streams, expected_coupled_streams,
+                "{channels} channels"
+            );
+            assert_eq!(
+                &mapping[..channels],
+                expected_mapping,
+                "{channels} channels"
+            );
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        for channels in [1, 2, 6, 8] {
+            // Every channel at its own level, so that the mixed up ones show.
+            #[allow(clippy::cast_precision_loss)]
+            let level = |channel: usize| 0.1 * (channel + 1) as f32;
+            let (rms, _) = round_trip(channels, |channel, sample| {
+                level(channel) * tone(100.0, sample)
+            });
+            for (channel, rms) in rms.into_iter().enumerate() {
+                let expected = level(channel) * std::f32::consts::FRAC_1_SQRT_2;
+                assert!(
+                    (rms - expected).abs() < expected * 0.2,
+                    "{} channels: channel {} came out at {} instead of {}",
+                    channels,
+                    channel,
+                    rms,
+                    expected
+                );
+            }
+        }
+    }
+
+    /// The channels of the layouts beyond stereo, in the WAVE order and in
+    /// the Vorbis order, see the RFC 7845, section 5.1.1.2.
+    const LAYOUTS: [(&[&str], &[&str]); 6] = [
+        (&["FL", "FR", "C"], &["FL", "C", "FR"]),
+        (&["FL", "FR", "BL", "BR"], &["FL", "FR", "BL", "BR"]),
+        (
+            &["FL", "FR", "C", "BL", "BR"],
+            &["FL", "C", "FR", "BL", "BR"],
+        ),
+        (
+            &["FL", "FR", "C", "LFE", "BL", "BR"],
+            &["FL", "C", "FR", "BL", "BR", "LFE"],
+        ),
+        (
+            &["FL", "FR", "C", "LFE", "BC", "SL", "SR"],
+            &["FL", "C", "FR", "SL", "SR", "BC", "LFE"],
+        ),
+        (
+            &["FL", "FR", "C", "LFE", "BL", "BR", "SL", "SR"],
+            &["FL", "C", "FR", "SL", "SR", "BL", "BR", "LFE"],
+        ),
+    ];
+
+    /// Check that the tone comes out in the `expected` channel alone.
+    fn assert_alone(rms: &[f32], expected: usize, what: &str) {
+        for (channel, &rms_of_channel) in rms.iter().enumerate() {
+            if channel == expected {
+                assert!(
+                    rms_of_channel > 0.25,
+                    "{}: came out at {} in channel {}",
+                    what,
+                    rms_of_channel,
+                    channel
+                );
+            } else {
+                assert!(
+                    rms_of_channel < 0.05,
+                    "{}: leaked {} into channel {}",
+                    what,
+                    rms_of_channel,
+                    channel
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_channel_order() {
+        for (wave, vorbis) in LAYOUTS {
+            let channels = wave.len();
+            for (source, name) in wave.iter().enumerate() {
+                // The LFE only carries the lowest frequencies.
+                let frequency = if *name == "LFE" { 60.0 } else { 400.0 };
+                let (rms, vorbis_rms) = round_trip(channels, |channel, sample| {
+                    if channel == source {
+                        0.5 * tone(frequency, sample)
+                    } else {
+                        0.0
+                    }
+                });
+                let what = format!("{name} of {channels} channels");
+                assert_alone(&rms, source, &what);
+                let coded = vorbis.iter().position(|coded| coded == name).unwrap();
+                assert_alone(&vorbis_rms, coded, &what);
+            }
+        }
+    }
+}
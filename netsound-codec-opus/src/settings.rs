@@ -0,0 +1,177 @@
+//! The encoder settings.
+
+use crate::{error, meta::FRAME_DURATIONS_US, multistream};
+use audiopus::Bitrate;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What the encoder is tuned for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Application {
+    /// The best intelligibility of the speech.
+    Voip,
+    /// The best fidelity of the music and the mixed content.
+    Audio,
+    /// The lowest delay, at the cost of the speech optimizations.
+    LowDelay,
+}
+
+/// How the bitrate varies from one frame to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vbr {
+    /// The bitrate follows the complexity of the signal.
+    On,
+    /// The bitrate varies, but stays close to the target over every frame.
+    Constrained,
+    /// The constant bitrate.
+    Off,
+}
+
+/// The hint about the kind of the signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Let the encoder figure it out.
+    Auto,
+    /// The speech.
+    Voice,
+    /// The music.
+    Music,
+}
+
+/// The encoder settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// What the encoder is tuned for.
+    pub application: Application,
+    /// The target bitrate in bits per second, picked by the encoder if not
+    /// set.
+    pub bitrate: Option<u32>,
+    /// The computational complexity, from 0 to 10, picked by the encoder if
+    /// not set.
+    pub complexity: Option<u8>,
+    /// How the bitrate varies.
+    pub vbr: Vbr,
+    /// The discontinuous transmission, sending the tiny packets during
+    /// the silence.
+    pub dtx: bool,
+    /// The hint about the kind of the signal.
+    pub signal: Signal,
+    /// The duration of a single frame, one of the durations opus supports.
+    /// The longest one that fits the packet if not set.
+    pub frame_duration: Option<Duration>,
+}
+
+impl Default for Settings {
+    /// The libopus defaults, tuned for the music.
+    fn default() -> Self {
+        Self {
+            application: Application::Audio,
+            bitrate: None,
+            complexity: None,
+            vbr: Vbr::Constrained,
+            dtx: false,
+            signal: Signal::Auto,
+            frame_duration: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Apply the settings, except for the application and the frame
+    /// duration, to the `opus` encoder.
+    pub(crate) fn apply(&self, opus: &mut multistream::Encoder) -> Result<(), error::Init> {
+        if let Some(bitrate) = self.bitrate {
+            opus.set_bitrate(Bitrate::BitsPerSecond(i32::try_from(bitrate)?))?;
+        }
+        if let Some(complexity) = self.complexity {
+            opus.set_complexity(complexity)?;
+        }
+        opus.set_vbr(self.vbr != Vbr::Off)?;
+        if self.vbr != Vbr::Off {
+            opus.set_vbr_constraint(self.vbr == Vbr::Constrained)?;
+        }
+        opus.set_dtx(self.dtx)?;
+        opus.set_signal(self.signal.into())?;
+        Ok(())
+    }
+}
+
+impl From<Application> for audiopus::Application {
+    fn from(application: Application) -> Self {
+        match application {
+            Application::Voip => audiopus::Application::Voip,
+            Application::Audio => audiopus::Application::Audio,
+            Application::LowDelay => audiopus::Application::LowDelay,
+        }
+    }
+}
+
+impl From<Signal> for audiopus::Signal {
+    fn from(signal: Signal) -> Self {
+        match signal {
+            Signal::Auto => audiopus::Signal::Auto,
+            Signal::Voice => audiopus::Signal::Voice,
+            Signal::Music => audiopus::Signal::Music,
+        }
+    }
+}
+
+impl FromStr for Application {
+    type Err = error::ParseSetting;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "voip" => Ok(Application::Voip),
+            "audio" => Ok(Application::Audio),
+            "lowdelay" => Ok(Application::LowDelay),
+            _ => Err(error::ParseSetting::new("application", s)),
+        }
+    }
+}
+
+impl FromStr for Vbr {
+    type Err = error::ParseSetting;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on" => Ok(Vbr::On),
+            "constrained" => Ok(Vbr::Constrained),
+            "off" => Ok(Vbr::Off),
+            _ => Err(error::ParseSetting::new("VBR mode", s)),
+        }
+    }
+}
+
+impl FromStr for Signal {
+    type Err = error::ParseSetting;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Signal::Auto),
+            "voice" => Ok(Signal::Voice),
+            "music" => Ok(Signal::Music),
+            _ => Err(error::ParseSetting::new("signal", s)),
+        }
+    }
+}
+
+/// Parse the frame duration given in milliseconds, like `2.5` or `20`.
+///
+/// # Errors
+///
+/// Fails if opus doesn't support the frames of that duration.
+pub fn parse_frame_duration(s: &str) -> Result<Duration, error::ParseSetting> {
+    let invalid = || error::ParseSetting::new("frame duration", s);
+    let duration_ms: f64 = s.parse().map_err(|_| invalid())?;
+    FRAME_DURATIONS_US
+        .iter()
+        .copied()
+        .find(|&duration_us| {
+            #[allow(clippy::cast_precision_loss)]
+            let supported_ms = duration_us as f64 / 1000.0;
+            (supported_ms - duration_ms).abs() < f64::EPSILON
+        })
+        .map(Duration::from_micros)
+        .ok_or_else(invalid)
+}
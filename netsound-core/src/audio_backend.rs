@@ -46,4 +46,8 @@ pub trait Builder {
 #[async_trait]
 pub trait Backend: Send + Sync {
     async fn run(&mut self);
+
+    /// Stop the streams, and wait until they're stopped. Nothing is
+    /// captured or played after this returns.
+    fn stop(&mut self);
 }
@@ -8,6 +8,8 @@ use std::future::Future;
 use std::io::Result;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 #[derive(Debug)]
@@ -19,6 +21,33 @@ struct Inner<T> {
 
     // Waits on write for buffer becoming non-full.
     write_waker: AtomicWaker,
+
+    fill_level: FillLevel,
+}
+
+/// Tells how full the buffer is, without locking it.
+#[derive(Debug, Clone)]
+pub struct FillLevel {
+    len: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl FillLevel {
+    /// The amount of items in the buffer.
+    #[must_use]
+    pub fn get(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// The amount of items the buffer can hold.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn set(&self, len: usize) {
+        self.len.store(len, Ordering::Relaxed);
+    }
 }
 
 #[must_use]
@@ -30,16 +59,23 @@ pub fn vec_deque_buffer_with_capacity<T>(
 
 #[must_use]
 pub fn vec_deque_buffer<T>(vd: VecDeque<T>) -> (VecDequeBufferWriter<T>, VecDequeBufferReader<T>) {
+    let fill_level = FillLevel {
+        len: Arc::new(AtomicUsize::new(vd.len())),
+        capacity: vd.capacity(),
+    };
     let (reader_inner, writer_inner) = BiLock::new(Inner {
         vd,
         read_waker: AtomicWaker::new(),
         write_waker: AtomicWaker::new(),
+        fill_level: fill_level.clone(),
     });
     let writer = VecDequeBufferWriter {
         inner: writer_inner,
+        fill_level: fill_level.clone(),
     };
     let reader = VecDequeBufferReader {
         inner: reader_inner,
+        fill_level,
     };
     (writer, reader)
 }
@@ -65,11 +101,13 @@ impl<T> Inner<T> {
 #[derive(Debug)]
 pub struct VecDequeBufferReader<T> {
     inner: BiLock<Inner<T>>,
+    fill_level: FillLevel,
 }
 
 #[derive(Debug)]
 pub struct VecDequeBufferWriter<T> {
     inner: BiLock<Inner<T>>,
+    fill_level: FillLevel,
 }
 
 fn is_full<T>(vd: &VecDeque<T>) -> bool {
@@ -114,6 +152,7 @@ impl<T: Unpin> AsyncReadItems<T> for VecDequeBufferReader<T> {
             }
         }
         let len = vd.len();
+        inner.fill_level.set(len);
 
         inner.wake_writer_if_needed();
 
@@ -157,6 +196,7 @@ impl<T: Unpin + Copy> AsyncWriteItems<T> for VecDequeBufferWriter<T> {
             filled += 1;
         }
         let len = vd.len();
+        inner.fill_level.set(len);
 
         inner.wake_reader_if_needed();
 
@@ -186,6 +226,8 @@ impl<T: Unpin> DerefMut for InnerVecDequeGuard<'_, T> {
 impl<T: Unpin> Drop for InnerVecDequeGuard<'_, T> {
     #[inline]
     fn drop(&mut self) {
+        let len = self.inner_guard.vd.len();
+        self.inner_guard.fill_level.set(len);
         self.inner_guard.wake_writer_if_needed();
         self.inner_guard.wake_reader_if_needed();
         trace!(
@@ -218,6 +260,11 @@ impl<T> VecDequeBufferReader<T> {
         let inner_acquire = self.inner.lock();
         InnerVecDequeAcquire { inner_acquire }
     }
+
+    #[must_use]
+    pub fn fill_level(&self) -> FillLevel {
+        self.fill_level.clone()
+    }
 }
 
 impl<T> VecDequeBufferWriter<T> {
@@ -225,6 +272,11 @@ impl<T> VecDequeBufferWriter<T> {
         let inner_acquire = self.inner.lock();
         InnerVecDequeAcquire { inner_acquire }
     }
+
+    #[must_use]
+    pub fn fill_level(&self) -> FillLevel {
+        self.fill_level.clone()
+    }
 }
 
 #[cfg(test)]
@@ -25,6 +25,25 @@ fn test_sequence_write_read() {
     assert_eq!(&read_buf[..items_read], &write_buf[..]);
 }
 
+#[test]
+fn test_fill_level() {
+    let (mut writer, mut reader) = vec_deque_buffer_with_capacity::<u8>(1024);
+    let fill_level = reader.fill_level();
+    assert_eq!(fill_level.get(), 0);
+    assert!(fill_level.capacity() >= 1024);
+
+    block_on(writer.write_items(&[1, 2, 3], WaitMode::WaitForReady)).unwrap();
+    assert_eq!(fill_level.get(), 3);
+    assert_eq!(writer.fill_level().get(), 3);
+
+    let mut read_buf = [0_u8; 2];
+    block_on(reader.read_items(&mut read_buf, WaitMode::WaitForReady)).unwrap();
+    assert_eq!(fill_level.get(), 1);
+
+    block_on(reader.lock()).clear();
+    assert_eq!(fill_level.get(), 0);
+}
+
 #[test]
 fn test_read_non_pending_underflowing() {
     // Ensure VecDeque has expected capacity.
@@ -0,0 +1,460 @@
+//! The IMA ADPCM codec, 4 bits per sample, a quarter of the 16-bit PCM.
+//!
+//! Every packet starts with the predictor state of every channel, so that
+//! the packets decode on their own, regardless of the ones lost before:
+//!
+//! ```text
+//! padding:       8 bits, 1 if the last nibble isn't a sample, 0 otherwise
+//! for every channel:
+//!   predictor:   16 bits, signed, little-endian
+//!   step index:  8 bits
+//! the samples, interleaved, two per byte, the lower nibble first
+//! ```
+
+use super::concealment::FadeOut;
+use super::registry::{self, Capabilities, DynDecoder, DynEncoder};
+use crate::io::{AsyncReadItems, AsyncReadItemsExt, AsyncWriteItems, AsyncWriteItemsExt, WaitMode};
+use crate::pcm;
+use async_trait::async_trait;
+use byteorder::{ByteOrder, LittleEndian};
+use std::io;
+
+/// The size of the predictor state of a single channel in the packet.
+const STATE_SIZE: usize = 3;
+
+/// The size of the packet header carrying the predictor state of
+/// `channels` channels.
+fn header_size(channels: pcm::Channels) -> usize {
+    1 + STATE_SIZE * channels
+}
+
+const INDEX_TABLE: [isize; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+
+const STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+/// The amount of the interleaved samples of `channels` channels that fit
+/// a packet of `payload_size` bytes, in whole frames.
+#[must_use]
+pub fn max_packet_samples(channels: pcm::Channels, payload_size: usize) -> usize {
+    let samples = payload_size.saturating_sub(header_size(channels)) * 2;
+    samples / channels * channels
+}
+
+/// The state of the predictor of a single channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Channel {
+    predictor: i32,
+    index: usize,
+}
+
+impl Channel {
+    fn write(self, buf: &mut [u8]) {
+        #[allow(clippy::cast_possible_truncation)]
+        LittleEndian::write_i16(buf, self.predictor as i16);
+        #[allow(clippy::cast_possible_truncation)]
+        let index = self.index as u8;
+        buf[2] = index;
+    }
+
+    fn read(buf: &[u8]) -> io::Result<Self> {
+        let index = usize::from(buf[2]);
+        if index >= STEP_TABLE.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid step index {index}"),
+            ));
+        }
+        Ok(Self {
+            predictor: i32::from(LittleEndian::read_i16(buf)),
+            index,
+        })
+    }
+
+    fn encode(&mut self, sample: i16) -> u8 {
+        let step = STEP_TABLE[self.index];
+        let mut diff = i32::from(sample) - self.predictor;
+        let mut nibble = 0;
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        if diff >= step {
+            nibble |= 4;
+            diff -= step;
+        }
+        if diff >= step >> 1 {
+            nibble |= 2;
+            diff -= step >> 1;
+        }
+        if diff >= step >> 2 {
+            nibble |= 1;
+        }
+        // The encoder tracks exactly what the decoder is going to see.
+        self.decode(nibble);
+        nibble
+    }
+
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let step = STEP_TABLE[self.index];
+        let mut delta = step >> 3;
+        if nibble & 4 != 0 {
+            delta += step;
+        }
+        if nibble & 2 != 0 {
+            delta += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            delta += step >> 2;
+        }
+        if nibble & 8 != 0 {
+            delta = -delta;
+        }
+        self.predictor = (self.predictor + delta).clamp(i16::MIN.into(), i16::MAX.into());
+
+        self.index = self
+            .index
+            .saturating_add_signed(INDEX_TABLE[usize::from(nibble & 7)])
+            .min(STEP_TABLE.len() - 1);
+
+        #[allow(clippy::cast_possible_truncation)]
+        let sample = self.predictor as i16;
+        sample
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn to_linear(sample: f32) -> i16 {
+    (sample * 32768.0).round().clamp(-32768.0, 32767.0) as i16
+}
+
+#[derive(Debug)]
+pub struct Encoder {
+    channels: Vec<Channel>,
+    samples: Vec<f32>,
+    /// The samples of the incomplete frame read last time, at the start of
+    /// the `samples`.
+    buffered: usize,
+}
+
+impl Encoder {
+    /// Create the encoder for the stream of `channels` interleaved
+    /// channels.
+    #[must_use]
+    pub fn new(channels: pcm::Channels) -> Self {
+        Self {
+            channels: vec![Channel::default(); channels],
+            samples: Vec::new(),
+            buffered: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl<T> super::Encoder<f32, T> for Encoder
+where
+    T: AsyncReadItems<f32> + Send + Unpin,
+{
+    async fn encode(
+        &mut self,
+        input: &mut T,
+        output: &mut [u8],
+    ) -> Result<super::Encoded, super::error::Encoding> {
+        let channels = self.channels.len();
+        self.samples
+            .resize(max_packet_samples(channels, output.len()), 0.0);
+        // Encode only the whole frames, so that a lost packet never shifts
+        // the channels of the ones that follow, and keep the rest for
+        // the next packet.
+        let mut buffered = self.buffered;
+        let samples = loop {
+            let read = input
+                .read_items(&mut self.samples[buffered..], WaitMode::WaitForReady)
+                .await
+                .map_err(|err| super::error::Encoding::Other(err.into()))?;
+            buffered += read;
+            let samples = buffered / channels * channels;
+            if samples > 0 || read == 0 {
+                break samples;
+            }
+        };
+
+        let (header, data) = output.split_at_mut(header_size(channels));
+        header[0] = u8::from(samples % 2 != 0);
+        for (state, channel) in header[1..].chunks_exact_mut(STATE_SIZE).zip(&self.channels) {
+            channel.write(state);
+        }
+        let data = &mut data[..(samples + 1) / 2];
+        data.fill(0);
+        for (position, &sample) in self.samples[..samples].iter().enumerate() {
+            let nibble = self.channels[position % channels].encode(to_linear(sample));
+            data[position / 2] |= nibble << (4 * (position % 2));
+        }
+
+        let bytes = header_size(channels) + data.len();
+        self.samples.copy_within(samples..buffered, 0);
+        self.buffered = buffered - samples;
+
+        Ok(super::Encoded { bytes, samples })
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    channels: pcm::Channels,
+    fade_out: FadeOut,
+}
+
+impl Decoder {
+    /// Create the decoder for the stream of `channels` interleaved
+    /// channels.
+    #[must_use]
+    pub fn new(channels: pcm::Channels) -> Self {
+        Self {
+            channels,
+            fade_out: FadeOut::default(),
+        }
+    }
+
+    fn decode_packet(&mut self, input: &[u8]) -> io::Result<&[f32]> {
+        let channels = self.channels;
+        if input.len() < header_size(channels) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the packet is too short to carry the predictor state",
+            ));
+        }
+        let (header, data) = input.split_at(header_size(channels));
+        let padding = usize::from(header[0]);
+        if padding > 1 || (padding > 0 && data.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid padding {padding}"),
+            ));
+        }
+        let samples = data.len() * 2 - padding;
+        if samples % channels != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the packet doesn't carry a whole amount of frames",
+            ));
+        }
+        let mut states = header[1..]
+            .chunks_exact(STATE_SIZE)
+            .map(Channel::read)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let frame = self.fade_out.frame();
+        frame.clear();
+        let nibbles = data.iter().flat_map(|byte| [byte & 0x0f, byte >> 4]);
+        for (position, nibble) in nibbles.take(samples).enumerate() {
+            let sample = states[position % channels].decode(nibble);
+            frame.push(f32::from(sample) / 32768.0);
+        }
+        Ok(frame)
+    }
+}
+
+#[async_trait]
+impl<T> super::Decoder<f32, T> for Decoder
+where
+    T: AsyncWriteItems<f32> + Send + Unpin,
+{
+    async fn decode(
+        &mut self,
+        input: &[u8],
+        output: &mut T,
+    ) -> Result<usize, super::error::Decoding> {
+        let frame = self
+            .decode_packet(input)
+            .map_err(|err| super::error::Decoding::Other(err.into()))?;
+        Ok(output
+            .write_items(frame, WaitMode::WaitForReady)
+            .await
+            .map_err(|err| super::error::Decoding::Other(err.into()))?)
+    }
+
+    /// Repeat the last frame, fading it out over the course of a few
+    /// consecutive losses.
+    async fn conceal(&mut self, output: &mut T) -> Result<usize, super::error::Decoding> {
+        Ok(self
+            .fade_out
+            .conceal(output)
+            .await
+            .map_err(|err| super::error::Decoding::Other(err.into()))?)
+    }
+}
+
+/// The ADPCM codec.
+#[derive(Debug, Clone, Copy)]
+pub struct Factory;
+
+impl registry::Factory for Factory {
+    fn name(&self) -> &str {
+        "adpcm"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            sample_rates: registry::COMMON_SAMPLE_RATES.to_vec(),
+            max_channels: registry::MAX_CHANNELS,
+        }
+    }
+
+    fn frame_samples(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        settings: &registry::Settings,
+    ) -> usize {
+        max_packet_samples(stream_config.channels(), settings.max_packet_size)
+    }
+
+    fn encoder(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        _settings: &registry::Settings,
+    ) -> Result<Box<DynEncoder>, crate::Error> {
+        Ok(Box::new(Encoder::new(stream_config.channels())))
+    }
+
+    fn decoder(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        _settings: &registry::Settings,
+    ) -> Result<Box<DynDecoder>, crate::Error> {
+        Ok(Box::new(Decoder::new(stream_config.channels())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Decoder as _, Encoder as _};
+    use super::*;
+
+    fn sine(samples: u16) -> Vec<f32> {
+        (0..samples)
+            .map(|n| (f32::from(n) * 0.05).sin() * 0.5)
+            .collect()
+    }
+
+    async fn encode(encoder: &mut Encoder, samples: &[f32], packet_size: usize) -> Vec<u8> {
+        let (mut writer, mut reader) = crate::buf::vec_deque_buffer_with_capacity(samples.len());
+        writer.write_items(samples, WaitMode::NoWait).await.unwrap();
+        let mut packet = vec![0; packet_size];
+        let result = encoder.encode(&mut reader, &mut packet).await.unwrap();
+        assert_eq!(result.samples, samples.len());
+        packet.truncate(result.bytes);
+        packet
+    }
+
+    #[test]
+    fn encode_decode() {
+        futures::executor::block_on(async {
+            let samples = sine(401);
+            let packet = encode(&mut Encoder::new(1), &samples, 1000).await;
+            // A quarter of the 16-bit PCM, and the header.
+            assert_eq!(packet.len(), 4 + 201);
+            assert_eq!(packet[0], 1);
+
+            let mut output = Vec::new();
+            let samples_written = Decoder::new(1).decode(&packet, &mut output).await.unwrap();
+            assert_eq!(samples_written, samples.len());
+            // Past the first few samples, while the step size adapts.
+            for (decoded, sample) in output.iter().zip(&samples).skip(20) {
+                assert!(
+                    (decoded - sample).abs() < 0.01,
+                    "{} became {}",
+                    sample,
+                    decoded
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn packets_decode_independently() {
+        futures::executor::block_on(async {
+            let samples: Vec<f32> = sine(400)
+                .into_iter()
+                .flat_map(|sample| [sample, -sample])
+                .collect();
+            let mut encoder = Encoder::new(2);
+            let first = encode(&mut encoder, &samples[..400], 1000).await;
+            let second = encode(&mut encoder, &samples[400..], 1000).await;
+            assert_eq!(first.len(), 7 + 200);
+
+            // The second packet alone decodes just as it does after
+            // the first one.
+            let mut decoder = Decoder::new(2);
+            let mut after_first = Vec::new();
+            decoder.decode(&first, &mut after_first).await.unwrap();
+            after_first.clear();
+            decoder.decode(&second, &mut after_first).await.unwrap();
+
+            let mut alone = Vec::new();
+            Decoder::new(2).decode(&second, &mut alone).await.unwrap();
+            assert_eq!(alone, after_first);
+            for (decoded, sample) in alone.iter().zip(&samples[400..]) {
+                assert!(
+                    (decoded - sample).abs() < 0.01,
+                    "{} became {}",
+                    sample,
+                    decoded
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn keeps_the_incomplete_frame() {
+        futures::executor::block_on(async {
+            let samples = sine(9);
+            let (mut writer, mut reader) = crate::buf::vec_deque_buffer_with_capacity(9);
+            let mut encoder = Encoder::new(3);
+            let mut decoder = Decoder::new(3);
+            let mut output = Vec::new();
+            let mut packet = vec![0; 1000];
+
+            // Seven samples are two frames of three channels, and a bit.
+            writer
+                .write_items(&samples[..7], WaitMode::NoWait)
+                .await
+                .unwrap();
+            let first = encoder.encode(&mut reader, &mut packet).await.unwrap();
+            assert_eq!(first.samples, 6);
+            decoder
+                .decode(&packet[..first.bytes], &mut output)
+                .await
+                .unwrap();
+
+            writer
+                .write_items(&samples[7..], WaitMode::NoWait)
+                .await
+                .unwrap();
+            let second = encoder.encode(&mut reader, &mut packet).await.unwrap();
+            assert_eq!(second.samples, 3);
+            decoder
+                .decode(&packet[..second.bytes], &mut output)
+                .await
+                .unwrap();
+
+            // Just as if all of them were read at once.
+            let packet = encode(&mut Encoder::new(3), &samples, 1000).await;
+            let mut at_once = Vec::new();
+            Decoder::new(3).decode(&packet, &mut at_once).await.unwrap();
+            assert_eq!(output, at_once);
+        });
+    }
+
+    #[test]
+    fn fits_the_payload() {
+        assert_eq!(max_packet_samples(1, 1452), 2 * 1448);
+        assert_eq!(max_packet_samples(2, 1452), 2 * 1445);
+        assert_eq!(max_packet_samples(2, 4), 0);
+    }
+}
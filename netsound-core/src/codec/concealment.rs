@@ -0,0 +1,60 @@
+//! The packet loss concealment for the codecs that have none of their own.
+
+use crate::io::{AsyncWriteItems, AsyncWriteItemsExt, WaitMode};
+use std::io::Result;
+
+/// The amount of consecutive lost packets over which the concealment fades
+/// the last frame out to silence.
+const FADE_OUT_FRAMES: usize = 2;
+
+/// Repeats the last decoded frame, fading it out over the course of a few
+/// consecutive losses.
+#[derive(Debug, Default)]
+pub(crate) struct FadeOut {
+    /// The last successfully decoded frame.
+    last_frame: Vec<f32>,
+    /// The amount of consecutive packets concealed so far.
+    concealed_frames: usize,
+}
+
+impl FadeOut {
+    /// The buffer to decode the next frame to. Decoding a frame ends
+    /// the fade out.
+    pub fn frame(&mut self) -> &mut Vec<f32> {
+        self.concealed_frames = 0;
+        &mut self.last_frame
+    }
+
+    /// Write the substitute for the lost frame to the `output`.
+    pub async fn conceal<T>(&mut self, output: &mut T) -> Result<usize>
+    where
+        T: AsyncWriteItems<f32> + Unpin,
+    {
+        let gain = |frames: usize| {
+            let left = FADE_OUT_FRAMES.saturating_sub(frames);
+            #[allow(clippy::cast_precision_loss)]
+            let gain = left as f32 / FADE_OUT_FRAMES as f32;
+            gain
+        };
+        let gain_from = gain(self.concealed_frames);
+        let gain_to = gain(self.concealed_frames + 1);
+        self.concealed_frames += 1;
+
+        // The gain goes linearly from `gain_from` to `gain_to` over
+        // the course of the frame.
+        #[allow(clippy::cast_precision_loss)]
+        let step = (gain_to - gain_from) / self.last_frame.len().max(1) as f32;
+        let mut gain = gain_from;
+        let samples: Vec<f32> = self
+            .last_frame
+            .iter()
+            .map(|sample| {
+                let sample = sample * gain;
+                gain += step;
+                sample
+            })
+            .collect();
+
+        output.write_items(&samples, WaitMode::WaitForReady).await
+    }
+}
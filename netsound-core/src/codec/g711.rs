@@ -0,0 +1,344 @@
+//! The G.711 codec, the μ-law and the A-law companding of the narrowband
+//! telephony audio, at 8 bits per sample.
+
+use super::concealment::FadeOut;
+use super::registry::{self, Capabilities, DynDecoder, DynEncoder};
+use crate::io::{AsyncReadItems, AsyncReadItemsExt, AsyncWriteItems, AsyncWriteItemsExt, WaitMode};
+use crate::pcm;
+use async_trait::async_trait;
+
+/// The only sample rate G.711 runs at.
+pub const SAMPLE_RATE: u32 = 8000;
+
+/// G.711 carries a single channel.
+pub const CHANNELS: u16 = 1;
+
+/// The amount of samples per packet, 20 ms like the telephony gear does.
+pub const FRAME_SAMPLES: usize = 160;
+
+/// The companding law.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Law {
+    /// μ-law, used in North America and Japan.
+    MuLaw,
+    /// A-law, used everywhere else.
+    ALaw,
+}
+
+impl Law {
+    fn encode(self, sample: f32) -> u8 {
+        let linear = to_linear(sample);
+        match self {
+            Law::MuLaw => linear_to_mu_law(linear),
+            Law::ALaw => linear_to_a_law(linear),
+        }
+    }
+
+    fn decode(self, byte: u8) -> f32 {
+        let linear = match self {
+            Law::MuLaw => mu_law_to_linear(byte),
+            Law::ALaw => a_law_to_linear(byte),
+        };
+        f32::from(linear) / 32768.0
+    }
+}
+
+/// Scale the `sample` to the 16-bit linear PCM.
+#[allow(clippy::cast_possible_truncation)]
+fn to_linear(sample: f32) -> i16 {
+    (sample * 32768.0).round().clamp(-32768.0, 32767.0) as i16
+}
+
+const MU_LAW_BIAS: i32 = 0x84;
+const MU_LAW_CLIP: i32 = 32635;
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn linear_to_mu_law(linear: i16) -> u8 {
+    let mut value = i32::from(linear);
+    let sign = if value < 0 {
+        value = -value;
+        0x80
+    } else {
+        0
+    };
+    value = value.min(MU_LAW_CLIP) + MU_LAW_BIAS;
+
+    let mut exponent = 7;
+    let mut mask = 0x4000;
+    while exponent > 0 && value & mask == 0 {
+        exponent -= 1;
+        mask >>= 1;
+    }
+    let mantissa = (value >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn mu_law_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = i32::from(byte & 0x0f);
+    let value = (((mantissa << 3) + MU_LAW_BIAS) << exponent) - MU_LAW_BIAS;
+    (if byte & 0x80 == 0 { value } else { -value }) as i16
+}
+
+/// The upper bounds of the A-law segments, for the 13-bit values.
+const A_LAW_SEGMENT_ENDS: [i32; 8] = [0x1f, 0x3f, 0x7f, 0xff, 0x1ff, 0x3ff, 0x7ff, 0xfff];
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+fn linear_to_a_law(linear: i16) -> u8 {
+    let mut value = i32::from(linear) >> 3;
+    let mask = if value >= 0 {
+        0xd5
+    } else {
+        value = -value - 1;
+        0x55
+    };
+
+    let Some(segment) = A_LAW_SEGMENT_ENDS.iter().position(|&end| value <= end) else {
+        return (0x7f ^ mask) as u8;
+    };
+    let shift = if segment < 2 { 1 } else { segment };
+    let byte = ((segment as i32) << 4) | ((value >> shift) & 0x0f);
+    (byte ^ mask) as u8
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn a_law_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let segment = (byte >> 4) & 0x07;
+    let mut value = i32::from(byte & 0x0f) << 4;
+    match segment {
+        0 => value += 0x08,
+        1 => value += 0x108,
+        _ => value = (value + 0x108) << (segment - 1),
+    }
+    (if byte & 0x80 == 0 { -value } else { value }) as i16
+}
+
+#[derive(Debug)]
+pub struct Encoder {
+    law: Law,
+    samples: Vec<f32>,
+}
+
+impl Encoder {
+    #[must_use]
+    pub fn new(law: Law) -> Self {
+        Self {
+            law,
+            samples: vec![0.0; FRAME_SAMPLES],
+        }
+    }
+}
+
+#[async_trait]
+impl<T> super::Encoder<f32, T> for Encoder
+where
+    T: AsyncReadItems<f32> + Send + Unpin,
+{
+    async fn encode(
+        &mut self,
+        input: &mut T,
+        output: &mut [u8],
+    ) -> Result<super::Encoded, super::error::Encoding> {
+        let samples = FRAME_SAMPLES.min(output.len());
+        input
+            .read_exact_items(&mut self.samples[..samples], WaitMode::WaitForReady)
+            .await
+            .map_err(|err| super::error::Encoding::Other(err.into()))?;
+        for (byte, &sample) in output.iter_mut().zip(&self.samples[..samples]) {
+            *byte = self.law.encode(sample);
+        }
+        Ok(super::Encoded {
+            bytes: samples,
+            samples,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    law: Law,
+    fade_out: FadeOut,
+}
+
+impl Decoder {
+    #[must_use]
+    pub fn new(law: Law) -> Self {
+        Self {
+            law,
+            fade_out: FadeOut::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<T> super::Decoder<f32, T> for Decoder
+where
+    T: AsyncWriteItems<f32> + Send + Unpin,
+{
+    async fn decode(
+        &mut self,
+        input: &[u8],
+        output: &mut T,
+    ) -> Result<usize, super::error::Decoding> {
+        let law = self.law;
+        let frame = self.fade_out.frame();
+        frame.clear();
+        frame.extend(input.iter().map(|&byte| law.decode(byte)));
+        Ok(output
+            .write_items(frame, WaitMode::WaitForReady)
+            .await
+            .map_err(|err| super::error::Decoding::Other(err.into()))?)
+    }
+
+    /// Repeat the last frame, fading it out over the course of a few
+    /// consecutive losses.
+    async fn conceal(&mut self, output: &mut T) -> Result<usize, super::error::Decoding> {
+        Ok(self
+            .fade_out
+            .conceal(output)
+            .await
+            .map_err(|err| super::error::Decoding::Other(err.into()))?)
+    }
+}
+
+/// G.711 with a single companding law.
+#[derive(Debug, Clone, Copy)]
+pub struct Factory {
+    law: Law,
+}
+
+impl Factory {
+    #[must_use]
+    pub fn new(law: Law) -> Self {
+        Self { law }
+    }
+}
+
+impl registry::Factory for Factory {
+    fn name(&self) -> &str {
+        match self.law {
+            Law::MuLaw => "pcmu",
+            Law::ALaw => "pcma",
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            sample_rates: vec![SAMPLE_RATE],
+            max_channels: CHANNELS,
+        }
+    }
+
+    fn frame_samples(
+        &self,
+        _stream_config: pcm::StreamConfig<f32>,
+        settings: &registry::Settings,
+    ) -> usize {
+        FRAME_SAMPLES.min(settings.max_packet_size)
+    }
+
+    fn encoder(
+        &self,
+        _stream_config: pcm::StreamConfig<f32>,
+        _settings: &registry::Settings,
+    ) -> Result<Box<DynEncoder>, crate::Error> {
+        Ok(Box::new(Encoder::new(self.law)))
+    }
+
+    fn decoder(
+        &self,
+        _stream_config: pcm::StreamConfig<f32>,
+        _settings: &registry::Settings,
+    ) -> Result<Box<DynDecoder>, crate::Error> {
+        Ok(Box::new(Decoder::new(self.law)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference_values() {
+        // Silence, and the positive and the negative full scale.
+        assert_eq!(linear_to_mu_law(0), 0xff);
+        assert_eq!(linear_to_mu_law(i16::MAX), 0x80);
+        assert_eq!(linear_to_mu_law(i16::MIN), 0x00);
+        assert_eq!(mu_law_to_linear(0xff), 0);
+        assert_eq!(mu_law_to_linear(0x80), 32124);
+        assert_eq!(mu_law_to_linear(0x00), -32124);
+
+        assert_eq!(linear_to_a_law(0), 0xd5);
+        assert_eq!(linear_to_a_law(i16::MAX), 0xaa);
+        assert_eq!(linear_to_a_law(i16::MIN), 0x2a);
+        assert_eq!(a_law_to_linear(0xd5), 8);
+        assert_eq!(a_law_to_linear(0xaa), 32256);
+        assert_eq!(a_law_to_linear(0x2a), -32256);
+    }
+
+    #[test]
+    fn round_trip() {
+        for law in [Law::MuLaw, Law::ALaw] {
+            // Every code decodes to the value that encodes back to it, but
+            // for the μ-law negative zero.
+            for byte in 0..=u8::MAX {
+                let decoded = law.decode(byte);
+                if law == Law::MuLaw && byte == 0x7f {
+                    continue;
+                }
+                assert_eq!(law.encode(decoded), byte, "{law:?} {byte:#x}");
+            }
+
+            // The error is within a few percent of the value.
+            for &sample in &[0.9, 0.5, -0.25, 0.1, -0.01] {
+                let decoded = law.decode(law.encode(sample));
+                assert!(
+                    (decoded - sample).abs() <= sample.abs() * 0.07,
+                    "{:?}: {} became {}",
+                    law,
+                    sample,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        use super::super::{Decoder as _, Encoder as _};
+
+        futures::executor::block_on(async {
+            let samples: Vec<f32> = (0..200_u16).map(|n| f32::from(n) / 400.0).collect();
+            let (mut writer, mut reader) = crate::buf::vec_deque_buffer_with_capacity(1000);
+            writer
+                .write_items(&samples, WaitMode::NoWait)
+                .await
+                .unwrap();
+
+            let mut packet = [0; 1000];
+            let encoded = Encoder::new(Law::ALaw)
+                .encode(&mut reader, &mut packet)
+                .await
+                .unwrap();
+            assert_eq!(encoded.bytes, FRAME_SAMPLES);
+            assert_eq!(encoded.samples, FRAME_SAMPLES);
+
+            let mut output = Vec::new();
+            let samples_written = Decoder::new(Law::ALaw)
+                .decode(&packet[..encoded.bytes], &mut output)
+                .await
+                .unwrap();
+            assert_eq!(samples_written, FRAME_SAMPLES);
+            for (decoded, sample) in output.iter().zip(&samples) {
+                assert!((decoded - sample).abs() < 0.01);
+            }
+        });
+    }
+}
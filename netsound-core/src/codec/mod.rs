@@ -2,16 +2,77 @@ use crate::io::{AsyncReadItems, AsyncWriteItems};
 use crate::pcm::Sample;
 use async_trait::async_trait;
 
+pub mod adpcm;
+pub mod g711;
 pub mod raw;
+pub mod registry;
+
+mod concealment;
 
 pub mod error;
 
+/// The outcome of encoding a single packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoded {
+    /// The amount of bytes written to the output.
+    pub bytes: usize,
+    /// The amount of samples consumed from the input.
+    pub samples: usize,
+}
+
+/// The encoding settings adapted to the network conditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adaptation {
+    /// The target bitrate, in bits per second.
+    pub bitrate: u32,
+    /// The packet loss percentage to prepare the stream for.
+    pub expected_packet_loss_perc: u8,
+    /// Whether to add the forward error correction data to the stream.
+    pub fec: bool,
+}
+
 #[async_trait]
 pub trait Encoder<S: Sample, T: AsyncReadItems<S>> {
-    async fn encode(&mut self, input: &mut T, output: &mut [u8]) -> Result<usize, error::Encoding>;
+    async fn encode(
+        &mut self,
+        input: &mut T,
+        output: &mut [u8],
+    ) -> Result<Encoded, error::Encoding>;
+
+    /// Apply the `adaptation` to the packets encoded from now on, and tell
+    /// whether the codec supports it. The codecs without the tunables
+    /// ignore it.
+    fn adapt(&mut self, _adaptation: &Adaptation) -> Result<bool, error::Encoding> {
+        Ok(false)
+    }
 }
 
 #[async_trait]
 pub trait Decoder<S: Sample, T: AsyncWriteItems<S>> {
     async fn decode(&mut self, input: &[u8], output: &mut T) -> Result<usize, error::Decoding>;
+
+    /// Write a substitute for a lost packet to the `output`, returning
+    /// the amount of samples written. The codecs without the concealment
+    /// write nothing, and leave the gap to the playback.
+    async fn conceal(&mut self, _output: &mut T) -> Result<usize, error::Decoding>
+    where
+        T: Send,
+    {
+        Ok(0)
+    }
+
+    /// Write a substitute for a lost packet to the `output`, recovering it
+    /// from the forward error correction data of the packet that follows
+    /// the lost one, if the codec supports it. The codecs without
+    /// the redundancy just conceal the loss.
+    async fn recover(
+        &mut self,
+        _next_input: &[u8],
+        output: &mut T,
+    ) -> Result<usize, error::Decoding>
+    where
+        T: Send,
+    {
+        self.conceal(output).await
+    }
 }
@@ -1,53 +1,92 @@
+use super::format::{Dither, Format};
+use crate::codec::Encoded;
 use crate::io::{AsyncReadItems, AsyncReadItemsExt, AsyncWriteItems, AsyncWriteItemsExt, WaitMode};
-use byteorder::ByteOrder;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
-pub async fn encode<E, T>(input: &mut T, output: &mut [u8]) -> Result<usize>
+/// Encode as many whole frames of `channels` samples in the `format` as
+/// fit the `output`, so that a lost packet never shifts the channels of
+/// the ones that follow. The samples of the incomplete frame are kept in
+/// the `pending` for the next packet.
+pub async fn encode<T>(
+    format: Format,
+    channels: usize,
+    dither: &mut Dither,
+    pending: &mut Vec<f32>,
+    input: &mut T,
+    output: &mut [u8],
+) -> Result<Encoded>
 where
-    E: ByteOrder,
     T: AsyncReadItems<f32> + Unpin,
 {
     // TODO: implement more efficiently.
 
-    // Get the amount of samples to read. Must be round, otherwise we can't
-    // fit all the samples we read to the output.
-    let samples_to_read = output.len() / 4;
-    assert_eq!(samples_to_read * 4, output.len());
+    let sample_size = format.sample_size();
+    let samples_to_read = output.len() / (sample_size * channels) * channels;
 
     let mut samples = Vec::with_capacity(samples_to_read);
     samples.resize(samples_to_read, 0_f32);
 
-    let samples_read = input
-        .read_items(&mut samples, WaitMode::WaitForReady)
-        .await?;
+    let mut buffered = pending.len().min(samples_to_read);
+    samples[..buffered].copy_from_slice(&pending[..buffered]);
+    let samples_read = loop {
+        let read = input
+            .read_items(&mut samples[buffered..], WaitMode::WaitForReady)
+            .await?;
+        buffered += read;
+        let samples_read = buffered / channels * channels;
+        if samples_read > 0 || read == 0 {
+            break samples_read;
+        }
+    };
+    pending.clear();
+    pending.extend_from_slice(&samples[samples_read..buffered]);
 
-    for (chunk, &sample) in output.chunks_exact_mut(4).zip(&samples[..samples_read]) {
-        E::write_f32(chunk, sample);
+    for (chunk, &sample) in output
+        .chunks_exact_mut(sample_size)
+        .zip(&samples[..samples_read])
+    {
+        format.write(chunk, sample, dither);
     }
 
-    Ok(samples_read * 4)
+    Ok(Encoded {
+        bytes: samples_read * sample_size,
+        samples: samples_read,
+    })
 }
 
-pub async fn decode<E, T>(input: &[u8], output: &mut T) -> Result<usize>
+pub async fn decode<T>(
+    format: Format,
+    input: &[u8],
+    samples: &mut Vec<f32>,
+    output: &mut T,
+) -> Result<usize>
 where
-    E: ByteOrder,
     T: AsyncWriteItems<f32> + Unpin,
 {
     // TODO: implement more efficiently.
 
-    // Get the amount of samples to write. Must be round, otherwise we can't
-    // prepare the samples buffer for all bytes we have at the input.
-    let samples_to_write = input.len() / 4;
-    assert_eq!(samples_to_write * 4, input.len());
-
-    let mut samples = Vec::with_capacity(samples_to_write);
-    samples.resize(samples_to_write, 0_f32);
-
-    for (chunk, sample_slot) in input.chunks(4).zip(&mut samples) {
-        *sample_slot = E::read_f32(chunk);
+    // Get the amount of samples to write. Must be round, otherwise the
+    // packet is not in the format we expect.
+    let sample_size = format.sample_size();
+    if input.len() % sample_size != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} bytes is not a whole amount of {} byte samples",
+                input.len(),
+                sample_size
+            ),
+        ));
     }
 
-    output.write_items(&samples, WaitMode::WaitForReady).await
+    samples.clear();
+    samples.extend(
+        input
+            .chunks_exact(sample_size)
+            .map(|chunk| format.read(chunk)),
+    );
+
+    output.write_items(samples, WaitMode::WaitForReady).await
 }
 
 // TODO: restore tests, see git history.
@@ -0,0 +1,215 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// How a single sample is represented.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    F32,
+    S16,
+    S24,
+    S32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// The layout of the samples on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub sample_format: SampleFormat,
+    pub endianness: Endianness,
+}
+
+impl Default for Format {
+    /// The samples as they are in the pipeline, little-endian `f32`.
+    fn default() -> Self {
+        Self::F32LE
+    }
+}
+
+impl Format {
+    pub const F32LE: Self = Self::new(SampleFormat::F32, Endianness::Little);
+    pub const F32BE: Self = Self::new(SampleFormat::F32, Endianness::Big);
+    pub const S16LE: Self = Self::new(SampleFormat::S16, Endianness::Little);
+    pub const S16BE: Self = Self::new(SampleFormat::S16, Endianness::Big);
+    pub const S24LE: Self = Self::new(SampleFormat::S24, Endianness::Little);
+    pub const S24BE: Self = Self::new(SampleFormat::S24, Endianness::Big);
+    pub const S32LE: Self = Self::new(SampleFormat::S32, Endianness::Little);
+    pub const S32BE: Self = Self::new(SampleFormat::S32, Endianness::Big);
+
+    /// All the formats.
+    pub const ALL: [Self; 8] = [
+        Self::F32LE,
+        Self::F32BE,
+        Self::S16LE,
+        Self::S16BE,
+        Self::S24LE,
+        Self::S24BE,
+        Self::S32LE,
+        Self::S32BE,
+    ];
+
+    #[must_use]
+    pub const fn new(sample_format: SampleFormat, endianness: Endianness) -> Self {
+        Self {
+            sample_format,
+            endianness,
+        }
+    }
+
+    /// The name of the raw codec in this format: `raw` for the `f32`
+    /// samples, `raw-s16`, `raw-s24` and `raw-s32` for the integer ones, and
+    /// the `be` suffix for the big-endian ones.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match (self.sample_format, self.endianness) {
+            (SampleFormat::F32, Endianness::Little) => "raw",
+            (SampleFormat::F32, Endianness::Big) => "raw-f32be",
+            (SampleFormat::S16, Endianness::Little) => "raw-s16",
+            (SampleFormat::S16, Endianness::Big) => "raw-s16be",
+            (SampleFormat::S24, Endianness::Little) => "raw-s24",
+            (SampleFormat::S24, Endianness::Big) => "raw-s24be",
+            (SampleFormat::S32, Endianness::Little) => "raw-s32",
+            (SampleFormat::S32, Endianness::Big) => "raw-s32be",
+        }
+    }
+
+    /// The size of a single sample, in bytes.
+    #[must_use]
+    pub fn sample_size(self) -> usize {
+        match self.sample_format {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::F32 | SampleFormat::S32 => 4,
+        }
+    }
+
+    /// Write the `sample` to the `buf` of the sample size, dithering it with
+    /// the `dither` if it's quantized to an integer.
+    pub(super) fn write(self, buf: &mut [u8], sample: f32, dither: &mut Dither) {
+        let (bits, dithered) = match self.sample_format {
+            SampleFormat::F32 => {
+                match self.endianness {
+                    Endianness::Little => LittleEndian::write_f32(buf, sample),
+                    Endianness::Big => BigEndian::write_f32(buf, sample),
+                }
+                return;
+            }
+            SampleFormat::S16 => (16, true),
+            SampleFormat::S24 => (24, true),
+            // The `f32` samples don't have the precision to dither at 32
+            // bits.
+            SampleFormat::S32 => (32, false),
+        };
+        let noise = if dithered { dither.noise() } else { 0.0 };
+        let value = quantize(sample, bits, noise);
+        match self.endianness {
+            Endianness::Little => LittleEndian::write_int(buf, value, buf.len()),
+            Endianness::Big => BigEndian::write_int(buf, value, buf.len()),
+        }
+    }
+
+    /// Read the sample from the `buf` of the sample size.
+    pub(super) fn read(self, buf: &[u8]) -> f32 {
+        let bits = match self.sample_format {
+            SampleFormat::F32 => {
+                return match self.endianness {
+                    Endianness::Little => LittleEndian::read_f32(buf),
+                    Endianness::Big => BigEndian::read_f32(buf),
+                }
+            }
+            SampleFormat::S16 => 16,
+            SampleFormat::S24 => 24,
+            SampleFormat::S32 => 32,
+        };
+        let value = match self.endianness {
+            Endianness::Little => LittleEndian::read_int(buf, buf.len()),
+            Endianness::Big => BigEndian::read_int(buf, buf.len()),
+        };
+        #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+        let sample = (value as f64 / full_scale(bits)) as f32;
+        sample
+    }
+}
+
+/// The value of the full-scale sample of the `bits` wide integer.
+fn full_scale(bits: u32) -> f64 {
+    f64::from(1_u32 << (bits - 1))
+}
+
+/// Scale the `sample` to the `bits` wide integer, adding the `noise` of
+/// the given amount of the least significant bits before rounding.
+#[allow(clippy::cast_possible_truncation)]
+fn quantize(sample: f32, bits: u32, noise: f64) -> i64 {
+    let full_scale = full_scale(bits);
+    let scaled = f64::from(sample) * full_scale + noise;
+    scaled.round().clamp(-full_scale, full_scale - 1.0) as i64
+}
+
+/// The triangular probability density function dither, spanning one least
+/// significant bit each way. Decorrelates the quantization error from
+/// the signal, turning the distortion of the quiet passages into a steady
+/// noise floor.
+#[derive(Debug)]
+pub(super) struct Dither {
+    rng: StdRng,
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl Dither {
+    fn noise(&mut self) -> f64 {
+        self.rng.gen::<f64>() - self.rng.gen::<f64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut dither = Dither::default();
+        for format in Format::ALL {
+            let mut buf = vec![0; format.sample_size()];
+            for &sample in &[0.0, 0.25, -0.5, 0.999, -1.0] {
+                format.write(&mut buf, sample, &mut dither);
+                let read = format.read(&buf);
+                // Within the dither and the rounding.
+                let tolerance = 2.0 / 32768.0;
+                assert!(
+                    (read - sample).abs() <= tolerance,
+                    "{:?}: {} became {}",
+                    format,
+                    sample,
+                    read
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let mut dither = Dither::default();
+        let mut buf = [0; 4];
+
+        Format::S32BE.write(&mut buf, -1.0, &mut dither);
+        assert_eq!(buf, [0x80, 0, 0, 0]);
+        Format::S32BE.write(&mut buf, 2.0, &mut dither);
+        assert_eq!(buf, [0x7f, 0xff, 0xff, 0xff]);
+        Format::S32LE.write(&mut buf, 0.5, &mut dither);
+        assert_eq!(buf, [0, 0, 0, 0x40]);
+
+        assert!((Format::S24BE.read(&[0xc0, 0, 0]) - -0.5).abs() < f32::EPSILON);
+    }
+}
@@ -1,12 +1,36 @@
+use super::concealment::FadeOut;
+use super::registry::{self, Capabilities, DynDecoder, DynEncoder};
 use crate::io::{AsyncReadItems, AsyncWriteItems};
+use crate::pcm;
 use async_trait::async_trait;
 
 mod codec;
+mod format;
 
-pub type Endian = byteorder::LittleEndian;
+pub use format::{Endianness, Format, SampleFormat};
 
 #[derive(Debug)]
-pub struct Encoder;
+pub struct Encoder {
+    format: Format,
+    channels: pcm::Channels,
+    dither: format::Dither,
+    /// The samples of the incomplete frame read last time.
+    pending: Vec<f32>,
+}
+
+impl Encoder {
+    /// Create the encoder for the stream of `channels` interleaved
+    /// channels, to be sent in the `format`.
+    #[must_use]
+    pub fn new(format: Format, channels: pcm::Channels) -> Self {
+        Self {
+            format,
+            channels,
+            dither: format::Dither::default(),
+            pending: Vec::new(),
+        }
+    }
+}
 
 #[async_trait]
 impl<T> super::Encoder<f32, T> for Encoder
@@ -17,15 +41,36 @@ where
         &mut self,
         input: &mut T,
         output: &mut [u8],
-    ) -> Result<usize, super::error::Encoding> {
-        Ok(codec::encode::<Endian, T>(input, output)
-            .await
-            .map_err(|err| super::error::Encoding::Other(err.into()))?)
+    ) -> Result<super::Encoded, super::error::Encoding> {
+        Ok(codec::encode(
+            self.format,
+            self.channels,
+            &mut self.dither,
+            &mut self.pending,
+            input,
+            output,
+        )
+        .await
+        .map_err(|err| super::error::Encoding::Other(err.into()))?)
     }
 }
 
-#[derive(Debug)]
-pub struct Decoder;
+#[derive(Debug, Default)]
+pub struct Decoder {
+    format: Format,
+    fade_out: FadeOut,
+}
+
+impl Decoder {
+    /// Create the decoder for the packets in the `format`.
+    #[must_use]
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            ..Self::default()
+        }
+    }
+}
 
 #[async_trait]
 impl<T> super::Decoder<f32, T> for Decoder
@@ -37,8 +82,75 @@ where
         input: &[u8],
         output: &mut T,
     ) -> Result<usize, super::error::Decoding> {
-        Ok(codec::decode::<Endian, T>(input, output)
+        Ok(
+            codec::decode(self.format, input, self.fade_out.frame(), output)
+                .await
+                .map_err(|err| super::error::Decoding::Other(err.into()))?,
+        )
+    }
+
+    /// Repeat the last frame, fading it out over the course of a few
+    /// consecutive losses.
+    async fn conceal(&mut self, output: &mut T) -> Result<usize, super::error::Decoding> {
+        Ok(self
+            .fade_out
+            .conceal(output)
             .await
             .map_err(|err| super::error::Decoding::Other(err.into()))?)
     }
 }
+
+/// The raw codec in a single format.
+#[derive(Debug, Clone, Copy)]
+pub struct Factory {
+    format: Format,
+}
+
+impl Factory {
+    #[must_use]
+    pub fn new(format: Format) -> Self {
+        Self { format }
+    }
+}
+
+impl registry::Factory for Factory {
+    fn name(&self) -> &str {
+        self.format.name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            sample_rates: registry::COMMON_SAMPLE_RATES.to_vec(),
+            max_channels: registry::MAX_CHANNELS,
+        }
+    }
+
+    /// As many whole frames as fit a packet.
+    fn frame_samples(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        settings: &registry::Settings,
+    ) -> usize {
+        let channels = stream_config.channels();
+        settings.max_packet_size / (self.format.sample_size() * channels) * channels
+    }
+
+    fn encoder(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        _settings: &registry::Settings,
+    ) -> Result<Box<DynEncoder>, crate::Error> {
+        Ok(Box::new(Encoder::new(
+            self.format,
+            stream_config.channels(),
+        )))
+    }
+
+    fn decoder(
+        &self,
+        _stream_config: pcm::StreamConfig<f32>,
+        _settings: &registry::Settings,
+    ) -> Result<Box<DynDecoder>, crate::Error> {
+        Ok(Box::new(Decoder::new(self.format)))
+    }
+}
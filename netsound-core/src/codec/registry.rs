@@ -0,0 +1,216 @@
+//! The registry of the codecs, by their names in the handshake.
+//!
+//! Every codec comes with a [`Factory`] that tells what the codec
+//! supports and builds its coders. The codecs of this crate are registered
+//! with [`Registry::with_builtin`], the ones from the other crates are
+//! added with [`Registry::register`].
+
+use super::{Decoder, Encoder};
+use crate::net::handshake;
+use crate::{buf, pcm};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The sample rates offered by the codecs that can run at any one.
+pub const COMMON_SAMPLE_RATES: [u32; 2] = [48000, 44100];
+
+/// The max amount of the interleaved channels the pipeline can carry, as
+/// the channels are matched and resampled for up to this many.
+pub const MAX_CHANNELS: u16 = 32;
+
+/// The reader the encoders take the samples from.
+pub type EncoderInput = buf::VecDequeBufferReader<f32>;
+
+/// The buffer the decoders write the samples to.
+pub type DecoderOutput = Vec<f32>;
+
+pub type DynEncoder = dyn Encoder<f32, EncoderInput> + Send;
+pub type DynDecoder = dyn Decoder<f32, DecoderOutput> + Send;
+
+/// The stream configs the codec supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The sample rates the codec can run at, from the best to the worst.
+    pub sample_rates: Vec<u32>,
+    /// The max amount of the interleaved channels the codec can carry.
+    pub max_channels: u16,
+}
+
+/// The settings the coders are built with, besides the stream config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// The max size of a single encoded packet, in bytes.
+    pub max_packet_size: usize,
+    /// The highest bitrate the encoder may be adapted to, in bits per
+    /// second, if it's adapted at all.
+    pub max_bitrate: Option<u32>,
+    /// Add the forward error correction data to the stream, tuned for this
+    /// expected packet loss percentage, if the codec supports it.
+    pub fec_expected_packet_loss: Option<u8>,
+}
+
+/// Tells what the codec supports and builds its coders.
+pub trait Factory: Send + Sync {
+    /// The name of the codec in the handshake.
+    fn name(&self) -> &str;
+
+    /// The stream configs the codec supports.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Whether the codec can add the forward error correction data.
+    fn supports_fec(&self) -> bool {
+        false
+    }
+
+    /// The max amount of the interleaved samples the encoder takes per
+    /// packet.
+    fn frame_samples(&self, stream_config: pcm::StreamConfig<f32>, settings: &Settings) -> usize;
+
+    fn encoder(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        settings: &Settings,
+    ) -> Result<Box<DynEncoder>, crate::Error>;
+
+    fn decoder(
+        &self,
+        stream_config: pcm::StreamConfig<f32>,
+        settings: &Settings,
+    ) -> Result<Box<DynDecoder>, crate::Error>;
+
+    /// What we can do with this codec, given the amount of the channels
+    /// the audio devices have.
+    fn offer(&self, device_channels: u16) -> handshake::CodecOffer {
+        let capabilities = self.capabilities();
+        handshake::CodecOffer {
+            name: self.name().to_owned(),
+            sample_rates: capabilities.sample_rates,
+            max_channels: std::cmp::min(capabilities.max_channels, device_channels),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("codec {0:?} is already registered")]
+pub struct AlreadyRegistered(pub String);
+
+/// The codecs, in the order of the registration.
+#[derive(Derivative, Default, Clone)]
+#[derivative(Debug)]
+pub struct Registry {
+    #[derivative(Debug(format_with = "fmt_names"))]
+    factories: Vec<Arc<dyn Factory>>,
+}
+
+fn fmt_names(factories: &[Arc<dyn Factory>], f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_list()
+        .entries(factories.iter().map(|factory| factory.name()))
+        .finish()
+}
+
+impl Registry {
+    /// The empty registry.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The registry of the codecs of this crate: the raw PCM in all
+    /// the formats, the G.711 and the ADPCM.
+    #[must_use]
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        let factories = super::raw::Format::ALL
+            .iter()
+            .map(|&format| Arc::new(super::raw::Factory::new(format)) as Arc<dyn Factory>)
+            .chain([
+                Arc::new(super::g711::Factory::new(super::g711::Law::MuLaw)) as Arc<dyn Factory>,
+                Arc::new(super::g711::Factory::new(super::g711::Law::ALaw)),
+                Arc::new(super::adpcm::Factory),
+            ]);
+        registry.factories.extend(factories);
+        registry
+    }
+
+    /// Add the codec of the `factory`.
+    pub fn register<T>(&mut self, factory: T) -> Result<(), AlreadyRegistered>
+    where
+        T: Factory + 'static,
+    {
+        if self.get(factory.name()).is_some() {
+            return Err(AlreadyRegistered(factory.name().to_owned()));
+        }
+        self.factories.push(Arc::new(factory));
+        Ok(())
+    }
+
+    /// The factory of the codec with the `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Factory>> {
+        self.factories
+            .iter()
+            .find(|factory| factory.name() == name)
+            .cloned()
+    }
+
+    /// The factories of all the codecs, in the order of the registration.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Factory>> {
+        self.factories.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register() {
+        let mut registry = Registry::with_builtin();
+        let names: Vec<_> = registry.iter().map(|factory| factory.name()).collect();
+        assert_eq!(
+            names,
+            [
+                "raw",
+                "raw-f32be",
+                "raw-s16",
+                "raw-s16be",
+                "raw-s24",
+                "raw-s24be",
+                "raw-s32",
+                "raw-s32be",
+                "pcmu",
+                "pcma",
+                "adpcm"
+            ]
+        );
+
+        let err = registry.register(super::super::adpcm::Factory).unwrap_err();
+        assert_eq!(err.to_string(), "codec \"adpcm\" is already registered");
+        assert!(registry.get("opus").is_none());
+
+        let pcma = registry.get("pcma").unwrap();
+        assert_eq!(
+            pcma.offer(2),
+            handshake::CodecOffer {
+                name: "pcma".to_owned(),
+                sample_rates: vec![8000],
+                max_channels: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn build() {
+        let settings = Settings {
+            max_packet_size: 1000,
+            max_bitrate: None,
+            fec_expected_packet_loss: None,
+        };
+        let stream_config = pcm::StreamConfig::new(48000.into(), 2);
+        for factory in Registry::with_builtin().iter() {
+            assert!(factory.frame_samples(stream_config, &settings) > 0);
+            factory.encoder(stream_config, &settings).unwrap();
+            factory.decoder(stream_config, &settings).unwrap();
+        }
+    }
+}
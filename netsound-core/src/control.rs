@@ -0,0 +1,250 @@
+//! The local control interface, for managing the peers we send to, and
+//! the codec we send with, while running.
+//!
+//! It's a line-based text protocol over TCP, meant to be used with tools
+//! like `nc`:
+//!
+//! ```text
+//! add <addr> [<ttl seconds>]  start sending to the peer, for the ttl if set
+//! remove <addr>               stop sending to the peer
+//! list                        list the peers with their expiry
+//! codec <name>                switch the stream to the codec
+//! shutdown                    stop the service
+//! help                        list the commands
+//! ```
+//!
+//! Every command is answered with the `ok` or the `error: <reason>` line,
+//! preceded by the output, if any.
+
+use crate::log::{debug, info, warn};
+use crate::net::{stream::CodecSwitch, PeerRegistry};
+use crate::shutdown::Shutdown;
+use crate::tcp;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const HELP: &str = "\
+add <addr> [<ttl seconds>]  start sending to the peer, for the ttl if set
+remove <addr>               stop sending to the peer
+list                        list the peers with their expiry
+codec <name>                switch the stream to the codec
+shutdown                    stop the service
+help                        list the commands
+";
+
+/// Serve the control interface for the `peers` and the `codec_switch`, if
+/// the codec can be switched, to the clients of the `listener`.
+/// The `shutdown` is triggered when asked to.
+pub async fn serve<A>(
+    listener: TcpListener,
+    peers: PeerRegistry<A>,
+    codec_switch: Option<CodecSwitch>,
+    shutdown: Shutdown,
+) -> Result<futures::never::Never, crate::Error>
+where
+    A: FromStr + Clone + Eq + Hash + Debug,
+    A::Err: Display,
+{
+    let mut connections = FuturesUnordered::new();
+    loop {
+        futures::select! {
+            (stream, addr) = tcp::accept(&listener, "Control").fuse() => {
+                debug!("Control: {} connected", addr);
+                connections.push(handle(stream, addr, &peers, codec_switch.as_ref(), &shutdown));
+            },
+            () = connections.select_next_some() => {}
+        }
+    }
+}
+
+async fn handle<A>(
+    stream: TcpStream,
+    addr: SocketAddr,
+    peers: &PeerRegistry<A>,
+    codec_switch: Option<&CodecSwitch>,
+    shutdown: &Shutdown,
+) where
+    A: FromStr + Clone + Eq + Hash + Debug,
+    A::Err: Display,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let result: io::Result<()> = async {
+        while let Some(line) = lines.next_line().await? {
+            let reply = execute(&line, peers, codec_switch, shutdown, Instant::now());
+            writer.write_all(reply.as_bytes()).await?;
+        }
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => debug!("Control: {} disconnected", addr),
+        Err(err) => warn!("Control: connection with {} failed: {}", addr, err),
+    }
+}
+
+/// Execute the command `line`, and return the reply.
+fn execute<A>(
+    line: &str,
+    peers: &PeerRegistry<A>,
+    codec_switch: Option<&CodecSwitch>,
+    shutdown: &Shutdown,
+    now: Instant,
+) -> String
+where
+    A: FromStr + Clone + Eq + Hash + Debug,
+    A::Err: Display,
+{
+    match run(line, peers, codec_switch, shutdown, now) {
+        Ok(mut output) => {
+            output.push_str("ok\n");
+            output
+        }
+        Err(err) => format!("error: {err}\n"),
+    }
+}
+
+fn run<A>(
+    line: &str,
+    peers: &PeerRegistry<A>,
+    codec_switch: Option<&CodecSwitch>,
+    shutdown: &Shutdown,
+    now: Instant,
+) -> Result<String, String>
+where
+    A: FromStr + Clone + Eq + Hash + Debug,
+    A::Err: Display,
+{
+    let parse_peer = |peer: Option<&str>| {
+        let peer = peer.ok_or("the peer address is missing")?;
+        peer.parse::<A>()
+            .map_err(|err| format!("invalid peer address {peer:?}: {err}"))
+    };
+
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let mut output = String::new();
+    match command {
+        "add" => {
+            let peer = parse_peer(words.next())?;
+            let ttl = words
+                .next()
+                .map(|ttl| {
+                    ttl.parse()
+                        .map(Duration::from_secs)
+                        .map_err(|err| format!("invalid ttl {ttl:?}: {err}"))
+                })
+                .transpose()?;
+            info!("Control: adding peer {:?}, ttl {:?}", peer, ttl);
+            peers.add(peer, ttl).map_err(|err| err.to_string())?;
+        }
+        "remove" => {
+            let peer = parse_peer(words.next())?;
+            if !peers.remove(&peer) {
+                return Err(format!("no such peer: {peer:?}"));
+            }
+            info!("Control: removed peer {:?}", peer);
+        }
+        "list" => {
+            for (peer, expires_at) in peers.list(now) {
+                output += &match expires_at {
+                    None => format!("{peer:?} permanent\n"),
+                    Some(expires_at) => format!(
+                        "{peer:?} expires in {}s\n",
+                        expires_at.saturating_duration_since(now).as_secs()
+                    ),
+                };
+            }
+        }
+        "codec" => {
+            let codec_switch = codec_switch.ok_or("the codec can't be switched")?;
+            let codec = words.next().ok_or("the codec name is missing")?;
+            codec_switch.request(codec).map_err(|err| err.to_string())?;
+            info!("Control: switching to codec {}", codec);
+        }
+        "shutdown" => {
+            info!("Control: shutdown requested");
+            shutdown.trigger();
+        }
+        "help" => output.push_str(HELP),
+        "" => return Err("empty command".to_owned()),
+        command => return Err(format!("unknown command {command:?}, try help")),
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let peers: PeerRegistry<SocketAddr> = PeerRegistry::new();
+        let codec_switch = CodecSwitch::new(vec!["raw".to_owned(), "adpcm".to_owned()]);
+        let shutdown = Shutdown::new();
+        let now = Instant::now();
+        let execute_with_no_switch = |line: &str| execute(line, &peers, None, &shutdown, now);
+        let execute = |line: &str| execute(line, &peers, Some(&codec_switch), &shutdown, now);
+
+        assert_eq!(execute("add 10.0.0.1:80"), "ok\n");
+        assert_eq!(execute("add 10.0.0.2:80 30"), "ok\n");
+        let listed = execute("list");
+        assert!(listed.contains("10.0.0.1:80 permanent\n"));
+        assert!(listed.contains("10.0.0.2:80 expires in "));
+        assert!(listed.ends_with("ok\n"));
+
+        assert_eq!(execute("remove 10.0.0.1:80"), "ok\n");
+        assert!(execute("remove 10.0.0.1:80").starts_with("error: no such peer"));
+        assert!(execute("add nonsense").starts_with("error: invalid peer address"));
+        assert!(execute("add 10.0.0.3:80 soon").starts_with("error: invalid ttl"));
+        assert!(execute(&format!("add 10.0.0.3:80 {}", u64::MAX)).starts_with("error: the ttl of"));
+        assert!(execute("frobnicate").starts_with("error: unknown command"));
+        assert_eq!(peers.current(now).len(), 1);
+
+        assert_eq!(execute("codec adpcm"), "ok\n");
+        assert_eq!(codec_switch.take().as_deref(), Some("adpcm"));
+        assert!(execute("codec opus").starts_with("error: no such codec"));
+        assert!(execute("codec").starts_with("error: the codec name is missing"));
+        assert_eq!(codec_switch.take(), None);
+        assert!(execute_with_no_switch("codec raw").starts_with("error: the codec can't be"));
+
+        assert!(!shutdown.is_triggered());
+        assert_eq!(execute("shutdown"), "ok\n");
+        assert!(shutdown.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn serve_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peers: PeerRegistry<SocketAddr> = PeerRegistry::new();
+        let server = serve(listener, peers.clone(), None, Shutdown::new());
+
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"add 10.0.0.1:80\n").await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            lines.next_line().await.unwrap()
+        };
+
+        futures::pin_mut!(server);
+        futures::pin_mut!(client);
+        let reply = match futures::future::select(server, client).await {
+            futures::future::Either::Right((reply, _)) => reply,
+            futures::future::Either::Left(_) => unreachable!(),
+        };
+        assert_eq!(reply.as_deref(), Some("ok"));
+        assert_eq!(
+            peers.current(Instant::now()),
+            vec!["10.0.0.1:80".parse().unwrap()]
+        );
+    }
+}
@@ -50,3 +50,16 @@ where
             .poll_write_items(cx, items, wait_mode)
     }
 }
+
+/// Writing to a `Vec` appends the items to it, and never blocks.
+impl<T: Unpin + Copy> AsyncWriteItems<T> for Vec<T> {
+    fn poll_write_items(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        items: &[T],
+        _wait_mode: WaitMode,
+    ) -> Poll<Result<usize>> {
+        self.get_mut().extend_from_slice(items);
+        Poll::Ready(Ok(items.len()))
+    }
+}
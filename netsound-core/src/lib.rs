@@ -12,11 +12,15 @@ pub use anyhow::Error;
 pub mod audio_backend;
 pub mod buf;
 pub mod codec;
+pub mod control;
 pub mod io;
 pub mod log;
 pub mod match_channels;
+pub mod metrics;
 pub mod net;
 pub mod pcm;
 pub mod samples_filter;
+pub mod shutdown;
+mod tcp;
 pub mod transcode;
 pub mod transcode_service;
@@ -0,0 +1,395 @@
+//! The metrics of a running instance, served over HTTP in the Prometheus
+//! text exposition format.
+//!
+//! The services publish their stats to the [`Registry`] as they go, at most
+//! once per [`PUBLISH_INTERVAL`], and the scrapes render whatever was
+//! published last.
+
+use crate::buf::FillLevel;
+use crate::log::{debug, warn};
+use crate::pcm;
+use crate::tcp;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Write};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The prefix of all the metric names.
+const NAMESPACE: &str = "netsound";
+
+/// How often the services publish their stats. Publishing serializes all
+/// the stats, which is too much to do for every packet, and the scrapes
+/// come way less often anyway.
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The requests are served one at a time, so a client gets this long to send
+/// the request and read the response before we move on to the next one.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// We only need the request line, the rest of a larger request is ignored.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// The content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The stats of a single entity, as the field name and value pairs.
+type Values = Vec<(String, u64)>;
+
+/// The shared storage of the latest metrics.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The stats by the group name.
+    stats: BTreeMap<&'static str, Values>,
+    /// The per-peer stats by the group name, and then by the peer.
+    peer_stats: BTreeMap<&'static str, BTreeMap<String, Values>>,
+    buffers: BTreeMap<&'static str, FillLevel>,
+    streams: BTreeMap<&'static str, Stream>,
+}
+
+#[derive(Debug)]
+struct Stream {
+    sample_type: &'static str,
+    sample_rate: usize,
+    channels: usize,
+}
+
+impl Registry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish the latest `stats` of the `group`. The numeric fields of
+    /// the `stats` become the `netsound_<group>_<field>` metrics.
+    pub fn set_stats<S: Serialize>(&self, group: &'static str, stats: &S) {
+        let values = values(stats);
+        self.lock().stats.insert(group, values);
+    }
+
+    /// Publish the latest stats of every peer in the `group`, replacing
+    /// the previously published ones, so that the peers that are gone are
+    /// gone from the metrics too. The peers are told apart by the `peer`
+    /// label.
+    pub fn set_peer_stats<'a, A, S, I>(&self, group: &'static str, peer_stats: I)
+    where
+        A: Debug + 'a,
+        S: Serialize + 'a,
+        I: IntoIterator<Item = (&'a A, &'a S)>,
+    {
+        let peer_stats = peer_stats
+            .into_iter()
+            .map(|(peer, stats)| (format!("{peer:?}"), values(stats)))
+            .collect();
+        self.lock().peer_stats.insert(group, peer_stats);
+    }
+
+    /// Report the fill level of the buffer under the `name`.
+    pub fn add_buffer(&self, name: &'static str, fill_level: FillLevel) {
+        self.lock().buffers.insert(name, fill_level);
+    }
+
+    /// Report the stream config under the `name`.
+    pub fn add_stream_config<S: pcm::Sample>(
+        &self,
+        name: &'static str,
+        config: &pcm::StreamConfig<S>,
+    ) {
+        let stream = Stream {
+            sample_type: pcm::StreamConfig::<S>::sample_type_name(),
+            sample_rate: config.sample_rate().as_usize(),
+            channels: config.channels(),
+        };
+        self.lock().streams.insert(name, stream);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let inner = self.lock();
+        let mut out = String::new();
+
+        for (group, values) in &inner.stats {
+            for (field, value) in values {
+                sample(&mut out, &format!("{group}_{field}"), &[], *value);
+            }
+        }
+
+        for (group, peers) in &inner.peer_stats {
+            // All the samples of a metric must go together, so we go over
+            // the fields first, and over the peers second.
+            let Some(fields) = peers.values().next() else {
+                continue;
+            };
+            for (index, (field, _)) in fields.iter().enumerate() {
+                let name = format!("{group}_{field}");
+                for (peer, values) in peers {
+                    if let Some((_, value)) = values.get(index) {
+                        sample(&mut out, &name, &[("peer", peer)], *value);
+                    }
+                }
+            }
+        }
+
+        if !inner.buffers.is_empty() {
+            help(&mut out, "buffer_fill_level", "Items in the buffer.");
+            for (name, fill_level) in &inner.buffers {
+                let value = fill_level.get() as u64;
+                sample(&mut out, "buffer_fill_level", &[("buffer", name)], value);
+            }
+            help(&mut out, "buffer_capacity", "Items the buffer can hold.");
+            for (name, fill_level) in &inner.buffers {
+                let value = fill_level.capacity() as u64;
+                sample(&mut out, "buffer_capacity", &[("buffer", name)], value);
+            }
+        }
+
+        if !inner.streams.is_empty() {
+            help(&mut out, "stream_sample_rate", "Negotiated sample rate.");
+            for (name, stream) in &inner.streams {
+                let labels = [("stream", *name), ("sample_type", stream.sample_type)];
+                let value = stream.sample_rate as u64;
+                sample(&mut out, "stream_sample_rate", &labels, value);
+            }
+            help(
+                &mut out,
+                "stream_channels",
+                "Negotiated amount of channels.",
+            );
+            for (name, stream) in &inner.streams {
+                let labels = [("stream", *name), ("sample_type", stream.sample_type)];
+                let value = stream.channels as u64;
+                sample(&mut out, "stream_channels", &labels, value);
+            }
+        }
+
+        out
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Tells the services when to publish their stats next.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    next_publish: Option<Instant>,
+}
+
+impl Throttle {
+    /// Whether the stats are due to be published at `now`. Once they are,
+    /// the next publishing is scheduled.
+    pub fn due(&mut self, now: Instant) -> bool {
+        if self.next_publish.map_or(false, |at| now < at) {
+            return false;
+        }
+        self.next_publish = Some(now + PUBLISH_INTERVAL);
+        true
+    }
+}
+
+/// Serve the metrics of the `registry` at `/metrics` to the clients of
+/// the `listener`.
+pub async fn serve(
+    listener: TcpListener,
+    registry: Registry,
+) -> Result<futures::never::Never, crate::Error> {
+    loop {
+        let (stream, addr) = tcp::accept(&listener, "Metrics").await;
+        debug!("Metrics: serving {}", addr);
+        match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &registry)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("Metrics: failed to serve {}: {}", addr, err),
+            Err(_) => warn!("Metrics: timed out serving {}", addr),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, registry: &Registry) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0_u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n")
+        && request.len() < MAX_REQUEST_SIZE
+    {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let request_line = request
+        .split(|&byte| byte == b'\r' || byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    let mut parts = request_line.split(|&byte| byte == b' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split(|&byte| byte == b'?').next().unwrap_or_default();
+
+    let (status, body) = match (method, path) {
+        (b"GET", b"/metrics") => ("200 OK", registry.render()),
+        (b"GET", _) => ("404 Not Found", "Not Found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {CONTENT_TYPE}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len(),
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Take the numeric fields of the `stats`.
+fn values<S: Serialize>(stats: &S) -> Values {
+    match serde_json::to_value(stats) {
+        Ok(serde_json::Value::Object(fields)) => fields
+            .into_iter()
+            .filter_map(|(field, value)| Some((field, value.as_u64()?)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn help(out: &mut String, name: &str, help: &str) {
+    // Writing to a string never fails.
+    let _ = writeln!(out, "# HELP {NAMESPACE}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {NAMESPACE}_{name} gauge");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    let _ = write!(out, "{NAMESPACE}_{name}");
+    if !labels.is_empty() {
+        out.push('{');
+        for (index, (label, value)) in labels.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{label}=\"{}\"", escape(value));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+/// Escape the label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buf;
+    use crate::io::{AsyncWriteItemsExt, WaitMode};
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Stats {
+        packets: usize,
+        bytes: usize,
+    }
+
+    #[test]
+    fn render() {
+        let registry = Registry::new();
+        registry.set_stats(
+            "send",
+            &Stats {
+                packets: 1,
+                bytes: 10,
+            },
+        );
+
+        let mut peer_stats = HashMap::new();
+        peer_stats.insert(
+            "a\"b",
+            Stats {
+                packets: 2,
+                bytes: 20,
+            },
+        );
+        registry.set_peer_stats("recv_peer", &peer_stats);
+
+        let (mut writer, _reader) = buf::vec_deque_buffer_with_capacity::<f32>(16);
+        futures::executor::block_on(writer.write_items(&[0.0; 3], WaitMode::NoWait)).unwrap();
+        registry.add_buffer("playback", writer.fill_level());
+
+        registry.add_stream_config("net", &pcm::StreamConfig::<f32>::new(48000.into(), 2));
+
+        let rendered = registry.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        for expected in [
+            "netsound_send_bytes 10",
+            "netsound_send_packets 1",
+            r#"netsound_recv_peer_packets{peer="\"a\\\"b\""} 2"#,
+            r#"netsound_buffer_fill_level{buffer="playback"} 3"#,
+            r#"netsound_stream_sample_rate{stream="net",sample_type="f32"} 48000"#,
+            r#"netsound_stream_channels{stream="net",sample_type="f32"} 2"#,
+        ] {
+            assert!(lines.contains(&expected), "{} in {}", expected, rendered);
+        }
+    }
+
+    #[test]
+    fn throttle() {
+        let mut throttle = Throttle::default();
+        let now = Instant::now();
+        assert!(throttle.due(now));
+        assert!(!throttle.due(now + PUBLISH_INTERVAL / 2));
+        assert!(throttle.due(now + PUBLISH_INTERVAL));
+        assert!(!throttle.due(now + PUBLISH_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn serve_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Registry::new();
+        registry.set_stats(
+            "send",
+            &Stats {
+                packets: 1,
+                bytes: 10,
+            },
+        );
+        let server = serve(listener, registry);
+
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        futures::pin_mut!(server);
+        futures::pin_mut!(client);
+        let response = match futures::future::select(server, client).await {
+            futures::future::Either::Right((response, _)) => response,
+            futures::future::Either::Left(_) => unreachable!(),
+        };
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("netsound_send_bytes 10\nnetsound_send_packets 1\n"));
+    }
+}
@@ -0,0 +1,387 @@
+//! Authenticated encryption of the packets with a pre-shared key.
+//!
+//! The packet header is sent in the clear, but is authenticated along with
+//! the payload. The payload is encrypted with ChaCha20-Poly1305, and is
+//! prefixed with the nonce and followed by the authentication tag.
+//!
+//! ```text
+//! +--------------------+--------------------+--------------------+-----------+
+//! | header (12 bytes)  |  nonce (12 bytes)  |  encrypted payload | tag (16)  |
+//! +--------------------+--------------------+--------------------+-----------+
+//! ```
+//!
+//! The nonce consists of a random salt, chosen by the sender at startup, and
+//! a counter. The counter is the wall clock time in microseconds, forced to
+//! increase with every packet, so it keeps growing across the sender
+//! restarts, and lets the receiver reject the stale packets.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use super::packet::HEADER_SIZE;
+
+/// The size of the key, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// The size of the nonce on the wire, in bytes.
+pub const NONCE_SIZE: usize = 12;
+
+/// The size of the authentication tag, in bytes.
+pub const TAG_SIZE: usize = 16;
+
+/// How much larger the packet becomes after the encryption.
+pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+const SALT_SIZE: usize = 4;
+
+/// How far the counter of the packet from a new sender is allowed to be
+/// from our clock.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// How many of the most recent counters we remember per sender, for
+/// detecting replays.
+const REPLAY_WINDOW: u64 = 64;
+
+/// The pre-shared key.
+#[derive(Clone)]
+pub struct Key([u8; KEY_SIZE]);
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// An error that can occur while parsing the key.
+#[derive(Error, Debug)]
+pub enum KeyParseError {
+    #[error("the key must be hex-encoded: {0}")]
+    Hex(#[from] hex::FromHexError),
+    #[error("the key must be {KEY_SIZE} bytes long, got {0} bytes")]
+    InvalidLength(usize),
+}
+
+impl FromStr for Key {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s.trim())?;
+        let len = bytes.len();
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| KeyParseError::InvalidLength(len))?;
+        Ok(Self(bytes))
+    }
+}
+
+/// An error that can occur while sealing or opening a packet.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("packet is too short: {0} bytes")]
+    TooShort(usize),
+    #[error("no room for the encryption overhead")]
+    NoRoom,
+    #[error("encryption failed")]
+    Encryption,
+    #[error("packet failed authentication")]
+    Authentication,
+    #[error("packet was replayed")]
+    Replayed,
+    #[error("packet is stale")]
+    Stale,
+}
+
+/// Encrypts the outgoing packets.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    counter: u64,
+}
+
+impl std::fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sealer")
+            .field("salt", &self.salt)
+            .field("counter", &self.counter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sealer {
+    #[must_use]
+    pub fn new(key: &Key) -> Self {
+        let mut salt = [0_u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.0.into()),
+            salt,
+            counter: 0,
+        }
+    }
+
+    /// Encrypt the plain packet of `len` bytes at the beginning of the `buf`
+    /// in place, and return the size of the encrypted packet.
+    pub fn seal(&mut self, buf: &mut [u8], len: usize, now: SystemTime) -> Result<usize, Error> {
+        if len < HEADER_SIZE {
+            return Err(Error::TooShort(len));
+        }
+        let sealed_len = len + OVERHEAD;
+        if buf.len() < sealed_len {
+            return Err(Error::NoRoom);
+        }
+
+        self.counter = std::cmp::max(self.counter + 1, unix_micros(now));
+        let nonce = make_nonce(self.salt, self.counter);
+
+        let payload_start = HEADER_SIZE + NONCE_SIZE;
+        let payload_end = len + NONCE_SIZE;
+        buf.copy_within(HEADER_SIZE..len, payload_start);
+        buf[HEADER_SIZE..payload_start].copy_from_slice(&nonce);
+
+        let (header, rest) = buf.split_at_mut(HEADER_SIZE);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                header,
+                &mut rest[NONCE_SIZE..payload_end - HEADER_SIZE],
+            )
+            .map_err(|_| Error::Encryption)?;
+        buf[payload_end..sealed_len].copy_from_slice(&tag);
+        Ok(sealed_len)
+    }
+}
+
+/// Decrypts and authenticates the incoming packets, and rejects
+/// the replayed ones.
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    /// The replay windows of the senders, by salt.
+    windows: HashMap<[u8; SALT_SIZE], ReplayWindow>,
+}
+
+impl std::fmt::Debug for Opener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Opener")
+            .field("windows", &self.windows)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Opener {
+    #[must_use]
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.0.into()),
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Decrypt the packet in the `buf` in place, and return the size of
+    /// the plain packet at the beginning of the `buf`.
+    pub fn open(&mut self, buf: &mut [u8], now: SystemTime) -> Result<usize, Error> {
+        let len = buf.len();
+        if len < HEADER_SIZE + OVERHEAD {
+            return Err(Error::TooShort(len));
+        }
+
+        let payload_start = HEADER_SIZE + NONCE_SIZE;
+        let payload_end = len - TAG_SIZE;
+        let nonce = Nonce::clone_from_slice(&buf[HEADER_SIZE..payload_start]);
+        let (salt, counter) = split_nonce(&nonce);
+
+        let now = unix_micros(now);
+        match self.windows.get(&salt) {
+            Some(window) => window.check(counter)?,
+            None => {
+                if now.abs_diff(counter) > duration_micros(MAX_CLOCK_SKEW) {
+                    return Err(Error::Stale);
+                }
+            }
+        }
+
+        let tag = Tag::clone_from_slice(&buf[payload_end..]);
+        let (header, rest) = buf.split_at_mut(HEADER_SIZE);
+        self.cipher
+            .decrypt_in_place_detached(
+                &nonce,
+                header,
+                &mut rest[NONCE_SIZE..payload_end - HEADER_SIZE],
+                &tag,
+            )
+            .map_err(|_| Error::Authentication)?;
+
+        if !self.windows.contains_key(&salt) {
+            self.expire_windows(now);
+        }
+        self.windows
+            .entry(salt)
+            .or_insert_with(|| ReplayWindow::new(counter))
+            .update(counter);
+
+        buf.copy_within(payload_start..payload_end, HEADER_SIZE);
+        Ok(payload_end - NONCE_SIZE)
+    }
+
+    /// Forget the senders that went silent. Their packets are stale by now.
+    fn expire_windows(&mut self, now: u64) {
+        let max_age = duration_micros(MAX_CLOCK_SKEW);
+        self.windows
+            .retain(|_, window| now.saturating_sub(window.highest) <= max_age);
+    }
+}
+
+/// The sliding window of the recently seen counters.
+#[derive(Debug)]
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `n` is set if the counter `highest - n` was seen.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn new(counter: u64) -> Self {
+        Self {
+            highest: counter,
+            seen: 0,
+        }
+    }
+
+    fn check(&self, counter: u64) -> Result<(), Error> {
+        if counter > self.highest {
+            return Ok(());
+        }
+        let behind = self.highest - counter;
+        if behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0 {
+            return Err(Error::Replayed);
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, counter: u64) {
+        if counter > self.highest {
+            let ahead = counter - self.highest;
+            self.seen = if ahead < REPLAY_WINDOW {
+                self.seen << ahead
+            } else {
+                0
+            };
+            self.highest = counter;
+            self.seen |= 1;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+fn make_nonce(salt: [u8; SALT_SIZE], counter: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0_u8; NONCE_SIZE];
+    nonce[..SALT_SIZE].copy_from_slice(&salt);
+    nonce[SALT_SIZE..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn split_nonce(nonce: &[u8]) -> ([u8; SALT_SIZE], u64) {
+    let mut salt = [0_u8; SALT_SIZE];
+    salt.copy_from_slice(&nonce[..SALT_SIZE]);
+    let mut counter = [0_u8; 8];
+    counter.copy_from_slice(&nonce[SALT_SIZE..]);
+    (salt, u64::from_be_bytes(counter))
+}
+
+fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, duration_micros)
+}
+
+fn duration_micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            .parse()
+            .unwrap()
+    }
+
+    fn sealed_packet(sealer: &mut Sealer, now: SystemTime) -> Vec<u8> {
+        let mut buf = vec![0_u8; 64];
+        buf[..HEADER_SIZE].copy_from_slice(&[1; HEADER_SIZE]);
+        buf[HEADER_SIZE..HEADER_SIZE + 3].copy_from_slice(&[7, 8, 9]);
+        let len = sealer.seal(&mut buf, HEADER_SIZE + 3, now).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn roundtrip() {
+        let now = SystemTime::now();
+        let mut sealer = Sealer::new(&key());
+        let mut opener = Opener::new(&key());
+
+        let mut packet = sealed_packet(&mut sealer, now);
+        assert_eq!(packet.len(), HEADER_SIZE + 3 + OVERHEAD);
+        assert_ne!(&packet[HEADER_SIZE + NONCE_SIZE..][..3], &[7, 8, 9]);
+
+        let len = opener.open(&mut packet, now).unwrap();
+        assert_eq!(&packet[..len - 3], &[1; HEADER_SIZE]);
+        assert_eq!(&packet[HEADER_SIZE..len], &[7, 8, 9]);
+    }
+
+    #[test]
+    fn tampering() {
+        let now = SystemTime::now();
+        let mut sealer = Sealer::new(&key());
+        let mut opener = Opener::new(&key());
+
+        let mut packet = sealed_packet(&mut sealer, now);
+        packet[0] ^= 1;
+        assert_eq!(opener.open(&mut packet, now), Err(Error::Authentication));
+
+        let other_key: Key = "ff".repeat(KEY_SIZE).parse().unwrap();
+        let mut packet = sealed_packet(&mut Sealer::new(&other_key), now);
+        assert_eq!(opener.open(&mut packet, now), Err(Error::Authentication));
+    }
+
+    #[test]
+    fn replay() {
+        let now = SystemTime::now();
+        let mut sealer = Sealer::new(&key());
+        let mut opener = Opener::new(&key());
+
+        let first = sealed_packet(&mut sealer, now);
+        let second = sealed_packet(&mut sealer, now);
+
+        assert!(opener.open(&mut second.clone(), now).is_ok());
+        assert!(opener.open(&mut first.clone(), now).is_ok());
+        assert_eq!(opener.open(&mut first.clone(), now), Err(Error::Replayed));
+        assert_eq!(opener.open(&mut second.clone(), now), Err(Error::Replayed));
+    }
+
+    #[test]
+    fn stale() {
+        let now = SystemTime::now();
+        let mut opener = Opener::new(&key());
+        let mut packet = sealed_packet(&mut Sealer::new(&key()), now);
+        assert_eq!(
+            opener.open(&mut packet, now + Duration::from_secs(3600)),
+            Err(Error::Stale)
+        );
+    }
+
+    #[test]
+    fn key_parsing() {
+        assert!("00".parse::<Key>().is_err());
+        assert!("zz".repeat(KEY_SIZE).parse::<Key>().is_err());
+        assert!("00".repeat(KEY_SIZE).parse::<Key>().is_ok());
+    }
+}
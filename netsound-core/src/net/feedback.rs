@@ -0,0 +1,447 @@
+//! The feedback the receivers send back to the senders about the network
+//! conditions, and the adaptation of the encoding to them at the sender.
+//!
+//! Every receiver periodically reports the packet loss and the jitter it
+//! observes for each of the senders it receives from. The report is carried
+//! in the packet of the [`packet::PayloadType::Feedback`] payload type:
+//!
+//! ```text
+//!  0               1               2               3
+//! +---------------+---------------+---------------+---------------+
+//! |  loss percent |                   jitter, us                  |
+//! +---------------+-------------------------------+---------------+
+//! |   jitter, us  |
+//! +---------------+
+//! ```
+//!
+//! The sender's [`Controller`] takes the worst of the recent reports of its
+//! peers, and lowers the bitrate while the network is struggling, raising
+//! it back slowly once it recovers.
+//!
+//! Only the native framing carries the feedback.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+use super::crypto;
+use super::packet;
+use super::PeerStats;
+use crate::codec::Adaptation;
+
+/// How often the receivers report to every sender.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The reports older than this are not taken into account.
+const REPORT_MAX_AGE: Duration = Duration::from_secs(5);
+
+/// How often the sender revises the encoding.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The size of the report on the wire, in bytes.
+const REPORT_SIZE: usize = 5;
+
+/// The loss above which we back off.
+const HIGH_LOSS_PERC: u8 = 10;
+
+/// The loss below which we speed up.
+const LOW_LOSS_PERC: u8 = 2;
+
+/// The jitter above which we back off, as it means the queues along the way
+/// are filling up.
+const HIGH_JITTER_US: u32 = 40_000;
+
+/// The jitter below which we speed up.
+const LOW_JITTER_US: u32 = 20_000;
+
+/// How much we raise the bitrate by at a time, in bits per second.
+const BITRATE_STEP: u32 = 8_000;
+
+/// The report of a receiver on the stream of a single sender.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// The percentage of the packets lost since the previous report.
+    pub loss_perc: u8,
+    /// The jitter of the packet arrival, in microseconds.
+    pub jitter_us: u32,
+}
+
+/// An error that can occur while reading a report.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("feedback report is too short: {0} bytes")]
+    TooShort(usize),
+}
+
+impl Report {
+    /// Encode the report to the end of the `buf`.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let mut report = [0; REPORT_SIZE];
+        report[0] = self.loss_perc;
+        BigEndian::write_u32(&mut report[1..], self.jitter_us);
+        buf.extend_from_slice(&report);
+    }
+
+    /// Decode the report.
+    pub fn read(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < REPORT_SIZE {
+            return Err(Error::TooShort(buf.len()));
+        }
+        Ok(Self {
+            loss_perc: buf[0].min(100),
+            jitter_us: BigEndian::read_u32(&buf[1..REPORT_SIZE]),
+        })
+    }
+}
+
+/// The counters at the time of the previous report.
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    at: Instant,
+    packets_read: usize,
+    packets_lost: usize,
+}
+
+/// Produces the reports on the senders we receive from.
+#[derive(Debug)]
+pub struct Reporter<A> {
+    /// Encrypts the report packets, if set.
+    pub cipher: Option<crypto::Sealer>,
+    snapshots: HashMap<A, Snapshot>,
+}
+
+impl<A: Clone + Eq + Hash> Reporter<A> {
+    #[must_use]
+    pub fn new(cipher: Option<crypto::Sealer>) -> Self {
+        Self {
+            cipher,
+            snapshots: HashMap::new(),
+        }
+    }
+
+    /// Build the report on the `peer` if it's due at `now`, covering
+    /// the packets that came since the previous one. There's nothing to
+    /// report if no packets came.
+    pub fn report(&mut self, peer: &A, stats: &PeerStats, now: Instant) -> Option<Report> {
+        let current = Snapshot {
+            at: now,
+            packets_read: stats.packets_read,
+            packets_lost: stats.packets_lost,
+        };
+        let previous = match self.snapshots.get(peer) {
+            None => {
+                self.snapshots.insert(peer.clone(), current);
+                return None;
+            }
+            Some(previous) if now < previous.at + REPORT_INTERVAL => return None,
+            Some(previous) => *previous,
+        };
+        self.snapshots.insert(peer.clone(), current);
+
+        let read = current.packets_read.saturating_sub(previous.packets_read);
+        let lost = current.packets_lost.saturating_sub(previous.packets_lost);
+        if read == 0 {
+            return None;
+        }
+        Some(Report {
+            loss_perc: percentage(lost, read + lost),
+            jitter_us: u32::try_from(stats.jitter_us).unwrap_or(u32::MAX),
+        })
+    }
+
+    /// Forget the `peer` that's gone.
+    pub fn forget(&mut self, peer: &A) {
+        self.snapshots.remove(peer);
+    }
+
+    /// Build the packet carrying the `report`.
+    pub fn packet(&mut self, report: &Report) -> Result<Vec<u8>, crypto::Error> {
+        let mut buf = vec![0; packet::HEADER_SIZE];
+        packet::Header {
+            payload_type: packet::PayloadType::Feedback,
+            stream: 0,
+            sequence: 0,
+            timestamp: 0,
+        }
+        .write(&mut buf);
+        report.write(&mut buf);
+
+        if let Some(cipher) = &mut self.cipher {
+            let len = buf.len();
+            buf.resize(len + crypto::OVERHEAD, 0);
+            let sealed = cipher.seal(&mut buf, len, SystemTime::now())?;
+            buf.truncate(sealed);
+        }
+        Ok(buf)
+    }
+}
+
+/// The latest reports of the receivers, shared between the receiving side
+/// that gets them and the sending side that acts on them.
+#[derive(Debug)]
+pub struct Reports<A> {
+    reports: Arc<Mutex<HashMap<A, (Report, Instant)>>>,
+}
+
+// Derived `Clone` would require `A: Clone`, which is not needed.
+impl<A> Clone for Reports<A> {
+    fn clone(&self) -> Self {
+        Self {
+            reports: Arc::clone(&self.reports),
+        }
+    }
+}
+
+impl<A> Default for Reports<A> {
+    fn default() -> Self {
+        Self {
+            reports: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<A: Eq + Hash> Reports<A> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store the `report` of the `peer` received at `now`, replacing
+    /// the previous one.
+    pub fn insert(&self, peer: A, report: Report, now: Instant) {
+        self.lock().insert(peer, (report, now));
+    }
+
+    /// The worst loss and the worst jitter among the recent reports of
+    /// the `peers`, if there are any. Forgets the stale reports.
+    #[must_use]
+    pub fn worst(&self, peers: &[A], now: Instant) -> Option<Report> {
+        let mut reports = self.lock();
+        reports.retain(|_, (_, received_at)| {
+            now.saturating_duration_since(*received_at) <= REPORT_MAX_AGE
+        });
+        peers
+            .iter()
+            .filter_map(|peer| reports.get(peer))
+            .map(|(report, _)| *report)
+            .reduce(|worst, report| Report {
+                loss_perc: worst.loss_perc.max(report.loss_perc),
+                jitter_us: worst.jitter_us.max(report.jitter_us),
+            })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<A, (Report, Instant)>> {
+        self.reports.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The bounds of the adaptation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerConfig {
+    /// The bitrate we never go below, in bits per second.
+    pub min_bitrate: u32,
+    /// The bitrate we never go above, in bits per second.
+    pub max_bitrate: u32,
+    /// The bitrate we start with, in bits per second.
+    pub start_bitrate: u32,
+    /// The expected packet loss percentage we never go below. The forward
+    /// error correction is always on if it's set.
+    pub min_expected_packet_loss_perc: u8,
+}
+
+/// Adapts the encoding of the sender to the reports of its peers.
+///
+/// The bitrate is cut by 15% whenever any of the peers reports the high
+/// loss or jitter, and raised by a fixed step while all of them report
+/// the low ones. The forward error correction follows the reported loss.
+#[derive(Debug)]
+pub struct Controller<A> {
+    reports: Reports<A>,
+    config: ControllerConfig,
+    current: Adaptation,
+    next_update: Option<Instant>,
+}
+
+impl<A: Eq + Hash> Controller<A> {
+    #[must_use]
+    pub fn new(reports: Reports<A>, config: ControllerConfig) -> Self {
+        let current = Adaptation {
+            bitrate: config
+                .start_bitrate
+                .clamp(config.min_bitrate, config.max_bitrate),
+            expected_packet_loss_perc: config.min_expected_packet_loss_perc,
+            fec: config.min_expected_packet_loss_perc > 0,
+        };
+        Self {
+            reports,
+            config,
+            current,
+            next_update: None,
+        }
+    }
+
+    /// The encoding settings to use now.
+    #[must_use]
+    pub fn current(&self) -> Adaptation {
+        self.current
+    }
+
+    /// Revise the encoding if it's time to at `now`, and return the new
+    /// settings if they've changed.
+    pub fn update(&mut self, peers: &[A], now: Instant) -> Option<Adaptation> {
+        if self
+            .next_update
+            .map_or(false, |next_update| now < next_update)
+        {
+            return None;
+        }
+        self.next_update = Some(now + UPDATE_INTERVAL);
+
+        let worst = self.reports.worst(peers, now)?;
+        let bitrate = if worst.loss_perc > HIGH_LOSS_PERC || worst.jitter_us > HIGH_JITTER_US {
+            let cut = u64::from(self.current.bitrate) * 85 / 100;
+            u32::try_from(cut).unwrap_or(u32::MAX)
+        } else if worst.loss_perc <= LOW_LOSS_PERC && worst.jitter_us < LOW_JITTER_US {
+            self.current.bitrate.saturating_add(BITRATE_STEP)
+        } else {
+            self.current.bitrate
+        };
+        let expected_packet_loss_perc = worst
+            .loss_perc
+            .max(self.config.min_expected_packet_loss_perc);
+        let adaptation = Adaptation {
+            bitrate: bitrate.clamp(self.config.min_bitrate, self.config.max_bitrate),
+            expected_packet_loss_perc,
+            fec: expected_packet_loss_perc > 0,
+        };
+
+        if adaptation == self.current {
+            return None;
+        }
+        self.current = adaptation;
+        Some(adaptation)
+    }
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+fn percentage(part: usize, total: usize) -> u8 {
+    (part as f64 * 100.0 / total as f64).round().min(100.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_roundtrip() {
+        let report = Report {
+            loss_perc: 7,
+            jitter_us: 0x0102_0304,
+        };
+        let mut buf = Vec::new();
+        report.write(&mut buf);
+        assert_eq!(buf, [7, 1, 2, 3, 4]);
+        assert_eq!(Report::read(&buf), Ok(report));
+        assert_eq!(Report::read(&buf[..4]), Err(Error::TooShort(4)));
+    }
+
+    #[test]
+    fn reporter() {
+        let mut reporter = Reporter::new(None);
+        let now = Instant::now();
+        let mut stats = PeerStats {
+            packets_read: 10,
+            jitter_us: 5_000,
+            ..PeerStats::default()
+        };
+        assert_eq!(reporter.report(&1, &stats, now), None);
+
+        stats.packets_read = 100;
+        stats.packets_lost = 10;
+        assert_eq!(reporter.report(&1, &stats, now), None);
+        let later = now + REPORT_INTERVAL;
+        assert_eq!(
+            reporter.report(&1, &stats, later),
+            Some(Report {
+                loss_perc: 10,
+                jitter_us: 5_000,
+            })
+        );
+
+        // Nothing came since.
+        assert_eq!(reporter.report(&1, &stats, later + REPORT_INTERVAL), None);
+    }
+
+    #[test]
+    fn controller() {
+        let reports = Reports::new();
+        let config = ControllerConfig {
+            min_bitrate: 16_000,
+            max_bitrate: 64_000,
+            start_bitrate: 32_000,
+            min_expected_packet_loss_perc: 0,
+        };
+        let mut controller = Controller::new(reports.clone(), config);
+        let mut now = Instant::now();
+        assert_eq!(controller.update(&[1, 2], now), None);
+
+        let clean = Report {
+            loss_perc: 0,
+            jitter_us: 1_000,
+        };
+        now += UPDATE_INTERVAL;
+        reports.insert(1, clean, now);
+        reports.insert(2, clean, now);
+        assert_eq!(
+            controller.update(&[1, 2], now),
+            Some(Adaptation {
+                bitrate: 40_000,
+                expected_packet_loss_perc: 0,
+                fec: false,
+            })
+        );
+        // Too early for another update.
+        assert_eq!(controller.update(&[1, 2], now), None);
+
+        now += UPDATE_INTERVAL;
+        reports.insert(
+            2,
+            Report {
+                loss_perc: 20,
+                jitter_us: 1_000,
+            },
+            now,
+        );
+        assert_eq!(
+            controller.update(&[1, 2], now),
+            Some(Adaptation {
+                bitrate: 34_000,
+                expected_packet_loss_perc: 20,
+                fec: true,
+            })
+        );
+
+        // The lossy peer is gone.
+        now += UPDATE_INTERVAL;
+        assert_eq!(controller.update(&[1], now).map(|a| a.fec), Some(false));
+
+        for _ in 0..10 {
+            now += UPDATE_INTERVAL;
+            reports.insert(1, clean, now);
+            controller.update(&[1], now);
+        }
+        assert_eq!(controller.current().bitrate, 64_000);
+
+        // The reports went stale.
+        now += REPORT_MAX_AGE + UPDATE_INTERVAL;
+        assert_eq!(controller.update(&[1], now), None);
+    }
+}
@@ -0,0 +1,673 @@
+//! The handshake the peers do at the startup to agree on the codec and
+//! the stream params.
+//!
+//! Every peer advertises the codecs it supports, along with the sample rates
+//! and the amount of channels, in an [`Offer`]. Once a peer has the offers of
+//! all the peers it sends to, it picks the [`Params`] that all of them
+//! support. Every peer picks by the same rules from the same offers, so they
+//! arrive at the same params without another round trip.
+//!
+//! The peers that are already streaming answer with the [`Params`] they use
+//! instead, and the joining peer adopts them.
+//!
+//! The messages are carried by the native packets with the
+//! [`packet::PayloadType::Control`] payload type:
+//!
+//! ```text
+//! message      = kind:u8 (offer | params)
+//! offer        = count:u8 (name sample_rates max_channels:u16)*
+//! sample_rates = count:u8 sample_rate:u32*
+//! params       = name sample_rate:u32 channels:u16
+//! name         = length:u8 utf8
+//! ```
+//!
+//! All the multibyte fields are big-endian.
+
+use crate::log::{debug, info, warn};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+use super::crypto;
+use super::packet;
+use super::{Transport, SIZE};
+
+/// How often we repeat the hello to the peers that haven't answered yet.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The codec support advertised by a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecOffer {
+    /// The name of the codec.
+    pub name: String,
+    /// The sample rates the codec can run at.
+    pub sample_rates: Vec<u32>,
+    /// The max amount of the interleaved channels the codec can carry.
+    pub max_channels: u16,
+}
+
+/// The codecs a peer supports, in the order of its preference.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Offer {
+    pub codecs: Vec<CodecOffer>,
+}
+
+/// The agreed codec and stream params.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Params {
+    /// The name of the codec.
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// The handshake message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Advertises the codecs and asks for the reply.
+    Hello(Offer),
+    /// The reply of a peer that's negotiating too.
+    Offer(Offer),
+    /// The reply of a peer that's already streaming with these params.
+    Session(Params),
+}
+
+/// An error that can occur during the handshake.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("malformed handshake message")]
+    Malformed,
+    #[error("no codec and stream params are supported by all the peers: {0}")]
+    NothingInCommon(String),
+    #[error("peer {peer} streams {params}, which we don't support")]
+    UnsupportedSession { peer: String, params: Params },
+    #[error("peers stream with different params: {0}")]
+    ConflictingSessions(String),
+    #[error("peers didn't answer the handshake in time: {0}")]
+    TimedOut(String),
+}
+
+impl Offer {
+    /// Whether the `params` are among the supported ones.
+    #[must_use]
+    pub fn supports(&self, params: &Params) -> bool {
+        self.codecs.iter().any(|codec| {
+            codec.name == params.codec
+                && codec.sample_rates.contains(&params.sample_rate)
+                && (1..=codec.max_channels).contains(&params.channels)
+        })
+    }
+}
+
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, codec) in self.codecs.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(
+                f,
+                "{} @ {:?} Hz, up to {} channels",
+                codec.name, codec.sample_rates, codec.max_channels
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Params {
+    /// Append the encoded params to the `buf`.
+    ///
+    /// The codec name longer than 255 is cut short.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        write_name(buf, &self.codec);
+        write_u32(buf, self.sample_rate);
+        write_u16(buf, self.channels);
+    }
+
+    /// Decode the params.
+    pub fn read(buf: &[u8]) -> Result<Self, Error> {
+        Reader(buf).params()
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} @ {} Hz, {} channels",
+            self.codec, self.sample_rate, self.channels
+        )
+    }
+}
+
+/// Pick the params supported by all the `offers`.
+///
+/// Among the codecs everyone supports, we pick the one preferred the most
+/// over all the offers, at the highest common sample rate, with as many
+/// channels as everyone can carry. The result doesn't depend on the order of
+/// the offers.
+#[must_use]
+pub fn agree(offers: &[&Offer]) -> Option<Params> {
+    offers
+        .first()?
+        .codecs
+        .iter()
+        .filter_map(|codec| {
+            let mut rank = 0;
+            let mut sample_rates = codec.sample_rates.clone();
+            let mut channels = codec.max_channels;
+            for offer in offers {
+                let (position, other) = offer
+                    .codecs
+                    .iter()
+                    .enumerate()
+                    .find(|(_, other)| other.name == codec.name)?;
+                rank += position;
+                sample_rates.retain(|rate| other.sample_rates.contains(rate));
+                channels = channels.min(other.max_channels);
+            }
+            let sample_rate = sample_rates.into_iter().max()?;
+            if channels == 0 {
+                return None;
+            }
+            let params = Params {
+                codec: codec.name.clone(),
+                sample_rate,
+                channels,
+            };
+            Some((rank, params))
+        })
+        .min_by(|(a_rank, a), (b_rank, b)| a_rank.cmp(b_rank).then_with(|| a.codec.cmp(&b.codec)))
+        .map(|(_, params)| params)
+}
+
+impl Message {
+    const HELLO: u8 = 0;
+    const OFFER: u8 = 1;
+    const SESSION: u8 = 2;
+
+    /// Append the encoded message to the `buf`.
+    ///
+    /// The lists and the names longer than 255 are cut short.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Hello(offer) => {
+                buf.push(Self::HELLO);
+                write_offer(buf, offer);
+            }
+            Self::Offer(offer) => {
+                buf.push(Self::OFFER);
+                write_offer(buf, offer);
+            }
+            Self::Session(params) => {
+                buf.push(Self::SESSION);
+                params.write(buf);
+            }
+        }
+    }
+
+    /// Decode the message.
+    pub fn read(buf: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader(buf);
+        let message = match reader.u8()? {
+            Self::HELLO => Self::Hello(reader.offer()?),
+            Self::OFFER => Self::Offer(reader.offer()?),
+            Self::SESSION => Self::Session(reader.params()?),
+            _ => return Err(Error::Malformed),
+        };
+        Ok(message)
+    }
+}
+
+/// Build the packet carrying the `message`, encrypted if the `cipher` is
+/// set.
+pub fn packet(
+    message: &Message,
+    cipher: Option<&mut crypto::Sealer>,
+) -> Result<Vec<u8>, crypto::Error> {
+    let mut buf = vec![0; packet::HEADER_SIZE];
+    packet::Header {
+        payload_type: packet::PayloadType::Control,
+        stream: 0,
+        sequence: 0,
+        timestamp: 0,
+    }
+    .write(&mut buf);
+    message.write(&mut buf);
+
+    if let Some(cipher) = cipher {
+        let len = buf.len();
+        buf.resize(len + crypto::OVERHEAD, 0);
+        let sealed = cipher.seal(&mut buf, len, SystemTime::now())?;
+        buf.truncate(sealed);
+    }
+    Ok(buf)
+}
+
+/// Answers the handshakes of the peers that join while we're streaming.
+#[derive(Debug)]
+pub struct Responder {
+    /// The params we stream with.
+    pub params: Params,
+    /// Encrypts the replies, if set.
+    pub cipher: Option<crypto::Sealer>,
+}
+
+impl Responder {
+    /// Build the reply to the handshake `message`, if it wants one.
+    pub fn reply(&mut self, message: &Message) -> Result<Option<Vec<u8>>, crypto::Error> {
+        match message {
+            Message::Hello(_) => {
+                let session = Message::Session(self.params.clone());
+                packet(&session, self.cipher.as_mut()).map(Some)
+            }
+            Message::Offer(_) | Message::Session(_) => Ok(None),
+        }
+    }
+}
+
+/// Do the handshake with the `peers`, and return the params to stream with.
+///
+/// Waits for all the `peers` to answer, for up to the `timeout`.
+pub async fn negotiate<T: Transport + ?Sized>(
+    socket: &T,
+    peers: &[T::Addr],
+    offer: &Offer,
+    key: Option<&crypto::Key>,
+    timeout: Duration,
+) -> Result<Params, crate::Error> {
+    let mut sealer = key.map(crypto::Sealer::new);
+    let mut opener = key.map(crypto::Opener::new);
+
+    let mut waiting: HashSet<T::Addr> = peers.iter().cloned().collect();
+    let mut offers: HashMap<T::Addr, Offer> = HashMap::new();
+    let mut sessions: HashMap<T::Addr, Params> = HashMap::new();
+    let hello = packet(&Message::Hello(offer.clone()), sealer.as_mut())?;
+
+    let mut recv_buf = vec![0_u8; SIZE];
+    // Too long a timeout is as good as none.
+    let deadline = Instant::now().checked_add(timeout);
+    while !waiting.is_empty() {
+        let now = Instant::now();
+        if deadline.map_or(false, |deadline| now >= deadline) {
+            return Err(Error::TimedOut(format!("{waiting:?}")).into());
+        }
+        info!("Handshake: waiting for {:?}", waiting);
+        for peer in &waiting {
+            // The failures like the network being down for a moment are
+            // temporary, we greet again on the next round.
+            if let Err(err) = socket.send_to(&hello, peer).await {
+                warn!("Handshake: failed to greet {:?}: {}", peer, err);
+            }
+        }
+
+        let next_round = now + RETRY_INTERVAL;
+        let retry_at = deadline.map_or(next_round, |deadline| next_round.min(deadline));
+        while let Ok(received) =
+            tokio::time::timeout_at(retry_at.into(), socket.recv_from(&mut recv_buf)).await
+        {
+            let (len, addr) = received?;
+            let Some(message) = read(&mut recv_buf[..len], opener.as_mut(), &addr) else {
+                continue;
+            };
+            debug!("Handshake: {:?} from {:?}", message, addr);
+
+            match message {
+                Message::Hello(their_offer) => {
+                    let reply = packet(&Message::Offer(offer.clone()), sealer.as_mut())?;
+                    // They greet again if they miss it.
+                    if let Err(err) = socket.send_to(&reply, &addr).await {
+                        warn!("Handshake: failed to answer {:?}: {}", addr, err);
+                    }
+                    if peers.contains(&addr) {
+                        offers.insert(addr.clone(), their_offer);
+                    }
+                }
+                Message::Offer(their_offer) => {
+                    if peers.contains(&addr) {
+                        offers.insert(addr.clone(), their_offer);
+                    }
+                }
+                Message::Session(params) => {
+                    if peers.contains(&addr) {
+                        sessions.insert(addr.clone(), params);
+                    }
+                }
+            }
+            waiting.remove(&addr);
+            if waiting.is_empty() {
+                break;
+            }
+        }
+    }
+
+    Ok(conclude(offer, &offers, &sessions)?)
+}
+
+/// Pick the params from what the peers told us.
+fn conclude<A: fmt::Debug>(
+    offer: &Offer,
+    offers: &HashMap<A, Offer>,
+    sessions: &HashMap<A, Params>,
+) -> Result<Params, Error> {
+    let mut sessions = sessions.iter();
+    if let Some((peer, params)) = sessions.next() {
+        if let Some((other_peer, other_params)) = sessions.find(|(_, other)| *other != params) {
+            return Err(Error::ConflictingSessions(format!(
+                "{peer:?} streams {params}, {other_peer:?} streams {other_params}"
+            )));
+        }
+        if !offer.supports(params) {
+            return Err(Error::UnsupportedSession {
+                peer: format!("{peer:?}"),
+                params: params.clone(),
+            });
+        }
+        return Ok(params.clone());
+    }
+
+    let all: Vec<&Offer> = std::iter::once(offer).chain(offers.values()).collect();
+    agree(&all).ok_or_else(|| {
+        let mut description = format!("we offer {offer}");
+        for (peer, their_offer) in offers {
+            description += &format!("; {peer:?} offers {their_offer}");
+        }
+        Error::NothingInCommon(description)
+    })
+}
+
+/// Decrypt and decode the handshake message, if the packet carries one.
+fn read<A: fmt::Debug>(
+    buf: &mut [u8],
+    opener: Option<&mut crypto::Opener>,
+    addr: &A,
+) -> Option<Message> {
+    let len = match opener {
+        None => buf.len(),
+        Some(opener) => match opener.open(buf, SystemTime::now()) {
+            Ok(len) => len,
+            Err(err) => {
+                warn!("Handshake: dropping a packet from {:?}: {}", addr, err);
+                return None;
+            }
+        },
+    };
+    let (header, payload) = packet::parse(&buf[..len]).ok()?;
+    if header.payload_type != packet::PayloadType::Control {
+        // The peer is streaming already, and will answer our hello soon.
+        return None;
+    }
+    match Message::read(payload) {
+        Ok(message) => Some(message),
+        Err(err) => {
+            warn!("Handshake: dropping a packet from {:?}: {}", addr, err);
+            None
+        }
+    }
+}
+
+fn write_offer(buf: &mut Vec<u8>, offer: &Offer) {
+    let codecs = &offer.codecs[..offer.codecs.len().min(usize::from(u8::MAX))];
+    buf.push(len_u8(codecs.len()));
+    for codec in codecs {
+        write_name(buf, &codec.name);
+        let sample_rates =
+            &codec.sample_rates[..codec.sample_rates.len().min(usize::from(u8::MAX))];
+        buf.push(len_u8(sample_rates.len()));
+        for &sample_rate in sample_rates {
+            write_u32(buf, sample_rate);
+        }
+        write_u16(buf, codec.max_channels);
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    let name = &name.as_bytes()[..name.len().min(usize::from(u8::MAX))];
+    buf.push(len_u8(name.len()));
+    buf.extend_from_slice(name);
+}
+
+fn write_u16(buf: &mut Vec<u8>, val: u16) {
+    let mut bytes = [0; 2];
+    BigEndian::write_u16(&mut bytes, val);
+    buf.extend_from_slice(&bytes);
+}
+
+fn write_u32(buf: &mut Vec<u8>, val: u32) {
+    let mut bytes = [0; 4];
+    BigEndian::write_u32(&mut bytes, val);
+    buf.extend_from_slice(&bytes);
+}
+
+fn len_u8(len: usize) -> u8 {
+    u8::try_from(len).unwrap_or(u8::MAX)
+}
+
+/// Reads the fields off the front of the buffer.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(BigEndian::read_u16(self.take(2)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        let len = usize::from(self.u8()?);
+        let name = self.take(len)?;
+        String::from_utf8(name.to_vec()).map_err(|_| Error::Malformed)
+    }
+
+    fn offer(&mut self) -> Result<Offer, Error> {
+        let count = self.u8()?;
+        let codecs = (0..count)
+            .map(|_| {
+                let name = self.name()?;
+                let rates = self.u8()?;
+                let sample_rates = (0..rates).map(|_| self.u32()).collect::<Result<_, _>>()?;
+                Ok(CodecOffer {
+                    name,
+                    sample_rates,
+                    max_channels: self.u16()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Offer { codecs })
+    }
+
+    fn params(&mut self) -> Result<Params, Error> {
+        Ok(Params {
+            codec: self.name()?,
+            sample_rate: self.u32()?,
+            channels: self.u16()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::memory;
+
+    /// Way more than the peers on the memory transport take to answer.
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn codec(name: &str, sample_rates: &[u32], max_channels: u16) -> CodecOffer {
+        CodecOffer {
+            name: name.to_owned(),
+            sample_rates: sample_rates.to_vec(),
+            max_channels,
+        }
+    }
+
+    fn opus_and_raw() -> Offer {
+        Offer {
+            codecs: vec![
+                codec("opus", &[48000, 24000, 16000], 2),
+                codec("raw", &[48000, 44100], 2),
+            ],
+        }
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        for message in [
+            Message::Hello(opus_and_raw()),
+            Message::Offer(Offer::default()),
+            Message::Session(Params {
+                codec: "opus".to_owned(),
+                sample_rate: 48000,
+                channels: 2,
+            }),
+        ] {
+            let mut buf = Vec::new();
+            message.write(&mut buf);
+            assert_eq!(Message::read(&buf), Ok(message));
+            assert_eq!(Message::read(&buf[..buf.len() - 1]), Err(Error::Malformed));
+        }
+        assert_eq!(Message::read(&[9]), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn agreement() {
+        let ours = opus_and_raw();
+        let theirs = Offer {
+            codecs: vec![
+                codec("raw", &[44100, 48000], 1),
+                codec("opus", &[16000, 8000], 1),
+            ],
+        };
+        let expected = Params {
+            codec: "opus".to_owned(),
+            sample_rate: 16000,
+            channels: 1,
+        };
+        // Tied on the preference, broken by the name.
+        assert_eq!(agree(&[&ours, &theirs]), Some(expected.clone()));
+        assert_eq!(agree(&[&theirs, &ours]), Some(expected));
+
+        let raw_only = Offer {
+            codecs: vec![codec("raw", &[8000], 2)],
+        };
+        assert_eq!(agree(&[&ours, &raw_only]), None);
+        assert_eq!(agree(&[]), None);
+    }
+
+    #[test]
+    fn joining_adopts_the_session() {
+        let ours = opus_and_raw();
+        let params = Params {
+            codec: "raw".to_owned(),
+            sample_rate: 44100,
+            channels: 1,
+        };
+        let sessions: HashMap<_, _> = std::iter::once((1, params.clone())).collect();
+        assert_eq!(conclude(&ours, &HashMap::new(), &sessions), Ok(params));
+
+        let unsupported = Params {
+            codec: "g711".to_owned(),
+            sample_rate: 8000,
+            channels: 1,
+        };
+        let sessions: HashMap<_, _> = std::iter::once((1, unsupported)).collect();
+        assert!(matches!(
+            conclude(&ours, &HashMap::new(), &sessions),
+            Err(Error::UnsupportedSession { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn negotiation_over_memory_transport() {
+        let (first, second) = memory::pair();
+        let key: crypto::Key = "00".repeat(crypto::KEY_SIZE).parse().unwrap();
+        let first_offer = opus_and_raw();
+        let second_offer = Offer {
+            codecs: vec![codec("raw", &[48000], 2)],
+        };
+
+        let (first_params, second_params) = futures::future::join(
+            negotiate(
+                &first,
+                &[second.local_addr()],
+                &first_offer,
+                Some(&key),
+                TIMEOUT,
+            ),
+            negotiate(
+                &second,
+                &[first.local_addr()],
+                &second_offer,
+                Some(&key),
+                TIMEOUT,
+            ),
+        )
+        .await;
+        let expected = Params {
+            codec: "raw".to_owned(),
+            sample_rate: 48000,
+            channels: 2,
+        };
+        assert_eq!(first_params.unwrap(), expected);
+        assert_eq!(second_params.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn nothing_in_common() {
+        let (first, second) = memory::pair();
+        let opus_only = Offer {
+            codecs: vec![codec("opus", &[48000], 2)],
+        };
+        let raw_only = Offer {
+            codecs: vec![codec("raw", &[48000], 2)],
+        };
+
+        let (result, _) = futures::future::join(
+            negotiate(&first, &[second.local_addr()], &opus_only, None, TIMEOUT),
+            negotiate(&second, &[first.local_addr()], &raw_only, None, TIMEOUT),
+        )
+        .await;
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::NothingInCommon(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_the_silent_peers() {
+        let (first, second) = memory::pair();
+        let result = negotiate(
+            &first,
+            &[second.local_addr()],
+            &opus_and_raw(),
+            None,
+            RETRY_INTERVAL / 10,
+        )
+        .await;
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::TimedOut(_))
+        ));
+    }
+}
@@ -0,0 +1,503 @@
+//! An adaptive jitter buffer.
+//!
+//! Packets are put into the buffer as they arrive, and are taken out in
+//! the sequence order, each at its playout deadline. The deadline is derived
+//! from the packet timestamp, the minimal observed transit time and the
+//! target playout delay. The target delay follows the measured interarrival
+//! jitter.
+
+use crate::pcm;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The max amount of packet slots the buffer can hold. Packets that are
+/// further ahead than this cause the buffer to reset.
+const MAX_SLOTS: usize = 512;
+
+/// How far behind the playout point a packet can be before we assume
+/// the sender has restarted the sequence, rather than the packet being late.
+const MAX_LATE: u32 = 1000;
+
+/// The amount of packets in the window for computing the minimal transit
+/// time.
+const TRANSIT_WINDOW: usize = 128;
+
+/// The target delay is this many times the jitter estimate.
+const JITTER_MULTIPLIER: f64 = 4.0;
+
+/// The target delay is only changed when the desired delay differs from it
+/// by more than this.
+const DELAY_HYSTERESIS: Duration = Duration::from_millis(10);
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy)]
+pub struct JitterBufferConfig {
+    /// The sample clock rate of the packet timestamps, in interleaved samples
+    /// per second.
+    pub clock_rate: f64,
+    /// The lower bound of the target playout delay.
+    pub min_delay: Duration,
+    /// The upper bound of the target playout delay. Packets that are behind
+    /// their deadline further than this are dropped.
+    pub max_delay: Duration,
+    /// The target playout delay to start with.
+    pub initial_delay: Duration,
+}
+
+impl JitterBufferConfig {
+    /// Create a config with the default delays for the stream of the given
+    /// params.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(sample_rate: pcm::SampleRate, channels: pcm::Channels) -> Self {
+        Self {
+            clock_rate: (sample_rate.as_usize() * channels) as f64,
+            min_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(500),
+            initial_delay: Duration::from_millis(60),
+        }
+    }
+}
+
+/// A packet held by the jitter buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// The id of the stream config, see [`super::stream`].
+    pub stream: u16,
+    pub sequence: u32,
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
+}
+
+/// The outcome of putting a packet into the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Insert {
+    /// The packet was buffered.
+    Buffered,
+    /// The packet arrived after its playout point has passed, and was dropped.
+    Late,
+    /// The packet is already in the buffer.
+    Duplicate,
+    /// The packet did not fit the buffer, the buffer was reset and the packet
+    /// was buffered as the first one.
+    Reset,
+}
+
+/// The next item to play.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout {
+    /// The packet to play.
+    Packet(Packet),
+    /// The packet with this sequence number is missing at its playout
+    /// deadline.
+    Missing { sequence: u32 },
+}
+
+/// The jitter estimate we start with, such that the target delay starts
+/// at the initial delay and converges from there.
+fn initial_jitter(config: &JitterBufferConfig) -> f64 {
+    config.initial_delay.as_secs_f64() / JITTER_MULTIPLIER
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug)]
+pub struct JitterBuffer {
+    config: JitterBufferConfig,
+
+    /// The slot `n` holds the packet with the sequence `next_sequence + n`.
+    slots: VecDeque<Option<Packet>>,
+    next_sequence: Option<u32>,
+
+    /// The reference point for the times, set at the first arrival.
+    epoch: Option<Instant>,
+    /// The last seen timestamp and its extended (unwrapped) value.
+    last_timestamp: Option<(u32, i64)>,
+    /// The amount of samples in a packet, as seen between the consecutive
+    /// packets.
+    packet_samples: Option<u32>,
+
+    last_transit: Option<f64>,
+    min_transit_current: f64,
+    min_transit_previous: f64,
+    transit_window_fill: usize,
+
+    jitter: f64,
+    target_delay: Duration,
+
+    late_packets: usize,
+    delay_changes: usize,
+    resets: usize,
+}
+
+impl JitterBuffer {
+    #[must_use]
+    pub fn new(config: JitterBufferConfig) -> Self {
+        Self {
+            config,
+            slots: VecDeque::new(),
+            next_sequence: None,
+            epoch: None,
+            last_timestamp: None,
+            packet_samples: None,
+            last_transit: None,
+            min_transit_current: f64::INFINITY,
+            min_transit_previous: f64::INFINITY,
+            transit_window_fill: 0,
+            jitter: initial_jitter(&config),
+            target_delay: config.initial_delay,
+            late_packets: 0,
+            delay_changes: 0,
+            resets: 0,
+        }
+    }
+
+    /// Put the packet that arrived at `arrival` into the buffer.
+    pub fn insert(&mut self, packet: Packet, arrival: Instant) -> Insert {
+        let mut next_sequence = *self.next_sequence.get_or_insert(packet.sequence);
+        let behind = next_sequence.wrapping_sub(packet.sequence);
+        if behind != 0 && behind <= MAX_LATE {
+            self.late_packets += 1;
+            return Insert::Late;
+        }
+
+        let mut outcome = Insert::Buffered;
+        if packet.sequence.wrapping_sub(next_sequence) as usize >= MAX_SLOTS {
+            self.reset(packet.sequence);
+            next_sequence = packet.sequence;
+            outcome = Insert::Reset;
+        }
+
+        let index = packet.sequence.wrapping_sub(next_sequence) as usize;
+        if self.slots.len() <= index {
+            self.slots.resize(index + 1, None);
+        }
+        if self.slots[index].is_some() {
+            return Insert::Duplicate;
+        }
+
+        self.measure(&packet, arrival);
+        self.slots[index] = Some(packet);
+
+        self.learn_packet_samples(index);
+        outcome
+    }
+
+    /// The time at which the next item will be ready to play.
+    #[must_use]
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let epoch = self.epoch?;
+        let (offset, packet) = self
+            .slots
+            .iter()
+            .enumerate()
+            .find_map(|(offset, slot)| slot.as_ref().map(|packet| (offset, packet)))?;
+        let deadline = self.deadline(packet.timestamp)?;
+        if offset == 0 {
+            return Some(deadline);
+        }
+        // The head is missing, estimate its deadline from the packet that
+        // follows it.
+        let packet_duration = self.samples_duration(self.packet_samples.unwrap_or(0));
+        #[allow(clippy::cast_possible_truncation)]
+        let missing_duration = packet_duration * (offset as u32);
+        Some(deadline.checked_sub(missing_duration).unwrap_or(epoch))
+    }
+
+    /// Take the next item to play, if it is due at `now`.
+    pub fn pop(&mut self, now: Instant) -> Option<Playout> {
+        let deadline = self.next_deadline()?;
+        if now < deadline {
+            return None;
+        }
+
+        let sequence = self.next_sequence?;
+        self.next_sequence = Some(sequence.wrapping_add(1));
+        let packet = match self.slots.pop_front()? {
+            None => return Some(Playout::Missing { sequence }),
+            Some(packet) => packet,
+        };
+
+        if now.duration_since(deadline) > self.config.max_delay {
+            // Playing this packet would exceed the max delay, so we drop it,
+            // and have its slot concealed to keep the timeline.
+            self.late_packets += 1;
+            return Some(Playout::Missing { sequence });
+        }
+        Some(Playout::Packet(packet))
+    }
+
+    /// The packet that is next in line to play, if it has arrived.
+    #[must_use]
+    pub fn front(&self) -> Option<&Packet> {
+        self.slots.front()?.as_ref()
+    }
+
+    /// The amount of packets in the buffer.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(Option::is_none)
+    }
+
+    /// The current target playout delay.
+    #[must_use]
+    pub fn target_delay(&self) -> Duration {
+        self.target_delay
+    }
+
+    /// The interarrival jitter estimate.
+    #[must_use]
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    /// The amount of packets dropped due to arriving past their playout
+    /// point.
+    #[must_use]
+    pub fn late_packets(&self) -> usize {
+        self.late_packets
+    }
+
+    /// The amount of times the target delay was changed.
+    #[must_use]
+    pub fn delay_changes(&self) -> usize {
+        self.delay_changes
+    }
+
+    /// The amount of times the buffer was reset.
+    #[must_use]
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    fn reset(&mut self, sequence: u32) {
+        self.slots.clear();
+        self.next_sequence = Some(sequence);
+        self.epoch = None;
+        self.last_timestamp = None;
+        self.packet_samples = None;
+        self.last_transit = None;
+        self.min_transit_current = f64::INFINITY;
+        self.min_transit_previous = f64::INFINITY;
+        self.transit_window_fill = 0;
+        self.resets += 1;
+    }
+
+    /// Update the transit and jitter estimates with the packet arrival.
+    fn measure(&mut self, packet: &Packet, arrival: Instant) {
+        let epoch = *self.epoch.get_or_insert(arrival);
+        let timestamp = self.extend_timestamp(packet.timestamp);
+
+        #[allow(clippy::cast_precision_loss)]
+        let transit = arrival.saturating_duration_since(epoch).as_secs_f64()
+            - timestamp as f64 / self.config.clock_rate;
+
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+
+        self.min_transit_current = self.min_transit_current.min(transit);
+        self.transit_window_fill += 1;
+        if self.transit_window_fill >= TRANSIT_WINDOW {
+            self.min_transit_previous = self.min_transit_current;
+            self.min_transit_current = f64::INFINITY;
+            self.transit_window_fill = 0;
+        }
+
+        self.adapt_delay();
+    }
+
+    fn adapt_delay(&mut self) {
+        let desired = Duration::from_secs_f64(self.jitter * JITTER_MULTIPLIER)
+            .clamp(self.config.min_delay, self.config.max_delay);
+        let difference = if desired > self.target_delay {
+            desired - self.target_delay
+        } else {
+            self.target_delay - desired
+        };
+        if difference > DELAY_HYSTERESIS {
+            self.target_delay = desired;
+            self.delay_changes += 1;
+        }
+    }
+
+    fn extend_timestamp(&mut self, timestamp: u32) -> i64 {
+        let extended = match self.last_timestamp {
+            None => 0,
+            Some((last, last_extended)) => {
+                #[allow(clippy::cast_possible_wrap)]
+                let delta = timestamp.wrapping_sub(last) as i32;
+                last_extended + i64::from(delta)
+            }
+        };
+        self.last_timestamp = Some((timestamp, extended));
+        extended
+    }
+
+    fn learn_packet_samples(&mut self, index: usize) {
+        let Some(this) = &self.slots[index] else {
+            return;
+        };
+        let prev = index
+            .checked_sub(1)
+            .and_then(|prev| self.slots[prev].as_ref());
+        let next = self.slots.get(index + 1).and_then(Option::as_ref);
+        if let Some(prev) = prev {
+            self.packet_samples = Some(this.timestamp.wrapping_sub(prev.timestamp));
+        } else if let Some(next) = next {
+            self.packet_samples = Some(next.timestamp.wrapping_sub(this.timestamp));
+        }
+    }
+
+    fn samples_duration(&self, samples: u32) -> Duration {
+        Duration::from_secs_f64(f64::from(samples) / self.config.clock_rate)
+    }
+
+    fn min_transit(&self) -> f64 {
+        self.min_transit_current.min(self.min_transit_previous)
+    }
+
+    fn deadline(&self, timestamp: u32) -> Option<Instant> {
+        let epoch = self.epoch?;
+        let (last, last_extended) = self.last_timestamp?;
+        #[allow(clippy::cast_possible_wrap)]
+        let extended = last_extended + i64::from(timestamp.wrapping_sub(last) as i32);
+        #[allow(clippy::cast_precision_loss)]
+        let secs = extended as f64 / self.config.clock_rate
+            + self.min_transit()
+            + self.target_delay.as_secs_f64();
+        Some(epoch + Duration::from_secs_f64(secs.max(0.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET_SAMPLES: u32 = 960;
+
+    fn config() -> JitterBufferConfig {
+        JitterBufferConfig {
+            clock_rate: 48_000.0,
+            min_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(500),
+            initial_delay: Duration::from_millis(40),
+        }
+    }
+
+    fn packet(sequence: u32) -> Packet {
+        Packet {
+            stream: 0,
+            sequence,
+            timestamp: sequence * PACKET_SAMPLES,
+            payload: vec![sequence.to_le_bytes()[0]],
+        }
+    }
+
+    fn ms(val: u64) -> Duration {
+        Duration::from_millis(val)
+    }
+
+    #[test]
+    fn holds_packets_for_the_target_delay() {
+        let epoch = Instant::now();
+        let mut jb = JitterBuffer::new(config());
+
+        assert_eq!(jb.insert(packet(0), epoch), Insert::Buffered);
+        assert_eq!(jb.next_deadline(), Some(epoch + ms(40)));
+        assert_eq!(jb.pop(epoch + ms(39)), None);
+        assert_eq!(jb.pop(epoch + ms(40)), Some(Playout::Packet(packet(0))));
+        assert_eq!(jb.pop(epoch + ms(40)), None);
+    }
+
+    #[test]
+    fn reorders() {
+        let epoch = Instant::now();
+        let mut jb = JitterBuffer::new(config());
+
+        jb.insert(packet(0), epoch);
+        jb.insert(packet(2), epoch + ms(40));
+        jb.insert(packet(1), epoch + ms(41));
+        assert_eq!(jb.len(), 3);
+
+        let now = epoch + ms(100);
+        assert_eq!(jb.pop(now), Some(Playout::Packet(packet(0))));
+        assert_eq!(jb.pop(now), Some(Playout::Packet(packet(1))));
+        assert_eq!(jb.pop(now), Some(Playout::Packet(packet(2))));
+        assert!(jb.is_empty());
+    }
+
+    #[test]
+    fn reports_missing_and_late() {
+        let epoch = Instant::now();
+        let mut jb = JitterBuffer::new(config());
+
+        jb.insert(packet(0), epoch);
+        jb.insert(packet(1), epoch + ms(20));
+        jb.insert(packet(3), epoch + ms(60));
+
+        let now = epoch + ms(110);
+        assert_eq!(jb.pop(now), Some(Playout::Packet(packet(0))));
+        assert_eq!(jb.pop(now), Some(Playout::Packet(packet(1))));
+        assert_eq!(jb.pop(now), Some(Playout::Missing { sequence: 2 }));
+        assert_eq!(jb.pop(now), Some(Playout::Packet(packet(3))));
+
+        assert_eq!(jb.insert(packet(2), now), Insert::Late);
+        assert_eq!(jb.late_packets(), 1);
+
+        // Too late to play by the time it's due, but its slot still comes
+        // up, to be concealed.
+        jb.insert(packet(4), now);
+        let too_late = jb.next_deadline().unwrap() + config().max_delay + ms(1);
+        assert_eq!(jb.pop(too_late), Some(Playout::Missing { sequence: 4 }));
+        assert_eq!(jb.late_packets(), 2);
+        assert!(jb.is_empty());
+    }
+
+    #[test]
+    fn ignores_duplicates() {
+        let epoch = Instant::now();
+        let mut jb = JitterBuffer::new(config());
+
+        jb.insert(packet(0), epoch);
+        jb.insert(packet(1), epoch + ms(20));
+        let jitter = jb.jitter();
+
+        assert_eq!(jb.insert(packet(1), epoch + ms(300)), Insert::Duplicate);
+        assert_eq!(jb.jitter(), jitter);
+        assert_eq!(jb.len(), 2);
+    }
+
+    #[test]
+    fn adapts_delay_to_jitter() {
+        let epoch = Instant::now();
+        let mut jb = JitterBuffer::new(config());
+
+        for sequence in 0..200 {
+            let jitter = if sequence % 2 == 0 { 0 } else { 30 };
+            let arrival = epoch + ms(u64::from(sequence) * 20 + jitter);
+            jb.insert(packet(sequence), arrival);
+            jb.pop(arrival);
+        }
+        assert!(jb.target_delay() > ms(40));
+        assert!(jb.delay_changes() > 0);
+    }
+
+    #[test]
+    fn resets_on_sequence_restart() {
+        let epoch = Instant::now();
+        let mut jb = JitterBuffer::new(config());
+
+        jb.insert(packet(5000), epoch);
+        assert_eq!(jb.insert(packet(0), epoch + ms(20)), Insert::Reset);
+        assert_eq!(jb.resets(), 1);
+        assert_eq!(jb.len(), 1);
+        assert_eq!(jb.pop(epoch + ms(60)), Some(Playout::Packet(packet(0))));
+    }
+}
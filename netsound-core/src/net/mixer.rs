@@ -0,0 +1,159 @@
+//! Mixing of the audio coming from multiple sources into a single stream.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// The level above which the soft clipper starts compressing the signal.
+const CLIP_KNEE: f32 = 0.75;
+
+/// Mixes the samples from multiple sources.
+///
+/// Every source has a cursor pointing to the position in the mix where its
+/// next samples go. The samples are ready to be taken from the mix once all
+/// the sources that are still expected to contribute to them did so.
+#[derive(Debug)]
+pub struct Mixer<K> {
+    mix: VecDeque<f32>,
+    cursors: HashMap<K, usize>,
+    /// How far behind the most advanced source a lagging source is allowed
+    /// to fall before we stop waiting for it, in samples.
+    max_lag: usize,
+    /// Must be a multiple of the number of channels, so that we never split
+    /// an interleaved frame.
+    frame_size: usize,
+    clipped: usize,
+}
+
+impl<K: Eq + Hash + Clone> Mixer<K> {
+    pub fn new(max_lag: usize, frame_size: usize) -> Self {
+        Self {
+            mix: VecDeque::new(),
+            cursors: HashMap::new(),
+            max_lag,
+            frame_size: frame_size.max(1),
+            clipped: 0,
+        }
+    }
+
+    /// Add the samples from the `source` to the mix.
+    pub fn add(&mut self, source: &K, samples: impl IntoIterator<Item = f32>) {
+        let cursor = self.cursors.entry(source.clone()).or_insert(0);
+        for sample in samples {
+            match self.mix.get_mut(*cursor) {
+                Some(slot) => *slot += sample,
+                None => self.mix.push_back(sample),
+            }
+            *cursor += 1;
+        }
+    }
+
+    /// Forget the `source`.
+    pub fn remove(&mut self, source: &K) {
+        self.cursors.remove(source);
+    }
+
+    /// Take the samples that are ready for playback.
+    ///
+    /// We wait for the sources that are `pending` (i.e. have more samples to
+    /// contribute soon), and don't wait for the rest.
+    pub fn take_ready(&mut self, pending: impl Fn(&K) -> bool) -> Vec<f32> {
+        let end = self.mix.len();
+        let waiting_for = self
+            .cursors
+            .iter()
+            .filter(|(source, _)| pending(source))
+            .map(|(_, &cursor)| cursor)
+            .min()
+            .unwrap_or(end);
+        let ready = waiting_for.max(end.saturating_sub(self.max_lag));
+        let ready = ready - ready % self.frame_size;
+
+        for cursor in self.cursors.values_mut() {
+            *cursor = cursor.saturating_sub(ready);
+        }
+
+        let clipped = &mut self.clipped;
+        self.mix
+            .drain(..ready)
+            .map(|sample| {
+                if sample.abs() > 1.0 {
+                    *clipped += 1;
+                }
+                soft_clip(sample)
+            })
+            .collect()
+    }
+
+    /// The amount of samples that exceeded the full scale in the mix.
+    pub fn clipped(&self) -> usize {
+        self.clipped
+    }
+}
+
+/// Keep the `sample` within the full scale, compressing the peaks above the
+/// knee smoothly instead of cutting them off.
+fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= CLIP_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - CLIP_KNEE;
+    let compressed = CLIP_KNEE + headroom * ((magnitude - CLIP_KNEE) / headroom).tanh();
+    compressed.copysign(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_source_passes_through() {
+        let mut mixer = Mixer::new(100, 1);
+        mixer.add(&1, vec![0.1, -0.2, 0.3]);
+        assert_eq!(mixer.take_ready(|_| true), vec![0.1, -0.2, 0.3]);
+        assert!(mixer.take_ready(|_| true).is_empty());
+    }
+
+    #[test]
+    fn waits_for_pending_sources() {
+        let mut mixer = Mixer::new(100, 1);
+        mixer.add(&1, vec![0.1, 0.1, 0.1, 0.1]);
+        mixer.add(&2, vec![0.2, 0.2]);
+        assert_eq!(mixer.take_ready(|_| true), vec![0.3_f32, 0.3]);
+
+        mixer.add(&2, vec![0.2, 0.2]);
+        assert_eq!(mixer.take_ready(|_| true), vec![0.3_f32, 0.3]);
+    }
+
+    #[test]
+    fn does_not_wait_for_idle_sources() {
+        let mut mixer = Mixer::new(100, 1);
+        mixer.add(&1, vec![0.1, 0.1]);
+        mixer.add(&2, vec![0.2, 0.2, 0.2]);
+        assert_eq!(mixer.take_ready(|&source| source == 2).len(), 3);
+
+        // The idle source continues from the current position.
+        mixer.add(&1, vec![0.1]);
+        assert_eq!(mixer.take_ready(|&source| source == 1), vec![0.1]);
+    }
+
+    #[test]
+    fn bounded_lag() {
+        let mut mixer = Mixer::new(2, 2);
+        mixer.add(&1, vec![0.1; 7]);
+        mixer.add(&2, vec![0.1; 1]);
+        assert_eq!(mixer.take_ready(|_| true).len(), 4);
+    }
+
+    #[test]
+    fn clipping() {
+        let mut mixer = Mixer::new(100, 1);
+        mixer.add(&1, vec![0.9, -0.9, 0.5]);
+        mixer.add(&2, vec![0.9, -0.9, 0.1]);
+        let out = mixer.take_ready(|_| true);
+        assert!(out[0] < 1.0 && out[0] > 0.9);
+        assert!(out[1] > -1.0 && out[1] < -0.9);
+        assert!((out[2] - 0.6).abs() < 1e-6);
+        assert_eq!(mixer.clipped(), 2);
+    }
+}
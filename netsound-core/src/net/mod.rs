@@ -2,15 +2,35 @@ use crate::codec::{Decoder, Encoder};
 use crate::io::{AsyncReadItems, AsyncWriteItems};
 use crate::log::{debug, logger, o, LogScopeFutureExt};
 use crate::pcm::Sample;
+use dasp_sample::Duplex;
 use futures::{future::select, FutureExt};
+use std::fmt::Debug;
+use std::hash::Hash;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::net::UdpSocket;
 
+pub mod crypto;
+pub mod feedback;
+pub mod handshake;
+mod jitter;
+mod mixer;
+pub mod mtu;
+pub mod multicast;
+pub mod packet;
+mod peers;
 mod recv;
+mod relay;
+pub mod rtp;
 mod send;
+mod sequence;
+pub mod stream;
+pub mod transport;
 
+pub use jitter::{JitterBuffer, JitterBufferConfig};
+pub use peers::{AutoJoin, PeerRegistry, TtlTooLong};
 pub use recv::*;
+pub use relay::*;
 pub use send::*;
+pub use transport::Transport;
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug)]
@@ -22,6 +42,7 @@ pub struct NetService<
     TPlaybackDataWriter,
     TEncoder,
     TDecoder,
+    TAddr = SocketAddr,
 > where
     TCaptureSample: Sample,
     TPlaybackSample: Sample,
@@ -30,10 +51,10 @@ pub struct NetService<
     TPlaybackDataWriter: AsyncWriteItems<TPlaybackSample> + Unpin,
 
     TEncoder: Encoder<TCaptureSample, TCaptureDataReader> + ?Sized,
-    TDecoder: Decoder<TPlaybackSample, TPlaybackDataWriter> + ?Sized,
+    TDecoder: Decoder<TPlaybackSample, Vec<TPlaybackSample>> + ?Sized,
 {
-    pub send_service: SendService<'a, TCaptureSample, TCaptureDataReader, TEncoder>,
-    pub recv_service: RecvService<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder>,
+    pub send_service: SendService<'a, TCaptureSample, TCaptureDataReader, TEncoder, TAddr>,
+    pub recv_service: RecvService<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder, TAddr>,
 }
 
 pub type DynNetService<'a, TCaptureSample, TPlaybackSample, TCaptureData, TPlaybackData> =
@@ -44,10 +65,25 @@ pub type DynNetService<'a, TCaptureSample, TPlaybackSample, TCaptureData, TPlayb
         TCaptureData,
         TPlaybackData,
         dyn Encoder<TCaptureSample, TCaptureData> + Send + 'a,
-        dyn Decoder<TPlaybackSample, TPlaybackData> + Send + 'a,
+        dyn Decoder<TPlaybackSample, Vec<TPlaybackSample>> + Send + 'a,
     >;
 
-const SIZE: usize = 1024 * 4 * 2;
+/// The framing of the packets on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Our own packet format, see [`packet`].
+    Native,
+    /// RTP with the opus payload format, see [`rtp`].
+    Rtp(rtp::Config),
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self::Native
+    }
+}
+
+const SIZE: usize = packet::HEADER_SIZE + 1024 * 4 * 2;
 
 impl<
         'a,
@@ -57,6 +93,7 @@ impl<
         TPlaybackDataWriter,
         TEncoder,
         TDecoder,
+        TAddr,
     >
     NetService<
         'a,
@@ -66,21 +103,24 @@ impl<
         TPlaybackDataWriter,
         TEncoder,
         TDecoder,
+        TAddr,
     >
 where
     TCaptureSample: Sample + Send,
-    TPlaybackSample: Sample + Send,
+    TPlaybackSample: Sample + Duplex<f32> + Send + Sync,
 
     TCaptureDataReader: AsyncReadItems<TCaptureSample> + Unpin + Send,
     TPlaybackDataWriter: AsyncWriteItems<TPlaybackSample> + Unpin + Send,
 
     TEncoder: Encoder<TCaptureSample, TCaptureDataReader> + Send + ?Sized,
-    TDecoder: Decoder<TPlaybackSample, TPlaybackDataWriter> + Send + ?Sized,
+    TDecoder: Decoder<TPlaybackSample, Vec<TPlaybackSample>> + Send + ?Sized,
+
+    TAddr: Clone + Eq + Hash + Debug + Send + Sync,
 {
-    pub async fn net_loop(
+    pub async fn net_loop<T: Transport<Addr = TAddr> + 'static>(
         &mut self,
-        socket: UdpSocket,
-        peer_addrs: Vec<SocketAddr>,
+        socket: T,
+        peers: PeerRegistry<TAddr>,
     ) -> Result<futures::never::Never, crate::Error> {
         let send_service = &mut self.send_service;
         let recv_service = &mut self.recv_service;
@@ -88,7 +128,7 @@ where
         let socket = Arc::new(socket);
 
         let send_future = send_service
-            .send_loop(socket.clone(), peer_addrs)
+            .send_loop(socket.clone(), peers)
             .with_logger(logger().new(o!("logger" => "net::send")))
             .boxed();
         let recv_future = recv_service
@@ -102,4 +142,354 @@ where
 
         val
     }
+
+    /// Log the final stats of both directions, and publish them for
+    /// the metrics. Meant to be called once the net loop is stopped.
+    pub fn flush_stats(&self) {
+        self.send_service.flush_stats();
+        self.recv_service.flush_stats();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{AsyncReadItemsExt, AsyncWriteItemsExt};
+    use crate::{buf, codec, io::WaitMode, pcm};
+    use std::collections::HashMap;
+    use std::marker::PhantomData;
+
+    fn params(codec: &str, channels: u16) -> handshake::Params {
+        handshake::Params {
+            codec: codec.to_owned(),
+            sample_rate: 48000,
+            channels,
+        }
+    }
+
+    /// A send service with nothing but the encoder set up.
+    fn send_service<'a, TCaptureDataReader, TEncoder, TAddr>(
+        capture_data_reader: TCaptureDataReader,
+        encoder: Box<TEncoder>,
+    ) -> SendService<'a, f32, TCaptureDataReader, TEncoder, TAddr>
+    where
+        TCaptureDataReader: AsyncReadItems<f32>,
+        TEncoder: codec::Encoder<f32, TCaptureDataReader> + ?Sized,
+    {
+        SendService {
+            capture_sample: PhantomData,
+            capture_data_reader,
+            encoder,
+            encoder_factory: None,
+            codec_switch: None,
+            cipher: None,
+            framing: Framing::Native,
+            max_payload_size: mtu::DEFAULT_MAX_PAYLOAD_SIZE,
+            stats: SendStats::default(),
+            peer_stats: HashMap::new(),
+            bitrate_controller: None,
+            announcer: None,
+            metrics: None,
+        }
+    }
+
+    /// A recv service with nothing but the decoding of the `stream_params`
+    /// set up.
+    fn recv_service<TPlaybackDataWriter, TDecoder, TAddr>(
+        playback_data_writer: TPlaybackDataWriter,
+        decoder_factory: DecoderFactory<'_, TDecoder>,
+        stream_params: handshake::Params,
+    ) -> RecvService<'_, f32, TPlaybackDataWriter, TDecoder, TAddr>
+    where
+        TPlaybackDataWriter: AsyncWriteItems<f32>,
+        TDecoder: codec::Decoder<f32, Vec<f32>> + ?Sized,
+    {
+        let sample_rate = (stream_params.sample_rate as usize).into();
+        let channels = stream_params.channels.into();
+        RecvService {
+            playback_sample: PhantomData,
+            playback_data_writer,
+            decoder_factory,
+            stream_params,
+            jitter_buffer_config: JitterBufferConfig::new(sample_rate, channels),
+            playback_stream_config: pcm::StreamConfig::new(sample_rate, channels),
+            framing: Framing::Native,
+            max_payload_size: mtu::DEFAULT_MAX_PAYLOAD_SIZE,
+            cipher: None,
+            handshake: None,
+            auto_join: None,
+            feedback: None,
+            feedback_reports: None,
+            stats: RecvStats::default(),
+            peer_stats: HashMap::new(),
+            metrics: None,
+        }
+    }
+
+    /// Run the `service` loop until the `done` future completes, and return
+    /// its output.
+    async fn run_until<T>(
+        service: impl futures::Future<Output = Result<futures::never::Never, crate::Error>>,
+        done: impl futures::Future<Output = T>,
+    ) -> T {
+        futures::pin_mut!(service, done);
+        match select(service, done).await {
+            futures::future::Either::Left((Err(err), _)) => panic!("the loop failed: {}", err),
+            futures::future::Either::Left((Ok(never), _)) => match never {},
+            futures::future::Either::Right((output, _)) => output,
+        }
+    }
+
+    #[tokio::test]
+    async fn full_duplex_over_memory_transport() {
+        let (first, second) = transport::memory::pair();
+        let first_addr = first.local_addr();
+        let second_addr = second.local_addr();
+
+        let samples: Vec<f32> = (0..4800_u16).map(|n| f32::from(n % 100) / 1000.0).collect();
+
+        let mut services = Vec::new();
+        for _ in 0..2 {
+            let (mut capture_writer, capture_reader) = buf::vec_deque_buffer_with_capacity(10_000);
+            capture_writer
+                .write_items(&samples, WaitMode::NoWait)
+                .await
+                .unwrap();
+            let (playback_writer, playback_reader) = buf::vec_deque_buffer_with_capacity(10_000);
+            services.push((
+                capture_writer,
+                playback_reader,
+                NetService {
+                    send_service: send_service(
+                        capture_reader,
+                        Box::new(codec::raw::Encoder::new(codec::raw::Format::default(), 2)),
+                    ),
+                    recv_service: recv_service(
+                        playback_writer,
+                        Box::new(|_| Ok(Box::<codec::raw::Decoder>::default())),
+                        params("raw", 2),
+                    ),
+                },
+            ));
+        }
+        let (_second_capture, mut second_playback, mut second_service) = services.pop().unwrap();
+        let (_first_capture, mut first_playback, mut first_service) = services.pop().unwrap();
+
+        let net = select(
+            first_service
+                .net_loop(first, std::iter::once(second_addr).collect())
+                .boxed(),
+            second_service
+                .net_loop(second, std::iter::once(first_addr).collect())
+                .boxed(),
+        )
+        .map(|either| either.factor_first().0);
+        let mut first_played = vec![0.0; samples.len()];
+        let mut second_played = vec![0.0; samples.len()];
+        let (first_result, second_result) = run_until(
+            net,
+            futures::future::join(
+                first_playback.read_exact_items(&mut first_played, WaitMode::WaitForReady),
+                second_playback.read_exact_items(&mut second_played, WaitMode::WaitForReady),
+            ),
+        )
+        .await;
+        first_result.unwrap();
+        second_result.unwrap();
+
+        assert_eq!(first_played, samples);
+        assert_eq!(second_played, samples);
+        for service in [&first_service, &second_service] {
+            assert_eq!(service.recv_service.stats.peers, 1);
+            // 180 stereo frames fit a packet.
+            assert_eq!(service.send_service.stats.packets_sent, 14);
+        }
+    }
+
+    #[tokio::test]
+    async fn recv_follows_the_stream_switches() {
+        let (sender, receiver) = transport::memory::pair();
+        let receiver_addr = receiver.local_addr();
+
+        let registry = codec::registry::Registry::with_builtin();
+        let stream_config = pcm::StreamConfig::new(48000.into(), 1);
+        let settings = codec::registry::Settings {
+            max_packet_size: mtu::DEFAULT_MAX_PAYLOAD_SIZE,
+            max_bitrate: None,
+            fec_expected_packet_loss: None,
+        };
+        let (playback_writer, mut playback_reader) = buf::vec_deque_buffer_with_capacity(100);
+        let mut recv_service: RecvService<'_, f32, _, codec::registry::DynDecoder, _> =
+            recv_service(
+                playback_writer,
+                Box::new(move |params| {
+                    registry
+                        .get(&params.codec)
+                        .ok_or_else(|| anyhow::format_err!("no {}", params.codec))?
+                        .decoder(stream_config, &settings)
+                }),
+                params("raw", 1),
+            );
+
+        let mut announcer = stream::Announcer::new(params("raw", 1));
+        let mut header = send::HeaderWriter::new(Framing::Native);
+        let mut send_audio = |stream: u16, sample: f32, big_endian: bool| {
+            let mut buf = vec![0; packet::HEADER_SIZE];
+            header.write(&mut buf, 1, stream);
+            if big_endian {
+                buf.extend_from_slice(&sample.to_be_bytes());
+            } else {
+                buf.extend_from_slice(&sample.to_le_bytes());
+            }
+            buf
+        };
+        let mut packets = vec![announcer.packet(None).unwrap()];
+        packets.push(send_audio(announcer.id(), 0.1, false));
+        // The undescribed ones go with the default params.
+        packets.push(send_audio(0, 0.2, false));
+        announcer.switch(params("raw-f32be", 1));
+        packets.push(announcer.packet(None).unwrap());
+        packets.push(send_audio(announcer.id(), 0.3, true));
+        packets.push(send_audio(announcer.id().wrapping_add(1), 0.4, true));
+        packets.push(send_audio(announcer.id(), 0.5, true));
+        for packet in packets {
+            sender.send_to(&packet, &receiver_addr).await.unwrap();
+        }
+
+        let mut played = vec![0.0; 4];
+        run_until(
+            recv_service.recv_loop(Arc::new(receiver)),
+            playback_reader.read_exact_items(&mut played, WaitMode::WaitForReady),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(played, [0.1, 0.2, 0.3, 0.5]);
+        let peer_stats = recv_service.peer_stats.values().next().unwrap();
+        assert_eq!(peer_stats.stream_configs_received, 2);
+        assert_eq!(peer_stats.stream_switches, 1);
+        assert_eq!(peer_stats.packets_of_unknown_streams, 1);
+    }
+
+    #[tokio::test]
+    async fn send_switches_the_codec_as_asked() {
+        let (sender, receiver) = transport::memory::pair();
+        let receiver_addr = receiver.local_addr();
+
+        let registry = Arc::new(codec::registry::Registry::with_builtin());
+        let stream_config = pcm::StreamConfig::new(48000.into(), 1);
+        let settings = codec::registry::Settings {
+            max_packet_size: mtu::DEFAULT_MAX_PAYLOAD_SIZE,
+            max_bitrate: None,
+            fec_expected_packet_loss: None,
+        };
+        let factory = |registry: &codec::registry::Registry, params: &handshake::Params| {
+            registry
+                .get(&params.codec)
+                .ok_or_else(|| anyhow::format_err!("no {}", params.codec))
+        };
+
+        let codec_switch = stream::CodecSwitch::new(vec!["raw-f32be".to_owned()]);
+        let (mut capture_writer, capture_reader) = buf::vec_deque_buffer_with_capacity(100);
+        let mut send_service: SendService<'_, f32, _, codec::registry::DynEncoder, _> =
+            send_service(
+                capture_reader,
+                factory(&registry, &params("raw", 1))
+                    .unwrap()
+                    .encoder(stream_config, &settings)
+                    .unwrap(),
+            );
+        send_service.encoder_factory = Some({
+            let registry = registry.clone();
+            Box::new(move |params| factory(&registry, params)?.encoder(stream_config, &settings))
+        });
+        send_service.codec_switch = Some(codec_switch.clone());
+        send_service.announcer = Some(stream::Announcer::new(params("raw", 1)));
+        let (playback_writer, mut playback_reader) = buf::vec_deque_buffer_with_capacity(100);
+        let mut recv_service: RecvService<'_, f32, _, codec::registry::DynDecoder, _> =
+            recv_service(
+                playback_writer,
+                Box::new(move |params| {
+                    factory(&registry, params)?.decoder(stream_config, &settings)
+                }),
+                params("raw", 1),
+            );
+
+        let net = select(
+            send_service
+                .send_loop(Arc::new(sender), std::iter::once(receiver_addr).collect())
+                .boxed(),
+            recv_service.recv_loop(Arc::new(receiver)).boxed(),
+        )
+        .map(|either| either.factor_first().0);
+        let mut played = vec![0.0; 3];
+        run_until(net, async {
+            for (position, &sample) in [0.1, 0.2, 0.3].iter().enumerate() {
+                if position == 1 {
+                    // The sender may be waiting for the audio with the old
+                    // encoder already, so it switches by the next packet at
+                    // the latest.
+                    codec_switch.request("raw-f32be").unwrap();
+                }
+                capture_writer
+                    .write_items(&[sample], WaitMode::NoWait)
+                    .await
+                    .unwrap();
+                playback_reader
+                    .read_exact_items(&mut played[position..=position], WaitMode::WaitForReady)
+                    .await
+                    .unwrap();
+            }
+        })
+        .await;
+
+        assert_eq!(played, [0.1, 0.2, 0.3]);
+        assert_eq!(send_service.stats.encoder_switches, 1);
+        assert_eq!(
+            send_service.announcer.as_ref().unwrap().params(),
+            &params("raw-f32be", 1)
+        );
+        let peer_stats = recv_service.peer_stats.values().next().unwrap();
+        assert_eq!(peer_stats.stream_switches, 1);
+    }
+
+    #[tokio::test]
+    async fn recv_survives_undecodable_packets() {
+        let (sender, receiver) = transport::memory::pair();
+        let receiver_addr = receiver.local_addr();
+
+        let (playback_writer, mut playback_reader) = buf::vec_deque_buffer_with_capacity(100);
+        let mut recv_service = recv_service(
+            playback_writer,
+            Box::new(|_| Ok(Box::<codec::raw::Decoder>::default())),
+            params("raw", 1),
+        );
+
+        let mut header = send::HeaderWriter::new(Framing::Native);
+        let mut packet = |payload: &[u8]| {
+            let mut buf = vec![0; packet::HEADER_SIZE];
+            header.write(&mut buf, 1, 0);
+            buf.extend_from_slice(payload);
+            buf
+        };
+        // Not a whole amount of the f32 samples.
+        let garbage = packet(&[1, 2, 3]);
+        let valid = packet(&0.5_f32.to_le_bytes());
+        for packet in [garbage, valid] {
+            sender.send_to(&packet, &receiver_addr).await.unwrap();
+        }
+
+        let mut played = vec![0.0];
+        run_until(
+            recv_service.recv_loop(Arc::new(receiver)),
+            playback_reader.read_exact_items(&mut played, WaitMode::WaitForReady),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(played, [0.5]);
+        let peer_stats = recv_service.peer_stats.values().next().unwrap();
+        assert_eq!(peer_stats.decoding_errors, 1);
+        assert_eq!(peer_stats.frames_decoded, 1);
+    }
 }
@@ -0,0 +1,80 @@
+//! The limits on the size of the datagrams we send.
+//!
+//! The datagrams above the path MTU are fragmented at the IP layer, and
+//! a lot of networks drop the fragments, so we keep the datagrams under
+//! the configured max payload size, and the codecs fill only the room left
+//! after the headers.
+
+use super::crypto;
+use super::packet;
+use super::SIZE;
+use crate::codec::registry::MAX_CHANNELS;
+use thiserror::Error;
+
+/// The default max size of the UDP payload: fits the 1500-byte Ethernet MTU
+/// less the IPv6 and UDP headers.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 1500 - 40 - 8;
+
+/// The biggest max payload size we can handle.
+pub const MAX_PAYLOAD_SIZE: usize = SIZE;
+
+/// The least room for the encoded audio that makes sense. Fits a frame of
+/// the raw `f32` samples of the most channels we carry.
+pub const MIN_AUDIO_PAYLOAD_SIZE: usize = MAX_CHANNELS as usize * std::mem::size_of::<f32>();
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("the max payload size of {0} bytes is above the supported {MAX_PAYLOAD_SIZE} bytes")]
+    TooBig(usize),
+    #[error(
+        "the max payload size of {0} bytes leaves less than {MIN_AUDIO_PAYLOAD_SIZE} bytes \
+         for the audio"
+    )]
+    TooSmall(usize),
+}
+
+/// The room for the encoded audio in the datagrams of `max_payload_size`
+/// bytes, after the header and the encryption overhead if `encrypted`.
+pub fn audio_payload_size(max_payload_size: usize, encrypted: bool) -> Result<usize, Error> {
+    if max_payload_size > MAX_PAYLOAD_SIZE {
+        return Err(Error::TooBig(max_payload_size));
+    }
+    let overhead = if encrypted {
+        packet::HEADER_SIZE + crypto::OVERHEAD
+    } else {
+        packet::HEADER_SIZE
+    };
+    match max_payload_size.checked_sub(overhead) {
+        Some(room) if room >= MIN_AUDIO_PAYLOAD_SIZE => Ok(room),
+        _ => Err(Error::TooSmall(max_payload_size)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_payload() {
+        assert_eq!(
+            audio_payload_size(DEFAULT_MAX_PAYLOAD_SIZE, false).unwrap(),
+            DEFAULT_MAX_PAYLOAD_SIZE - packet::HEADER_SIZE
+        );
+        assert_eq!(
+            audio_payload_size(DEFAULT_MAX_PAYLOAD_SIZE, true).unwrap(),
+            DEFAULT_MAX_PAYLOAD_SIZE - packet::HEADER_SIZE - crypto::OVERHEAD
+        );
+        assert!(matches!(
+            audio_payload_size(MAX_PAYLOAD_SIZE + 1, false),
+            Err(Error::TooBig(_))
+        ));
+        assert!(matches!(
+            audio_payload_size(packet::HEADER_SIZE + 1, false),
+            Err(Error::TooSmall(_))
+        ));
+        assert!(matches!(
+            audio_payload_size(packet::HEADER_SIZE + MIN_AUDIO_PAYLOAD_SIZE, true),
+            Err(Error::TooSmall(_))
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+//! IP multicast, for feeding many receivers on a LAN with a single datagram
+//! per packet.
+//!
+//! The receivers join the group on the bind side, and the senders just send
+//! to the group address.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+/// The network interface to use for the multicast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    /// Let the OS pick.
+    Default,
+    /// The IPv4 interface with this address.
+    V4(Ipv4Addr),
+    /// The IPv6 interface with this index.
+    V6(u32),
+}
+
+impl Default for Interface {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("expected an IPv4 address or an IPv6 interface index, got {0:?}")]
+pub struct InterfaceParseError(String);
+
+impl FromStr for Interface {
+    type Err = InterfaceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Self::V4(addr));
+        }
+        if let Ok(index) = s.parse() {
+            return Ok(Self::V6(index));
+        }
+        Err(InterfaceParseError(s.to_owned()))
+    }
+}
+
+/// The multicast params of the socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The groups to receive from.
+    pub groups: Vec<IpAddr>,
+    /// The interface to join the groups at and to send from.
+    pub interface: Interface,
+    /// How many hops the datagrams we send can make; 1 keeps them within
+    /// the local network.
+    pub ttl: u32,
+    /// Whether the datagrams we send are also delivered to the listeners on
+    /// this host, including ourselves.
+    pub loopback: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            groups: Vec::new(),
+            interface: Interface::Default,
+            ttl: 1,
+            loopback: false,
+        }
+    }
+}
+
+/// Bind the UDP socket to the `addr`, join the multicast groups and apply
+/// the multicast send params.
+///
+/// The address is bound for reuse when joining the groups, so that multiple
+/// receivers on the same host can listen to the same group.
+///
+/// Must be called within the tokio runtime.
+pub fn bind(addr: SocketAddr, config: &Config) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if !config.groups.is_empty() {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+
+    match addr {
+        SocketAddr::V4(_) => {
+            let interface = match config.interface {
+                Interface::Default => Ipv4Addr::UNSPECIFIED,
+                Interface::V4(interface) => interface,
+                Interface::V6(_) => return Err(mismatch("interface", addr)),
+            };
+            for group in &config.groups {
+                let IpAddr::V4(group) = group else {
+                    return Err(mismatch("group", addr));
+                };
+                socket.join_multicast_v4(group, &interface)?;
+            }
+            if let Interface::V4(interface) = config.interface {
+                socket.set_multicast_if_v4(&interface)?;
+            }
+            socket.set_multicast_ttl_v4(config.ttl)?;
+            socket.set_multicast_loop_v4(config.loopback)?;
+        }
+        SocketAddr::V6(_) => {
+            let interface = match config.interface {
+                Interface::Default => 0,
+                Interface::V6(interface) => interface,
+                Interface::V4(_) => return Err(mismatch("interface", addr)),
+            };
+            for group in &config.groups {
+                let IpAddr::V6(group) = group else {
+                    return Err(mismatch("group", addr));
+                };
+                socket.join_multicast_v6(group, interface)?;
+            }
+            if let Interface::V6(interface) = config.interface {
+                socket.set_multicast_if_v6(interface)?;
+            }
+            socket.set_multicast_hops_v6(config.ttl)?;
+            socket.set_multicast_loop_v6(config.loopback)?;
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn mismatch(what: &str, addr: SocketAddr) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("multicast {what} does not match the address family of {addr}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_interface() {
+        assert_eq!(
+            "192.168.1.2".parse(),
+            Ok(Interface::V4(Ipv4Addr::new(192, 168, 1, 2)))
+        );
+        assert_eq!("3".parse(), Ok(Interface::V6(3)));
+        assert!("eth0".parse::<Interface>().is_err());
+    }
+
+    #[tokio::test]
+    async fn family_mismatch() {
+        let config = Config {
+            groups: vec!["ff02::1".parse().unwrap()],
+            ..Config::default()
+        };
+        let err = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
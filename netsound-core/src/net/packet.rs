@@ -0,0 +1,152 @@
+//! The wire format of the packets we exchange over the network.
+//!
+//! Every packet starts with a fixed-size [`Header`], followed by the payload.
+//!
+//! ```text
+//!  0               1               2               3
+//! +---------------+---------------+-------------------------------+
+//! |    version    | payload type  |            stream             |
+//! +---------------+---------------+-------------------------------+
+//! |                        sequence number                        |
+//! +---------------------------------------------------------------+
+//! |                           timestamp                           |
+//! +---------------------------------------------------------------+
+//! |                            payload                            |
+//! ```
+//!
+//! All the multibyte fields are big-endian.
+
+use byteorder::{BigEndian, ByteOrder};
+use thiserror::Error;
+
+/// The version of the wire format we speak.
+pub const VERSION: u8 = 1;
+
+/// The size of the [`Header`] on the wire, in bytes.
+pub const HEADER_SIZE: usize = 12;
+
+/// The kind of the data a packet carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PayloadType {
+    /// Encoded audio, as produced by the codec.
+    Audio = 0,
+    /// A handshake message, see [`super::handshake`].
+    Control = 1,
+    /// A receiver report, see [`super::feedback`].
+    Feedback = 2,
+    /// The params of the stream in the header, see [`super::stream`].
+    StreamConfig = 3,
+}
+
+impl PayloadType {
+    fn from_u8(val: u8) -> Option<Self> {
+        Some(match val {
+            0 => PayloadType::Audio,
+            1 => PayloadType::Control,
+            2 => PayloadType::Feedback,
+            3 => PayloadType::StreamConfig,
+            _ => return None,
+        })
+    }
+}
+
+/// The packet header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// The kind of the payload.
+    pub payload_type: PayloadType,
+    /// The id of the stream config the audio is encoded with, see
+    /// [`super::stream`], or 0 if the stream isn't described.
+    pub stream: u16,
+    /// The sequence number, incremented by one for every packet sent.
+    /// Wraps around.
+    pub sequence: u32,
+    /// The sample clock position of the first sample in the payload.
+    /// Counts interleaved samples, and wraps around.
+    pub timestamp: u32,
+}
+
+/// An error that can occur while parsing a packet.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("packet is too short: {0} bytes")]
+    TooShort(usize),
+    #[error("unsupported wire format version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown payload type: {0}")]
+    UnknownPayloadType(u8),
+}
+
+impl Header {
+    /// Write the header to the beginning of the `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `buf` is shorter than [`HEADER_SIZE`].
+    pub fn write(&self, buf: &mut [u8]) {
+        let buf = &mut buf[..HEADER_SIZE];
+        buf[0] = VERSION;
+        buf[1] = self.payload_type as u8;
+        BigEndian::write_u16(&mut buf[2..4], self.stream);
+        BigEndian::write_u32(&mut buf[4..8], self.sequence);
+        BigEndian::write_u32(&mut buf[8..12], self.timestamp);
+    }
+
+    /// Read the header from the beginning of the `buf`.
+    pub fn read(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::TooShort(buf.len()));
+        }
+        if buf[0] != VERSION {
+            return Err(Error::UnsupportedVersion(buf[0]));
+        }
+        let payload_type = PayloadType::from_u8(buf[1]).ok_or(Error::UnknownPayloadType(buf[1]))?;
+        Ok(Self {
+            payload_type,
+            stream: BigEndian::read_u16(&buf[2..4]),
+            sequence: BigEndian::read_u32(&buf[4..8]),
+            timestamp: BigEndian::read_u32(&buf[8..12]),
+        })
+    }
+}
+
+/// Split the packet into the header and the payload.
+pub fn parse(packet: &[u8]) -> Result<(Header, &[u8]), Error> {
+    let header = Header::read(packet)?;
+    Ok((header, &packet[HEADER_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let header = Header {
+            payload_type: PayloadType::Audio,
+            stream: 0xABCD,
+            sequence: 0xDEAD_BEEF,
+            timestamp: 0x0102_0304,
+        };
+        let mut buf = [0_u8; HEADER_SIZE + 2];
+        header.write(&mut buf);
+        buf[HEADER_SIZE..].copy_from_slice(&[7, 8]);
+
+        let (parsed, payload) = parse(&buf).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload, &[7, 8]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse(&[VERSION, 0, 0]), Err(Error::TooShort(3)));
+
+        let mut buf = [0_u8; HEADER_SIZE];
+        assert_eq!(parse(&buf), Err(Error::UnsupportedVersion(0)));
+
+        buf[0] = VERSION;
+        buf[1] = 0xFF;
+        assert_eq!(parse(&buf), Err(Error::UnknownPayloadType(0xFF)));
+    }
+}
@@ -0,0 +1,192 @@
+//! The set of the peers we send the audio to, which can change while
+//! the services run.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::iter::FromIterator;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// The ttl is too long to tell when it runs out.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("the ttl of {0:?} is too long")]
+pub struct TtlTooLong(pub Duration);
+
+/// When the `ttl` starting now runs out.
+fn expiry(ttl: Duration) -> Result<Instant, TtlTooLong> {
+    Instant::now().checked_add(ttl).ok_or(TtlTooLong(ttl))
+}
+
+/// The shared, mutable set of the peers to send to.
+///
+/// Every peer either stays until removed, or expires at some point unless
+/// its expiry is extended.
+#[derive(Debug)]
+pub struct PeerRegistry<A> {
+    peers: Arc<Mutex<HashMap<A, Option<Instant>>>>,
+}
+
+// Derived `Clone` would require `A: Clone`, which is not needed.
+impl<A> Clone for PeerRegistry<A> {
+    fn clone(&self) -> Self {
+        Self {
+            peers: Arc::clone(&self.peers),
+        }
+    }
+}
+
+impl<A> Default for PeerRegistry<A> {
+    fn default() -> Self {
+        Self {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<A: Clone + Eq + Hash> PeerRegistry<A> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the `peer`, to stay until removed if the `ttl` is not set, and to
+    /// expire after the `ttl` otherwise. Replaces the expiry of the peer if
+    /// it's already there.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `ttl` is too long, leaving the peers as they are.
+    pub fn add(&self, peer: A, ttl: Option<Duration>) -> Result<(), TtlTooLong> {
+        let expires_at = ttl.map(expiry).transpose()?;
+        self.lock().insert(peer, expires_at);
+        Ok(())
+    }
+
+    /// Add the `peer` to expire after the `ttl`, or push its expiry back if
+    /// it's already there. The peers that stay until removed are left as is.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `ttl` is too long, leaving the peers as they are.
+    pub fn touch(&self, peer: &A, ttl: Duration) -> Result<(), TtlTooLong> {
+        let expires_at = expiry(ttl)?;
+        self.lock()
+            .entry(peer.clone())
+            .and_modify(|entry| {
+                if let Some(entry) = entry {
+                    *entry = (*entry).max(expires_at);
+                }
+            })
+            .or_insert(Some(expires_at));
+        Ok(())
+    }
+
+    /// Extend the expiry of the `peer` if it's there. The peers that stay
+    /// until removed are left as is.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `ttl` is too long, leaving the peers as they are.
+    pub fn refresh(&self, peer: &A, ttl: Duration) -> Result<(), TtlTooLong> {
+        let expires_at = expiry(ttl)?;
+        if let Some(Some(entry)) = self.lock().get_mut(peer) {
+            *entry = (*entry).max(expires_at);
+        }
+        Ok(())
+    }
+
+    /// Remove the `peer`, and tell whether it was there.
+    pub fn remove(&self, peer: &A) -> bool {
+        self.lock().remove(peer).is_some()
+    }
+
+    /// The peers that haven't expired by `now`, along with their expiry.
+    /// Forgets the expired ones.
+    #[must_use]
+    pub fn list(&self, now: Instant) -> Vec<(A, Option<Instant>)> {
+        let mut peers = self.lock();
+        peers.retain(|_, expires_at| expires_at.map_or(true, |expires_at| expires_at > now));
+        peers
+            .iter()
+            .map(|(peer, expires_at)| (peer.clone(), *expires_at))
+            .collect()
+    }
+
+    /// The peers that haven't expired by `now`.
+    #[must_use]
+    pub fn current(&self, now: Instant) -> Vec<A> {
+        self.list(now).into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<A, Option<Instant>>> {
+        self.peers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<A: Clone + Eq + Hash> FromIterator<A> for PeerRegistry<A> {
+    /// The registry of the peers that stay until removed.
+    fn from_iter<I: IntoIterator<Item = A>>(iter: I) -> Self {
+        let peers = iter.into_iter().map(|peer| (peer, None)).collect();
+        Self {
+            peers: Arc::new(Mutex::new(peers)),
+        }
+    }
+}
+
+/// Adds the peers that greet us with the handshake to the registry, and
+/// keeps them there for as long as they keep sending.
+#[derive(Debug)]
+pub struct AutoJoin<A> {
+    pub peers: PeerRegistry<A>,
+    /// How long the peer stays after the last packet from it.
+    pub ttl: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let registry: PeerRegistry<u32> = std::iter::once(1).collect();
+        registry.add(2, Some(Duration::from_secs(10))).unwrap();
+        registry.touch(&3, Duration::from_secs(20)).unwrap();
+        registry.touch(&1, Duration::from_secs(20)).unwrap();
+
+        let now = Instant::now();
+        let mut current = registry.current(now);
+        current.sort_unstable();
+        assert_eq!(current, vec![1, 2, 3]);
+
+        let later = now + Duration::from_secs(15);
+        let mut current = registry.current(later);
+        current.sort_unstable();
+        assert_eq!(current, vec![1, 3]);
+
+        registry.refresh(&3, Duration::from_secs(30)).unwrap();
+        registry.refresh(&2, Duration::from_secs(30)).unwrap();
+        let later = now + Duration::from_secs(25);
+        let mut current = registry.current(later);
+        current.sort_unstable();
+        assert_eq!(current, vec![1, 3]);
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let registry = PeerRegistry::new();
+        registry.add("a", None).unwrap();
+        assert!(registry.remove(&"a"));
+        assert!(!registry.remove(&"a"));
+        assert!(registry.current(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn too_long_ttl() {
+        let registry = PeerRegistry::new();
+        let ttl = Duration::from_secs(u64::MAX);
+        assert_eq!(registry.add("a", Some(ttl)), Err(TtlTooLong(ttl)));
+        assert_eq!(registry.touch(&"a", ttl), Err(TtlTooLong(ttl)));
+        assert!(registry.current(Instant::now()).is_empty());
+    }
+}
@@ -1,12 +1,54 @@
-use crate::codec::{self, Decoder};
-use crate::io::AsyncWriteItems;
-use crate::log::{debug, error, trace, warn, KV};
-use crate::pcm::Sample;
+use crate::codec::Decoder;
+use crate::io::{AsyncWriteItems, AsyncWriteItemsExt, WaitMode};
+use crate::log::{debug, info, trace, warn, KV};
+use crate::metrics;
+use crate::pcm;
+use dasp_sample::{Duplex, Sample};
 use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
 use std::{marker::PhantomData, sync::Arc};
-use tokio::net::UdpSocket;
 
-use super::SIZE;
+use super::crypto;
+use super::feedback;
+use super::handshake;
+use super::jitter::JitterBufferConfig;
+use super::mixer::Mixer;
+use super::packet;
+use super::rtp;
+use super::sequence::Arrival;
+use super::{AutoJoin, Framing, Transport};
+
+pub(super) mod peer;
+
+use peer::Peer;
+pub use peer::PeerStats;
+
+/// The max amount of remote senders we receive from at the same time.
+/// Packets from the senders beyond this are dropped.
+const MAX_PEERS: usize = 64;
+
+/// A remote sender we haven't heard from for this long is forgotten.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far behind the other senders a sender can fall before we stop
+/// waiting for it when mixing.
+pub(super) const MAX_MIX_LAG: Duration = Duration::from_millis(100);
+
+/// The header of the incoming packet, as it came on the wire.
+#[derive(Debug, Clone, Copy)]
+enum WireHeader {
+    Native(packet::Header),
+    Rtp(rtp::Header),
+}
+
+/// Creates the decoder for every stream of the remote senders, given
+/// the params of the stream.
+pub type DecoderFactory<'a, TDecoder> =
+    Box<dyn FnMut(&handshake::Params) -> Result<Box<TDecoder>, crate::Error> + Send + 'a>;
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug, Default, Clone, Serialize, Deserialize, KV)]
@@ -14,73 +56,465 @@ pub struct RecvStats {
     pub data_arrived_but_was_dropped_due_to_lock_conention: usize,
     pub packets_read: usize,
     pub bytes_read: usize,
-    pub samples_decoded: usize,
-    pub frames_decoded: usize,
-    pub empty_packets_read: usize,
-    pub empty_packets_decoding_errors: usize,
+    pub oversized_packets: usize,
+    pub invalid_packets: usize,
+    pub packets_failed_authentication: usize,
+    pub packets_replayed: usize,
+    pub packets_lost: usize,
+    pub packets_reordered: usize,
+    pub packets_duplicated: usize,
+    pub packets_too_old: usize,
+    pub peers: usize,
+    pub peers_joined: usize,
+    pub peers_expired: usize,
+    pub packets_from_excess_peers_dropped: usize,
+    pub samples_mixed: usize,
+    pub samples_clipped: usize,
+    pub handshakes_answered: usize,
+    pub feedback_reports_sent: usize,
+    pub feedback_reports_received: usize,
 }
 
 #[allow(clippy::module_name_repetitions)]
-#[derive(Debug)]
-pub struct RecvService<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder>
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct RecvService<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder, TAddr = SocketAddr>
 where
-    TPlaybackSample: Sample,
+    TPlaybackSample: pcm::Sample,
     TPlaybackDataWriter: AsyncWriteItems<TPlaybackSample>,
-    TDecoder: Decoder<TPlaybackSample, TPlaybackDataWriter> + ?Sized,
+    TDecoder: Decoder<TPlaybackSample, Vec<TPlaybackSample>> + ?Sized,
 {
     pub playback_sample: PhantomData<TPlaybackSample>,
     pub playback_data_writer: TPlaybackDataWriter,
-    pub decoder: &'a mut TDecoder,
+    #[derivative(Debug = "ignore")]
+    pub decoder_factory: DecoderFactory<'a, TDecoder>,
+    /// The params of the streams that don't describe themselves, like
+    /// the RTP ones, see [`super::stream`].
+    pub stream_params: handshake::Params,
+    pub jitter_buffer_config: JitterBufferConfig,
+    pub playback_stream_config: pcm::StreamConfig<TPlaybackSample>,
+    pub framing: Framing,
+    /// The max size of the datagrams to accept, see [`super::mtu`]. The bigger
+    /// ones are dropped.
+    pub max_payload_size: usize,
+    /// Decrypts and authenticates the packets, if set. Packets that don't
+    /// pass are dropped.
+    pub cipher: Option<crypto::Opener>,
+    /// Answers the handshakes of the joining peers, if set.
+    pub handshake: Option<handshake::Responder>,
+    /// Starts sending to the peers that greet us, if set.
+    pub auto_join: Option<AutoJoin<TAddr>>,
+    /// Reports the network conditions back to the senders, if set.
+    pub feedback: Option<feedback::Reporter<TAddr>>,
+    /// Collects the reports of the peers we send to, if set.
+    pub feedback_reports: Option<feedback::Reports<TAddr>>,
     pub stats: RecvStats,
+    /// The stats of every remote sender we're currently receiving from.
+    pub peer_stats: HashMap<TAddr, PeerStats>,
+    /// Where to publish the stats for the metrics, if anywhere.
+    pub metrics: Option<metrics::Registry>,
 }
 
-impl<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder>
-    RecvService<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder>
+impl<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder, TAddr>
+    RecvService<'a, TPlaybackSample, TPlaybackDataWriter, TDecoder, TAddr>
 where
-    TPlaybackSample: Sample,
+    TPlaybackSample: pcm::Sample + Duplex<f32>,
     TPlaybackDataWriter: AsyncWriteItems<TPlaybackSample> + Unpin,
-    TDecoder: Decoder<TPlaybackSample, TPlaybackDataWriter> + ?Sized,
+    TDecoder: Decoder<TPlaybackSample, Vec<TPlaybackSample>> + Send + ?Sized,
+    TAddr: Clone + Eq + Hash + Debug,
 {
-    pub async fn recv_loop(
+    pub async fn recv_loop<T: Transport<Addr = TAddr> + ?Sized>(
         &mut self,
-        socket: Arc<UdpSocket>,
+        socket: Arc<T>,
     ) -> Result<futures::never::Never, crate::Error> {
-        let mut recv_buf = [0_u8; SIZE];
+        // One extra byte to tell the oversized datagrams, which are
+        // truncated to fit, from the ones that fit exactly.
+        let mut recv_buf = vec![0_u8; self.max_payload_size + 1];
+        let mut peers: HashMap<TAddr, Peer<TPlaybackSample, TDecoder>> = HashMap::new();
+        let channels = self.playback_stream_config.channels();
+        let max_mix_lag = duration_samples(
+            MAX_MIX_LAG,
+            self.playback_stream_config.sample_rate().as_usize() * channels,
+        );
+        let mut mixer = Mixer::new(max_mix_lag, channels);
+        let mut publishing = metrics::Throttle::default();
         loop {
             trace!("Recv loop begin");
 
             trace!("Recv: before recv");
-            let num_recv = socket.recv(&mut recv_buf).await?;
-            trace!("Recv: after recv, read a packet of {} bytes", num_recv);
-            self.stats.packets_read += 1;
-            self.stats.bytes_read += num_recv;
-
-            if num_recv > 0 {
-                trace!("Recv: before decode");
-                match self
-                    .decoder
-                    .decode(&recv_buf[..num_recv], &mut self.playback_data_writer)
+            let deadline = peers.values().filter_map(Peer::next_deadline).min();
+            let recv = socket.recv_from(&mut recv_buf);
+            let received = match deadline {
+                None => Some(recv.await?),
+                Some(deadline) => tokio::time::timeout_at(deadline.into(), recv)
                     .await
-                {
-                    Ok(num_samples) => {
-                        trace!("Recv: after decode, samples decoded: {}", num_samples);
-                        self.stats.samples_decoded += num_samples;
-                        self.stats.frames_decoded += 1;
-                    }
-                    Err(codec::error::Decoding::EmptyPacket(_)) => {
-                        self.stats.empty_packets_decoding_errors += 1;
-                        // noop
-                    }
-                    Err(err) => {
-                        error!("Decoding failed: {}", &err);
-                        return Err(err.into());
-                    }
+                    .ok()
+                    .transpose()?,
+            };
+            let now = Instant::now();
+
+            if let Some((num_recv, addr)) = received {
+                trace!(
+                    "Recv: after recv, read a packet of {} bytes from {:?}",
+                    num_recv,
+                    addr
+                );
+                self.stats.packets_read += 1;
+                self.stats.bytes_read += num_recv;
+                let reply = if num_recv > self.max_payload_size {
+                    debug!(
+                        "Recv: dropping a packet from {:?} above the max payload size",
+                        addr
+                    );
+                    self.stats.oversized_packets += 1;
+                    None
+                } else {
+                    self.handle_packet(&mut peers, &mut recv_buf[..num_recv], &addr, now)?
                 };
+                if let Some(reply) = reply {
+                    match socket.send_to(&reply, &addr).await {
+                        Ok(_) => self.stats.handshakes_answered += 1,
+                        Err(err) => warn!("Recv: failed to answer {:?}: {}", addr, err),
+                    }
+                }
             } else {
-                warn!("Recv: skipped processing of an empty incoming packet");
-                self.stats.empty_packets_read += 1;
+                trace!("Recv: playout deadline reached");
+            }
+
+            for (addr, peer) in &mut peers {
+                let peer_stats = self.peer_stats.entry(addr.clone()).or_default();
+                peer.playout(
+                    now,
+                    &mut self.decoder_factory,
+                    &self.stream_params,
+                    peer_stats,
+                )
+                .await;
+                mixer.add(addr, peer.take_decoded().map(Sample::to_sample));
             }
+            self.send_feedback(socket.as_ref(), now).await;
+            self.expire_peers(&mut peers, &mut mixer, now);
+            let ready = mixer.take_ready(|addr| peers.get(addr).map_or(false, Peer::is_pending));
+            self.stats.samples_clipped = mixer.clipped();
+            self.play(ready).await?;
+
             debug!("network recv"; &self.stats);
+            if publishing.due(now) {
+                self.publish_stats();
+            }
+        }
+    }
+
+    fn publish_stats(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_stats("recv", &self.stats);
+            metrics.set_peer_stats("recv_peer", &self.peer_stats);
+        }
+    }
+
+    /// Log the final stats, and publish them for the metrics. Meant to be
+    /// called once the recv loop is stopped.
+    pub fn flush_stats(&self) {
+        info!("network recv totals"; &self.stats);
+        for (addr, peer_stats) in &self.peer_stats {
+            info!("network recv peer totals"; "peer" => ?addr, peer_stats);
+        }
+        self.publish_stats();
+    }
+
+    /// Handle the incoming packet, and return the reply to send back, if
+    /// any.
+    fn handle_packet(
+        &mut self,
+        peers: &mut HashMap<TAddr, Peer<TPlaybackSample, TDecoder>>,
+        buf: &mut [u8],
+        addr: &TAddr,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, crate::Error> {
+        let Some(len) = self.open(buf, addr) else {
+            return Ok(None);
+        };
+        if let Some(auto_join) = &self.auto_join {
+            if let Err(err) = auto_join.peers.refresh(addr, auto_join.ttl) {
+                warn!("Recv: can't keep the peer {:?} joined: {}", addr, err);
+            }
+        }
+
+        let (header, payload) = match self.parse(&buf[..len]) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                warn!("Recv: dropping an invalid packet from {:?}: {}", addr, err);
+                self.stats.invalid_packets += 1;
+                return Ok(None);
+            }
+        };
+        if let WireHeader::Native(packet::Header {
+            payload_type: packet::PayloadType::Control,
+            ..
+        }) = header
+        {
+            return self.handle_control(payload, addr);
+        }
+        if let WireHeader::Native(packet::Header {
+            payload_type: packet::PayloadType::Feedback,
+            ..
+        }) = header
+        {
+            self.handle_feedback(payload, addr, now);
+            return Ok(None);
+        }
+        if let WireHeader::Native(
+            header @ packet::Header {
+                payload_type: packet::PayloadType::StreamConfig,
+                ..
+            },
+        ) = header
+        {
+            self.handle_stream_config(peers, header.stream, payload, addr, now);
+            return Ok(None);
         }
+        self.receive(peers, addr, header, payload, now);
+        Ok(None)
     }
+
+    /// Remember the config of the stream the sender announced.
+    fn handle_stream_config(
+        &mut self,
+        peers: &mut HashMap<TAddr, Peer<TPlaybackSample, TDecoder>>,
+        stream: u16,
+        payload: &[u8],
+        addr: &TAddr,
+        now: Instant,
+    ) {
+        let params = match handshake::Params::read(payload) {
+            Ok(params) => params,
+            Err(err) => {
+                warn!("Recv: dropping an invalid packet from {:?}: {}", addr, err);
+                self.stats.invalid_packets += 1;
+                return;
+            }
+        };
+        trace!("Recv: stream {} of {:?} is {}", stream, addr, params);
+        let Some(peer) = self.peer(peers, addr) else {
+            return;
+        };
+        let peer_stats = self.peer_stats.entry(addr.clone()).or_default();
+        peer.announce(stream, params, now, peer_stats);
+    }
+
+    /// Store the report of the receiver we send to.
+    fn handle_feedback(&mut self, payload: &[u8], addr: &TAddr, now: Instant) {
+        let report = match feedback::Report::read(payload) {
+            Ok(report) => report,
+            Err(err) => {
+                warn!("Recv: dropping an invalid packet from {:?}: {}", addr, err);
+                self.stats.invalid_packets += 1;
+                return;
+            }
+        };
+        trace!("Recv: feedback {:?} from {:?}", report, addr);
+        self.stats.feedback_reports_received += 1;
+        if let Some(reports) = &self.feedback_reports {
+            reports.insert(addr.clone(), report, now);
+        }
+    }
+
+    /// Send the reports that are due to the senders we receive from.
+    async fn send_feedback<T: Transport<Addr = TAddr> + ?Sized>(
+        &mut self,
+        socket: &T,
+        now: Instant,
+    ) {
+        let Some(reporter) = &mut self.feedback else {
+            return;
+        };
+        let mut packets = Vec::new();
+        for (addr, peer_stats) in &self.peer_stats {
+            let Some(report) = reporter.report(addr, peer_stats, now) else {
+                continue;
+            };
+            match reporter.packet(&report) {
+                Ok(packet) => packets.push((addr.clone(), packet)),
+                Err(err) => warn!("Recv: failed to build the report for {:?}: {}", addr, err),
+            }
+        }
+        for (addr, packet) in packets {
+            match socket.send_to(&packet, &addr).await {
+                Ok(_) => self.stats.feedback_reports_sent += 1,
+                Err(err) => warn!("Recv: failed to report to {:?}: {}", addr, err),
+            }
+        }
+    }
+
+    /// Answer the handshake message, if it wants an answer.
+    fn handle_control(
+        &mut self,
+        payload: &[u8],
+        addr: &TAddr,
+    ) -> Result<Option<Vec<u8>>, crate::Error> {
+        let message = match handshake::Message::read(payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Recv: dropping an invalid packet from {:?}: {}", addr, err);
+                self.stats.invalid_packets += 1;
+                return Ok(None);
+            }
+        };
+        debug!("Recv: handshake {:?} from {:?}", message, addr);
+        if let (handshake::Message::Hello(_), Some(auto_join)) = (&message, &self.auto_join) {
+            match auto_join.peers.touch(addr, auto_join.ttl) {
+                Ok(()) => info!("Recv: peer {:?} joined", addr),
+                Err(err) => warn!("Recv: can't let the peer {:?} join: {}", addr, err),
+            }
+        }
+        let Some(responder) = &mut self.handshake else {
+            return Ok(None);
+        };
+        Ok(responder.reply(&message)?)
+    }
+
+    fn parse<'b>(&self, packet: &'b [u8]) -> Result<(WireHeader, &'b [u8]), crate::Error> {
+        match self.framing {
+            Framing::Native => {
+                let (header, payload) = packet::parse(packet)?;
+                Ok((WireHeader::Native(header), payload))
+            }
+            Framing::Rtp(config) => {
+                let (header, payload) = rtp::parse(packet)?;
+                if header.payload_type != config.payload_type {
+                    return Err(anyhow::format_err!(
+                        "unexpected RTP payload type: {}",
+                        header.payload_type
+                    ));
+                }
+                Ok((WireHeader::Rtp(header), payload))
+            }
+        }
+    }
+
+    /// Decrypt the packet in place if we have the cipher, and return
+    /// the size of the plain packet, or `None` if the packet is to be dropped.
+    fn open(&mut self, buf: &mut [u8], addr: &TAddr) -> Option<usize> {
+        let Some(cipher) = &mut self.cipher else {
+            return Some(buf.len());
+        };
+        match cipher.open(buf, SystemTime::now()) {
+            Ok(len) => Some(len),
+            Err(crypto::Error::Replayed | crypto::Error::Stale) => {
+                warn!("Recv: dropping a replayed packet from {:?}", addr);
+                self.stats.packets_replayed += 1;
+                None
+            }
+            Err(err) => {
+                warn!("Recv: dropping a packet from {:?}: {}", addr, err);
+                self.stats.packets_failed_authentication += 1;
+                None
+            }
+        }
+    }
+
+    /// The peer at the `addr`, added if it's new, or `None` if there's no
+    /// room for another peer.
+    fn peer<'p>(
+        &mut self,
+        peers: &'p mut HashMap<TAddr, Peer<TPlaybackSample, TDecoder>>,
+        addr: &TAddr,
+    ) -> Option<&'p mut Peer<TPlaybackSample, TDecoder>> {
+        let peers_len = peers.len();
+        match peers.entry(addr.clone()) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(_) if peers_len >= MAX_PEERS => {
+                warn!("Recv: too many peers, dropping a packet from {:?}", addr);
+                self.stats.packets_from_excess_peers_dropped += 1;
+                None
+            }
+            Entry::Vacant(entry) => {
+                info!("Recv: new peer {:?}", addr);
+                self.stats.peers_joined += 1;
+                self.stats.peers = peers_len + 1;
+                Some(entry.insert(Peer::new(self.jitter_buffer_config)))
+            }
+        }
+    }
+
+    /// Pass the packet to the peer it came from, adding the peer if it's new.
+    fn receive(
+        &mut self,
+        peers: &mut HashMap<TAddr, Peer<TPlaybackSample, TDecoder>>,
+        addr: &TAddr,
+        header: WireHeader,
+        payload: &[u8],
+        now: Instant,
+    ) {
+        let Some(peer) = self.peer(peers, addr) else {
+            return;
+        };
+
+        let header = match header {
+            WireHeader::Native(header) => header,
+            WireHeader::Rtp(header) => peer.extend_rtp_header(header),
+        };
+        let peer_stats = self.peer_stats.entry(addr.clone()).or_default();
+        match peer.receive(header, payload, now, peer_stats) {
+            Arrival::InOrder { lost } => self.stats.packets_lost += lost as usize,
+            Arrival::Reordered => {
+                self.stats.packets_lost = self.stats.packets_lost.saturating_sub(1);
+                self.stats.packets_reordered += 1;
+            }
+            Arrival::Duplicate => self.stats.packets_duplicated += 1,
+            Arrival::TooOld => self.stats.packets_too_old += 1,
+            Arrival::OutOfRange | Arrival::Resync => {}
+        }
+        debug!("network recv peer"; "peer" => ?addr, &*peer_stats);
+    }
+
+    /// Forget the peers that went silent.
+    fn expire_peers(
+        &mut self,
+        peers: &mut HashMap<TAddr, Peer<TPlaybackSample, TDecoder>>,
+        mixer: &mut Mixer<TAddr>,
+        now: Instant,
+    ) {
+        peers.retain(|addr, peer| {
+            if !peer.is_expired(now, PEER_TIMEOUT) {
+                return true;
+            }
+            info!("Recv: peer {:?} expired", addr);
+            self.stats.peers_expired += 1;
+            self.peer_stats.remove(addr);
+            if let Some(reporter) = &mut self.feedback {
+                reporter.forget(addr);
+            }
+            mixer.remove(addr);
+            false
+        });
+        self.stats.peers = peers.len();
+    }
+
+    /// Write the mixed audio to the playback.
+    async fn play(&mut self, mixed: Vec<f32>) -> Result<(), crate::Error> {
+        if mixed.is_empty() {
+            return Ok(());
+        }
+        let mixed: Vec<TPlaybackSample> = mixed
+            .into_iter()
+            .map(TPlaybackSample::from_sample)
+            .collect();
+
+        trace!("Recv: before playback write, samples: {}", mixed.len());
+        self.playback_data_writer
+            .write_items(&mixed, WaitMode::WaitForReady)
+            .await?;
+        self.stats.samples_mixed += mixed.len();
+        Ok(())
+    }
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+pub(super) fn duration_samples(duration: Duration, samples_per_sec: usize) -> usize {
+    (duration.as_secs_f64() * samples_per_sec as f64) as usize
 }
@@ -0,0 +1,358 @@
+use crate::codec::{self, Decoder};
+use crate::log::{info, trace, warn, KV};
+use crate::pcm::Sample;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use super::super::handshake::Params;
+use super::super::jitter::{self, JitterBuffer, JitterBufferConfig, Playout};
+use super::super::packet;
+use super::super::rtp;
+use super::super::sequence::{Arrival, SequenceTracker};
+use super::super::stream::Announced;
+use super::DecoderFactory;
+
+/// The stats of a single remote sender.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, KV)]
+pub struct PeerStats {
+    pub packets_read: usize,
+    pub bytes_read: usize,
+    pub samples_decoded: usize,
+    pub frames_decoded: usize,
+    pub empty_packets_read: usize,
+    pub empty_packets_decoding_errors: usize,
+    pub packets_lost: usize,
+    pub packets_reordered: usize,
+    pub packets_duplicated: usize,
+    pub packets_too_old: usize,
+    pub packets_out_of_range: usize,
+    pub sequence_resyncs: usize,
+    pub jitter_buffer_packets: usize,
+    pub jitter_buffer_target_delay_us: usize,
+    pub jitter_buffer_late_packets_dropped: usize,
+    pub jitter_buffer_delay_changes: usize,
+    pub jitter_buffer_resets: usize,
+    pub jitter_us: usize,
+    pub playout_gaps: usize,
+    pub samples_concealed: usize,
+    pub recovery_attempts: usize,
+    pub stream_configs_received: usize,
+    pub stream_switches: usize,
+    pub packets_of_unknown_streams: usize,
+    pub packets_of_unsupported_streams: usize,
+    pub decoding_errors: usize,
+}
+
+/// The receiving state we keep for every remote sender.
+#[derive(Debug)]
+pub(in crate::net) struct Peer<TPlaybackSample, TDecoder: ?Sized> {
+    /// The decoder of the current stream, built once its first packet comes
+    /// up for playout.
+    decoder: Option<Box<TDecoder>>,
+    /// The id of the current stream, see [`super::super::stream`].
+    stream: u16,
+    /// The params the current decoder was built for.
+    params: Option<Params>,
+    /// The stream we failed to build the decoder for, if any.
+    failed_stream: Option<u16>,
+    announced: Announced,
+    sequence_tracker: SequenceTracker,
+    rtp_sequence: rtp::SequenceExtender,
+    jitter_buffer: JitterBuffer,
+    /// The decoded samples that are yet to be mixed.
+    decoded: Vec<TPlaybackSample>,
+    last_arrival: Instant,
+}
+
+impl<TPlaybackSample, TDecoder> Peer<TPlaybackSample, TDecoder>
+where
+    TPlaybackSample: Sample,
+    TDecoder: Decoder<TPlaybackSample, Vec<TPlaybackSample>> + Send + ?Sized,
+{
+    pub fn new(jitter_buffer_config: JitterBufferConfig) -> Self {
+        Self {
+            decoder: None,
+            stream: 0,
+            params: None,
+            failed_stream: None,
+            announced: Announced::default(),
+            sequence_tracker: SequenceTracker::default(),
+            rtp_sequence: rtp::SequenceExtender::default(),
+            jitter_buffer: JitterBuffer::new(jitter_buffer_config),
+            decoded: Vec::new(),
+            last_arrival: Instant::now(),
+        }
+    }
+
+    /// Convert the RTP header to the native one, extending the sequence
+    /// number.
+    pub fn extend_rtp_header(&mut self, header: rtp::Header) -> packet::Header {
+        packet::Header {
+            payload_type: packet::PayloadType::Audio,
+            stream: 0,
+            sequence: self.rtp_sequence.extend(header.sequence),
+            timestamp: header.timestamp,
+        }
+    }
+
+    /// Accept the packet, and return how it arrived.
+    pub fn receive(
+        &mut self,
+        header: packet::Header,
+        payload: &[u8],
+        arrival: Instant,
+        stats: &mut PeerStats,
+    ) -> Arrival {
+        self.last_arrival = arrival;
+        stats.packets_read += 1;
+        stats.bytes_read += packet::HEADER_SIZE + payload.len();
+
+        let tracked = self.sequence_tracker.track(header.sequence);
+        let process = match tracked {
+            Arrival::InOrder { lost } => {
+                stats.packets_lost += lost as usize;
+                true
+            }
+            Arrival::Reordered => {
+                // The packet was counted as lost when we skipped over it.
+                stats.packets_lost = stats.packets_lost.saturating_sub(1);
+                stats.packets_reordered += 1;
+                true
+            }
+            Arrival::Duplicate => {
+                stats.packets_duplicated += 1;
+                false
+            }
+            Arrival::TooOld => {
+                // We can't tell whether it was counted as lost, so leave the
+                // loss as is, and let the jitter buffer decide whether it's
+                // still on time.
+                stats.packets_too_old += 1;
+                true
+            }
+            Arrival::OutOfRange => {
+                stats.packets_out_of_range += 1;
+                false
+            }
+            Arrival::Resync => {
+                warn!("Recv: incoming packets sequence was restarted");
+                stats.sequence_resyncs += 1;
+                true
+            }
+        };
+
+        if process {
+            let packet = jitter::Packet {
+                stream: header.stream,
+                sequence: header.sequence,
+                timestamp: header.timestamp,
+                payload: payload.to_vec(),
+            };
+            self.jitter_buffer.insert(packet, arrival);
+        }
+        tracked
+    }
+
+    /// Remember the config of the stream the peer announced.
+    pub fn announce(
+        &mut self,
+        stream: u16,
+        params: Params,
+        arrival: Instant,
+        stats: &mut PeerStats,
+    ) {
+        self.last_arrival = arrival;
+        stats.stream_configs_received += 1;
+        if self.announced.insert(stream, params) {
+            trace!("Recv: stream {} announced", stream);
+        }
+    }
+
+    /// When the next packet is due for playout.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.jitter_buffer.next_deadline()
+    }
+
+    /// Whether we have more audio from this peer coming up.
+    pub fn is_pending(&self) -> bool {
+        !self.jitter_buffer.is_empty()
+    }
+
+    /// Whether we haven't heard from this peer for longer than the `timeout`.
+    pub fn is_expired(&self, now: Instant, timeout: Duration) -> bool {
+        !self.is_pending() && now.saturating_duration_since(self.last_arrival) > timeout
+    }
+
+    /// Decode all the packets that are due for playout at `now`, switching
+    /// the decoder with the `decoder_factory` as the stream changes.
+    /// The streams that aren't described are decoded with
+    /// the `default_params`.
+    pub async fn playout(
+        &mut self,
+        now: Instant,
+        decoder_factory: &mut DecoderFactory<'_, TDecoder>,
+        default_params: &Params,
+        stats: &mut PeerStats,
+    ) {
+        while let Some(playout) = self.jitter_buffer.pop(now) {
+            match playout {
+                Playout::Packet(packet) => {
+                    if self.select_stream(packet.stream, decoder_factory, default_params, stats) {
+                        self.process_payload(&packet.payload, stats).await;
+                    }
+                }
+                Playout::Missing { sequence } => {
+                    trace!("Recv: packet {} is missing at playout", sequence);
+                    stats.playout_gaps += 1;
+                    self.replace_missing(stats).await;
+                }
+            }
+        }
+        self.update_jitter_buffer_stats(stats);
+    }
+
+    /// Take the decoded samples.
+    pub fn take_decoded(&mut self) -> std::vec::Drain<'_, TPlaybackSample> {
+        self.decoded.drain(..)
+    }
+
+    fn update_jitter_buffer_stats(&self, stats: &mut PeerStats) {
+        let jitter_buffer = &self.jitter_buffer;
+        stats.jitter_buffer_packets = jitter_buffer.len();
+        stats.jitter_buffer_target_delay_us = duration_us(jitter_buffer.target_delay());
+        stats.jitter_buffer_late_packets_dropped = jitter_buffer.late_packets();
+        stats.jitter_buffer_delay_changes = jitter_buffer.delay_changes();
+        stats.jitter_buffer_resets = jitter_buffer.resets();
+        stats.jitter_us = duration_us(jitter_buffer.jitter());
+    }
+
+    /// Make the decoder of the `stream` the current one, building it if
+    /// needed, and tell whether the packets of the `stream` can be decoded.
+    fn select_stream(
+        &mut self,
+        stream: u16,
+        decoder_factory: &mut DecoderFactory<'_, TDecoder>,
+        default_params: &Params,
+        stats: &mut PeerStats,
+    ) -> bool {
+        if self.decoder.is_some() && self.stream == stream {
+            return true;
+        }
+        if self.failed_stream == Some(stream) {
+            stats.packets_of_unsupported_streams += 1;
+            return false;
+        }
+        let params = if stream == 0 {
+            default_params
+        } else if let Some(params) = self.announced.get(stream) {
+            params
+        } else {
+            trace!("Recv: dropping a packet of the unknown stream {}", stream);
+            stats.packets_of_unknown_streams += 1;
+            return false;
+        };
+        if self.decoder.is_some() && self.params.as_ref() == Some(params) {
+            // Same params under a new id, the decoder carries on.
+            self.stream = stream;
+            return true;
+        }
+
+        let params = params.clone();
+        match decoder_factory(&params) {
+            Ok(decoder) => {
+                if self.decoder.is_some() {
+                    info!("Recv: switching to {}", params);
+                    stats.stream_switches += 1;
+                }
+                self.decoder = Some(decoder);
+                self.stream = stream;
+                self.params = Some(params);
+                self.failed_stream = None;
+                true
+            }
+            Err(err) => {
+                warn!(
+                    "Recv: can't decode {}, dropping its packets: {}",
+                    params, err
+                );
+                self.failed_stream = Some(stream);
+                stats.packets_of_unsupported_streams += 1;
+                false
+            }
+        }
+    }
+
+    async fn process_payload(&mut self, payload: &[u8], stats: &mut PeerStats) {
+        if payload.is_empty() {
+            warn!("Recv: skipped processing of an empty incoming packet");
+            stats.empty_packets_read += 1;
+            return;
+        }
+
+        let Some(decoder) = &mut self.decoder else {
+            return;
+        };
+        trace!("Recv: before decode");
+        match decoder.decode(payload, &mut self.decoded).await {
+            Ok(num_samples) => {
+                trace!("Recv: after decode, samples decoded: {}", num_samples);
+                stats.samples_decoded += num_samples;
+                stats.frames_decoded += 1;
+            }
+            Err(codec::error::Decoding::EmptyPacket(_)) => {
+                stats.empty_packets_decoding_errors += 1;
+                // noop
+            }
+            Err(err) => self.reset_decoder(&err, stats),
+        };
+    }
+
+    /// Produce a substitute for the missing packet. When the packet that
+    /// follows the missing one is available, we let the decoder recover the
+    /// missing packet from it, otherwise we conceal the loss. The packet of
+    /// another stream is of no use to the decoder.
+    async fn replace_missing(&mut self, stats: &mut PeerStats) {
+        let Some(decoder) = &mut self.decoder else {
+            return;
+        };
+        let stream = self.stream;
+        let next = self
+            .jitter_buffer
+            .front()
+            .filter(|packet| packet.stream == stream && !packet.payload.is_empty());
+
+        trace!("Recv: before replacing missing packet");
+        let result = match next {
+            Some(next) => {
+                stats.recovery_attempts += 1;
+                decoder.recover(&next.payload, &mut self.decoded).await
+            }
+            None => decoder.conceal(&mut self.decoded).await,
+        };
+        match result {
+            Ok(num_samples) => {
+                trace!(
+                    "Recv: after replacing missing packet, samples: {}",
+                    num_samples
+                );
+                stats.samples_concealed += num_samples;
+            }
+            Err(err) => self.reset_decoder(&err, stats),
+        };
+    }
+
+    /// Drop the decoder that failed, so that it's built anew for the next
+    /// packet. The packets come from anyone, so a bad one only costs its
+    /// sender the decoder state.
+    fn reset_decoder(&mut self, err: &codec::error::Decoding, stats: &mut PeerStats) {
+        warn!("Recv: decoding failed, resetting the decoder: {}", err);
+        stats.decoding_errors += 1;
+        self.decoder = None;
+    }
+}
+
+fn duration_us(duration: Duration) -> usize {
+    duration.as_micros().try_into().unwrap_or(usize::MAX)
+}
@@ -0,0 +1,798 @@
+//! The relay, for the groups where the peers can't all reach each other
+//! directly.
+//!
+//! Every client streams to the relay only, and the relay sends every client
+//! the mix of all the other clients, leaving its own voice out.
+//!
+//! The clients register with the regular handshake: the relay answers
+//! the hello with the session params it runs with, and starts mixing for
+//! the client. Every packet from the client keeps it registered, and
+//! the client is dropped once it goes silent for longer than the timeout.
+//! When the audio comes from an unregistered client, e.g. after the relay
+//! restarts, the relay greets it with the hello, and registers it again
+//! once it answers with the matching session.
+//!
+//! A client whose audio we fail to mix or whose mix we fail to encode is
+//! dropped, and registers again with the greeting, while the others carry
+//! on.
+//!
+//! Only the native framing is supported.
+
+use crate::codec::{self, Decoder, Encoder};
+use crate::io::{AsyncWriteItemsExt, WaitMode};
+use crate::log::{debug, info, trace, warn, KV};
+use crate::{buf, metrics};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::crypto;
+use super::handshake::{self, Message};
+use super::jitter::JitterBufferConfig;
+use super::mixer::Mixer;
+use super::packet;
+use super::recv::peer::Peer;
+use super::recv::{duration_samples, DecoderFactory, PeerStats, MAX_MIX_LAG};
+use super::send::HeaderWriter;
+use super::{mtu, Transport};
+
+/// The max amount of clients we relay for at the same time.
+const MAX_CLIENTS: usize = 64;
+
+/// How long the mix for a client can pile up before the encoder, in packets.
+const MIX_BUFFER_FRAMES: usize = 50;
+
+/// How often we greet an unregistered client that keeps sending audio.
+const GREETING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often we look for the expired clients while no packets come.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Creates a new encoder for every client.
+pub type EncoderFactory<'a, TEncoder> =
+    Box<dyn FnMut() -> Result<Box<TEncoder>, crate::Error> + Send + 'a>;
+
+/// The reader of the mix the client's encoder takes the samples from.
+pub type MixReader = buf::VecDequeBufferReader<f32>;
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, KV)]
+pub struct RelayStats {
+    pub packets_read: usize,
+    pub bytes_read: usize,
+    pub oversized_packets: usize,
+    pub invalid_packets: usize,
+    pub packets_failed_authentication: usize,
+    pub packets_replayed: usize,
+    pub packets_from_unregistered_clients_dropped: usize,
+    pub greetings_sent: usize,
+    pub clients: usize,
+    pub clients_joined: usize,
+    pub clients_rejected: usize,
+    pub clients_expired: usize,
+    pub clients_dropped_on_errors: usize,
+    pub frames_encoded: usize,
+    pub packets_sent: usize,
+    pub bytes_sent: usize,
+    pub send_errors: usize,
+    pub encryption_errors: usize,
+    pub mix_samples_dropped: usize,
+}
+
+/// The state we keep for every registered client.
+#[derive(Debug)]
+struct Client<TEncoder: ?Sized, TDecoder: ?Sized, TAddr> {
+    /// Decodes the audio of the client.
+    peer: Peer<f32, TDecoder>,
+    /// Mixes the audio of the other clients for the client.
+    mixer: Mixer<TAddr>,
+    mix_writer: buf::VecDequeBufferWriter<f32>,
+    mix_reader: MixReader,
+    mix_fill_level: buf::FillLevel,
+    encoder: Box<TEncoder>,
+    header: HeaderWriter,
+    last_seen: Instant,
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct RelayService<'a, TEncoder, TDecoder, TAddr = SocketAddr>
+where
+    TEncoder: Encoder<f32, MixReader> + ?Sized,
+    TDecoder: Decoder<f32, Vec<f32>> + ?Sized,
+{
+    /// The codec and the stream params every client has to use.
+    pub params: handshake::Params,
+    #[derivative(Debug = "ignore")]
+    pub encoder_factory: EncoderFactory<'a, TEncoder>,
+    #[derivative(Debug = "ignore")]
+    pub decoder_factory: DecoderFactory<'a, TDecoder>,
+    pub jitter_buffer_config: JitterBufferConfig,
+    /// The amount of the interleaved samples the encoder takes per packet.
+    pub frame_samples: usize,
+    /// How long a client stays registered after the last packet from it.
+    pub client_timeout: Duration,
+    /// The max size of the datagrams to send and accept, see [`mtu`].
+    pub max_payload_size: usize,
+    /// Encrypts the packets we send, if set.
+    pub sealer: Option<crypto::Sealer>,
+    /// Decrypts and authenticates the packets we receive, if set.
+    pub opener: Option<crypto::Opener>,
+    pub stats: RelayStats,
+    /// The stats of the audio coming from every registered client.
+    pub client_stats: HashMap<TAddr, PeerStats>,
+    /// Where to publish the stats for the metrics, if anywhere.
+    pub metrics: Option<metrics::Registry>,
+}
+
+impl<'a, TEncoder, TDecoder, TAddr> RelayService<'a, TEncoder, TDecoder, TAddr>
+where
+    TEncoder: Encoder<f32, MixReader> + ?Sized,
+    TDecoder: Decoder<f32, Vec<f32>> + Send + ?Sized,
+    TAddr: Clone + Eq + Hash + Debug,
+{
+    pub async fn relay_loop<T: Transport<Addr = TAddr> + ?Sized>(
+        &mut self,
+        socket: Arc<T>,
+    ) -> Result<futures::never::Never, crate::Error> {
+        // One extra byte to tell the oversized datagrams, which are
+        // truncated to fit, from the ones that fit exactly.
+        let mut recv_buf = vec![0_u8; self.max_payload_size + 1];
+        let mut send_buf = vec![0_u8; self.max_payload_size];
+        let payload_end = packet::HEADER_SIZE
+            + mtu::audio_payload_size(self.max_payload_size, self.sealer.is_some())?;
+        let mut clients: HashMap<TAddr, Client<TEncoder, TDecoder, TAddr>> = HashMap::new();
+        let mut greeted: HashMap<TAddr, Instant> = HashMap::new();
+        let mut publishing = metrics::Throttle::default();
+        loop {
+            trace!("Relay loop begin");
+
+            let deadline = clients
+                .values()
+                .filter_map(|client| client.peer.next_deadline())
+                .fold(Instant::now() + HOUSEKEEPING_INTERVAL, Instant::min);
+            let received =
+                tokio::time::timeout_at(deadline.into(), socket.recv_from(&mut recv_buf))
+                    .await
+                    .ok()
+                    .transpose()?;
+            let now = Instant::now();
+
+            if let Some((num_recv, addr)) = received {
+                trace!("Relay: read a packet of {} bytes from {:?}", num_recv, addr);
+                self.stats.packets_read += 1;
+                self.stats.bytes_read += num_recv;
+
+                let reply = if num_recv > self.max_payload_size {
+                    debug!(
+                        "Relay: dropping a packet from {:?} above the max payload size",
+                        addr
+                    );
+                    self.stats.oversized_packets += 1;
+                    None
+                } else {
+                    self.handle_packet(
+                        &mut clients,
+                        &mut greeted,
+                        &mut recv_buf[..num_recv],
+                        &addr,
+                        now,
+                    )
+                    .unwrap_or_else(|err| {
+                        warn!("Relay: failed to handle a packet from {:?}: {}", addr, err);
+                        None
+                    })
+                };
+                if let Some(reply) = reply {
+                    if let Err(err) = socket.send_to(&reply, &addr).await {
+                        warn!("Relay: failed to answer {:?}: {}", addr, err);
+                    }
+                }
+            }
+
+            let mut failed = self.mix(&mut clients, now).await;
+            for (addr, client) in &mut clients {
+                if let Err(err) = self
+                    .send_mix(socket.as_ref(), addr, client, &mut send_buf, payload_end)
+                    .await
+                {
+                    warn!("Relay: failed to encode the mix for {:?}: {}", addr, err);
+                    failed.push(addr.clone());
+                }
+            }
+            for addr in failed {
+                info!("Relay: dropping client {:?} on errors", addr);
+                self.remove_client(&mut clients, &addr);
+                self.stats.clients_dropped_on_errors += 1;
+            }
+            self.expire_clients(&mut clients, now);
+            greeted.retain(|_, greeted_at| now < *greeted_at + GREETING_INTERVAL);
+
+            debug!("relay"; &self.stats);
+            if publishing.due(now) {
+                self.publish_stats();
+            }
+        }
+    }
+
+    fn publish_stats(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_stats("relay", &self.stats);
+            metrics.set_peer_stats("relay_client", &self.client_stats);
+        }
+    }
+
+    /// Log the final stats, and publish them for the metrics. Meant to be
+    /// called once the relay loop is stopped.
+    pub fn flush_stats(&self) {
+        info!("relay totals"; &self.stats);
+        for (addr, client_stats) in &self.client_stats {
+            info!("relay client totals"; "client" => ?addr, client_stats);
+        }
+        self.publish_stats();
+    }
+
+    /// Handle the incoming packet, and return the reply to send back, if
+    /// any.
+    fn handle_packet(
+        &mut self,
+        clients: &mut HashMap<TAddr, Client<TEncoder, TDecoder, TAddr>>,
+        greeted: &mut HashMap<TAddr, Instant>,
+        buf: &mut [u8],
+        addr: &TAddr,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, crate::Error> {
+        let Some(len) = self.open(buf, addr) else {
+            return Ok(None);
+        };
+        let (header, payload) = match packet::parse(&buf[..len]) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                warn!("Relay: dropping an invalid packet from {:?}: {}", addr, err);
+                self.stats.invalid_packets += 1;
+                return Ok(None);
+            }
+        };
+
+        if header.payload_type == packet::PayloadType::Control {
+            return self.handle_control(clients, payload, addr, now);
+        }
+        let Some(client) = clients.get_mut(addr) else {
+            trace!("Relay: dropping a packet from unregistered {:?}", addr);
+            self.stats.packets_from_unregistered_clients_dropped += 1;
+            if greeted.contains_key(addr) {
+                return Ok(None);
+            }
+            greeted.insert(addr.clone(), now);
+            self.stats.greetings_sent += 1;
+            let greeting = Message::Hello(self.offer());
+            return Ok(Some(handshake::packet(&greeting, self.sealer.as_mut())?));
+        };
+        client.last_seen = now;
+
+        // The reports of the clients are of no use for us, as we don't adapt
+        // the encoding, but they keep the clients registered all the same.
+        let stats = self.client_stats.entry(addr.clone()).or_default();
+        match header.payload_type {
+            packet::PayloadType::Audio => {
+                client.peer.receive(header, payload, now, stats);
+            }
+            packet::PayloadType::StreamConfig => match handshake::Params::read(payload) {
+                Ok(params) => client.peer.announce(header.stream, params, now, stats),
+                Err(err) => {
+                    warn!("Relay: dropping an invalid packet from {:?}: {}", addr, err);
+                    self.stats.invalid_packets += 1;
+                }
+            },
+            packet::PayloadType::Control | packet::PayloadType::Feedback => {}
+        }
+        Ok(None)
+    }
+
+    /// Register the clients that greet us or answer our greeting.
+    fn handle_control(
+        &mut self,
+        clients: &mut HashMap<TAddr, Client<TEncoder, TDecoder, TAddr>>,
+        payload: &[u8],
+        addr: &TAddr,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, crate::Error> {
+        let message = match Message::read(payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Relay: dropping an invalid packet from {:?}: {}", addr, err);
+                self.stats.invalid_packets += 1;
+                return Ok(None);
+            }
+        };
+        debug!("Relay: handshake {:?} from {:?}", message, addr);
+        match message {
+            Message::Hello(offer) if offer.supports(&self.params) => {
+                if !self.register(clients, addr, now)? {
+                    return Ok(None);
+                }
+                let session = Message::Session(self.params.clone());
+                Ok(Some(handshake::packet(&session, self.sealer.as_mut())?))
+            }
+            Message::Hello(offer) => {
+                warn!(
+                    "Relay: rejecting {:?}, which offers {} while we need {}",
+                    addr, offer, self.params
+                );
+                self.stats.clients_rejected += 1;
+                let offer = Message::Offer(self.offer());
+                Ok(Some(handshake::packet(&offer, self.sealer.as_mut())?))
+            }
+            Message::Session(params) if params == self.params => {
+                self.register(clients, addr, now)?;
+                Ok(None)
+            }
+            Message::Session(params) => {
+                warn!(
+                    "Relay: rejecting {:?}, which streams {} while we need {}",
+                    addr, params, self.params
+                );
+                self.stats.clients_rejected += 1;
+                Ok(None)
+            }
+            Message::Offer(_) => Ok(None),
+        }
+    }
+
+    /// Register the client at the `addr` if it's new, and tell whether it's
+    /// registered.
+    fn register(
+        &mut self,
+        clients: &mut HashMap<TAddr, Client<TEncoder, TDecoder, TAddr>>,
+        addr: &TAddr,
+        now: Instant,
+    ) -> Result<bool, crate::Error> {
+        if let Some(client) = clients.get_mut(addr) {
+            client.last_seen = now;
+            return Ok(true);
+        }
+        if clients.len() >= MAX_CLIENTS {
+            warn!("Relay: too many clients, rejecting {:?}", addr);
+            self.stats.clients_rejected += 1;
+            return Ok(false);
+        }
+
+        info!("Relay: client {:?} joined", addr);
+        let (mix_writer, mix_reader) =
+            buf::vec_deque_buffer_with_capacity(self.frame_samples * MIX_BUFFER_FRAMES);
+        let channels = usize::from(self.params.channels);
+        let max_mix_lag = duration_samples(
+            MAX_MIX_LAG,
+            usize::try_from(self.params.sample_rate)? * channels,
+        );
+        let client = Client {
+            peer: Peer::new(self.jitter_buffer_config),
+            mixer: Mixer::new(max_mix_lag, channels),
+            mix_fill_level: mix_reader.fill_level(),
+            mix_writer,
+            mix_reader,
+            encoder: (self.encoder_factory)()?,
+            header: HeaderWriter::new(super::Framing::Native),
+            last_seen: now,
+        };
+        clients.insert(addr.clone(), client);
+        self.stats.clients_joined += 1;
+        self.stats.clients = clients.len();
+        Ok(true)
+    }
+
+    /// Decode the audio that's due at `now`, and add it to the mixes for
+    /// all the other clients. Returns the clients we failed to mix for.
+    async fn mix(
+        &mut self,
+        clients: &mut HashMap<TAddr, Client<TEncoder, TDecoder, TAddr>>,
+        now: Instant,
+    ) -> Vec<TAddr> {
+        // Every client is a source in the mixes of the others even while
+        // it has nothing to add, so that the mixes wait for it.
+        let mut decoded = Vec::with_capacity(clients.len());
+        for (addr, client) in clients.iter_mut() {
+            let stats = self.client_stats.entry(addr.clone()).or_default();
+            client
+                .peer
+                .playout(now, &mut self.decoder_factory, &self.params, stats)
+                .await;
+            let samples: Vec<f32> = client.peer.take_decoded().collect();
+            decoded.push((addr.clone(), samples));
+        }
+        let pending: HashSet<TAddr> = clients
+            .iter()
+            .filter(|(_, client)| client.peer.is_pending())
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        let mut failed = Vec::new();
+        for (addr, client) in clients.iter_mut() {
+            for (source, samples) in &decoded {
+                if source != addr {
+                    client.mixer.add(source, samples.iter().copied());
+                }
+            }
+            let ready = client
+                .mixer
+                .take_ready(|source| source != addr && pending.contains(source));
+            if ready.is_empty() {
+                continue;
+            }
+            match client
+                .mix_writer
+                .write_items(&ready, WaitMode::NoWait)
+                .await
+            {
+                Ok(written) => self.stats.mix_samples_dropped += ready.len() - written,
+                Err(err) => {
+                    warn!("Relay: failed to mix for {:?}: {}", addr, err);
+                    failed.push(addr.clone());
+                }
+            }
+        }
+        failed
+    }
+
+    /// Encode and send the client all the mix that's piled up for it.
+    async fn send_mix<T: Transport<Addr = TAddr> + ?Sized>(
+        &mut self,
+        socket: &T,
+        addr: &TAddr,
+        client: &mut Client<TEncoder, TDecoder, TAddr>,
+        send_buf: &mut [u8],
+        payload_end: usize,
+    ) -> Result<(), codec::error::Encoding> {
+        while client.mix_fill_level.get() >= self.frame_samples {
+            let encoded = match client
+                .encoder
+                .encode(
+                    &mut client.mix_reader,
+                    &mut send_buf[packet::HEADER_SIZE..payload_end],
+                )
+                .await
+            {
+                Ok(encoded) => encoded,
+                Err(codec::error::Encoding::NotEnoughData(_)) => break,
+                Err(err) => return Err(err),
+            };
+            self.stats.frames_encoded += 1;
+
+            client.header.write(send_buf, encoded.samples, 0);
+            let mut bytes_to_send = packet::HEADER_SIZE + encoded.bytes;
+            if let Some(sealer) = &mut self.sealer {
+                match sealer.seal(send_buf, bytes_to_send, SystemTime::now()) {
+                    Ok(sealed) => bytes_to_send = sealed,
+                    Err(err) => {
+                        warn!("Relay: encryption failed: {}", err);
+                        self.stats.encryption_errors += 1;
+                        continue;
+                    }
+                }
+            }
+
+            match socket.send_to(&send_buf[..bytes_to_send], addr).await {
+                Ok(bytes_sent) => {
+                    self.stats.packets_sent += 1;
+                    self.stats.bytes_sent += bytes_sent;
+                }
+                Err(err) => {
+                    warn!("Relay: failed to send to {:?}: {}", addr, err);
+                    self.stats.send_errors += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the clients that went silent.
+    fn expire_clients(
+        &mut self,
+        clients: &mut HashMap<TAddr, Client<TEncoder, TDecoder, TAddr>>,
+        now: Instant,
+    ) {
+        let expired: Vec<TAddr> = clients
+            .iter()
+            .filter(|(_, client)| {
+                !client.peer.is_pending()
+                    && now.saturating_duration_since(client.last_seen) > self.client_timeout
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in expired {
+            info!("Relay: client {:?} expired", addr);
+            self.remove_client(clients, &addr);
+            self.stats.clients_expired += 1;
+        }
+    }
+
+    /// Forget the client at the `addr`, along with its place in the mixes of
+    /// the others.
+    fn remove_client(
+        &mut self,
+        clients: &mut HashMap<TAddr, Client<TEncoder, TDecoder, TAddr>>,
+        addr: &TAddr,
+    ) {
+        clients.remove(addr);
+        for client in clients.values_mut() {
+            client.mixer.remove(addr);
+        }
+        self.client_stats.remove(addr);
+        self.stats.clients = clients.len();
+    }
+
+    /// Decrypt the packet in place if we have the cipher, and return
+    /// the size of the plain packet, or `None` if the packet is to be dropped.
+    fn open(&mut self, buf: &mut [u8], addr: &TAddr) -> Option<usize> {
+        let Some(opener) = &mut self.opener else {
+            return Some(buf.len());
+        };
+        match opener.open(buf, SystemTime::now()) {
+            Ok(len) => Some(len),
+            Err(crypto::Error::Replayed | crypto::Error::Stale) => {
+                warn!("Relay: dropping a replayed packet from {:?}", addr);
+                self.stats.packets_replayed += 1;
+                None
+            }
+            Err(err) => {
+                warn!("Relay: dropping a packet from {:?}: {}", addr, err);
+                self.stats.packets_failed_authentication += 1;
+                None
+            }
+        }
+    }
+
+    /// The offer of exactly the params we run with.
+    fn offer(&self) -> handshake::Offer {
+        handshake::Offer {
+            codecs: vec![handshake::CodecOffer {
+                name: self.params.codec.clone(),
+                sample_rates: vec![self.params.sample_rate],
+                max_channels: self.params.channels,
+            }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::memory;
+    use futures::FutureExt;
+    use std::convert::TryInto;
+
+    /// A relay of the raw mono audio, in the frames of 4 samples.
+    fn relay_service<TEncoder, TAddr>(
+        encoder_factory: EncoderFactory<'_, TEncoder>,
+    ) -> RelayService<'_, TEncoder, codec::raw::Decoder, TAddr>
+    where
+        TEncoder: Encoder<f32, MixReader> + ?Sized,
+    {
+        RelayService {
+            params: handshake::Params {
+                codec: "raw".to_owned(),
+                sample_rate: 48000,
+                channels: 1,
+            },
+            encoder_factory,
+            decoder_factory: Box::new(|_| Ok(Box::<codec::raw::Decoder>::default())),
+            jitter_buffer_config: JitterBufferConfig::new(48000.into(), 1),
+            frame_samples: 4,
+            client_timeout: Duration::from_secs(10),
+            max_payload_size: mtu::DEFAULT_MAX_PAYLOAD_SIZE,
+            sealer: None,
+            opener: None,
+            stats: RelayStats::default(),
+            client_stats: HashMap::new(),
+            metrics: None,
+        }
+    }
+
+    fn hello() -> Vec<u8> {
+        let offer = handshake::Offer {
+            codecs: vec![handshake::CodecOffer {
+                name: "raw".to_owned(),
+                sample_rates: vec![48000],
+                max_channels: 1,
+            }],
+        };
+        handshake::packet(&Message::Hello(offer), None).unwrap()
+    }
+
+    fn audio(sequence: u32, samples: &[f32]) -> Vec<u8> {
+        let mut buf = vec![0; packet::HEADER_SIZE];
+        packet::Header {
+            payload_type: packet::PayloadType::Audio,
+            stream: 0,
+            sequence,
+            timestamp: sequence * 4,
+        }
+        .write(&mut buf);
+        for sample in samples {
+            buf.extend_from_slice(&sample.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Receive the packets until there's the audio one, and return its
+    /// samples.
+    async fn recv_audio(socket: &memory::Endpoint) -> Vec<f32> {
+        let mut buf = vec![0; mtu::MAX_PAYLOAD_SIZE];
+        loop {
+            let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+            let (header, payload) = packet::parse(&buf[..len]).unwrap();
+            if header.payload_type == packet::PayloadType::Audio {
+                return payload
+                    .chunks_exact(4)
+                    .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mixes_minus_own_voice() {
+        let network = memory::Network::new();
+        let relay_socket = Arc::new(network.bind(memory::Addr(0)).unwrap());
+        let clients: Vec<_> = (1..=3)
+            .map(|n| network.bind(memory::Addr(n)).unwrap())
+            .collect();
+
+        let mut relay = relay_service(Box::new(|| {
+            Ok(Box::new(codec::raw::Encoder::new(
+                codec::raw::Format::default(),
+                1,
+            )))
+        }));
+        let relay_addr = relay_socket.local_addr();
+        let relay_loop = relay.relay_loop(relay_socket).fuse();
+        futures::pin_mut!(relay_loop);
+
+        let test = async {
+            let mut buf = vec![0; mtu::MAX_PAYLOAD_SIZE];
+            for client in &clients {
+                client.send_to(&hello(), &relay_addr).await.unwrap();
+                let (len, _) = client.recv_from(&mut buf).await.unwrap();
+                let (_, payload) = packet::parse(&buf[..len]).unwrap();
+                assert!(matches!(
+                    Message::read(payload).unwrap(),
+                    Message::Session(_)
+                ));
+            }
+
+            // The unregistered ones are greeted.
+            let stranger = network.bind(memory::Addr(9)).unwrap();
+            stranger
+                .send_to(&audio(0, &[0.5; 4]), &relay_addr)
+                .await
+                .unwrap();
+            let (len, _) = stranger.recv_from(&mut buf).await.unwrap();
+            let (_, payload) = packet::parse(&buf[..len]).unwrap();
+            assert!(matches!(Message::read(payload).unwrap(), Message::Hello(_)));
+
+            let levels = [0.1, 0.2, 0.4];
+            for (client, level) in clients.iter().zip(levels) {
+                client
+                    .send_to(&audio(0, &[level; 4]), &relay_addr)
+                    .await
+                    .unwrap();
+            }
+            let mut mixes = Vec::new();
+            for client in &clients {
+                mixes.push(recv_audio(client).await);
+            }
+            mixes
+        };
+        futures::pin_mut!(test);
+
+        let mixes = futures::select! {
+            _ = relay_loop => unreachable!(),
+            mixes = test.fuse() => mixes,
+        };
+        let expected = [0.6, 0.5, 0.3];
+        for (mix, expected) in mixes.iter().zip(expected) {
+            assert!(!mix.is_empty());
+            for sample in mix {
+                assert!(
+                    (sample - expected).abs() < 1e-6,
+                    "{} != {}",
+                    sample,
+                    expected
+                );
+            }
+        }
+    }
+
+    /// Fails to encode anything.
+    struct BrokenEncoder;
+
+    #[async_trait::async_trait]
+    impl Encoder<f32, MixReader> for BrokenEncoder {
+        async fn encode(
+            &mut self,
+            _input: &mut MixReader,
+            _output: &mut [u8],
+        ) -> Result<codec::Encoded, codec::error::Encoding> {
+            Err(codec::error::Encoding::Other(anyhow::format_err!("broken")))
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_mixing_for_the_others_when_a_client_fails() {
+        let network = memory::Network::new();
+        let relay_socket = Arc::new(network.bind(memory::Addr(0)).unwrap());
+        let clients: Vec<_> = (1..=3)
+            .map(|n| network.bind(memory::Addr(n)).unwrap())
+            .collect();
+
+        // The first client to join gets the broken encoder.
+        let mut encoders_created = 0;
+        let mut relay: RelayService<'_, dyn Encoder<f32, MixReader> + Send, _, _> =
+            relay_service(Box::new(move || {
+                encoders_created += 1;
+                if encoders_created == 1 {
+                    return Ok(Box::new(BrokenEncoder));
+                }
+                Ok(Box::new(codec::raw::Encoder::new(
+                    codec::raw::Format::default(),
+                    1,
+                )))
+            }));
+        let relay_addr = relay_socket.local_addr();
+        // The loop borrows the relay until it's dropped.
+        let mixes = {
+            let relay_loop = relay.relay_loop(relay_socket).fuse();
+            futures::pin_mut!(relay_loop);
+
+            let test = async {
+                let mut buf = vec![0; mtu::MAX_PAYLOAD_SIZE];
+                for client in &clients {
+                    client.send_to(&hello(), &relay_addr).await.unwrap();
+                    client.recv_from(&mut buf).await.unwrap();
+                }
+
+                // Not a whole amount of the f32 samples.
+                let mut garbage = audio(0, &[]);
+                garbage.extend_from_slice(&[1, 2, 3]);
+                clients[0].send_to(&garbage, &relay_addr).await.unwrap();
+                for (client, level) in clients[1..].iter().zip([0.2, 0.4]) {
+                    client
+                        .send_to(&audio(0, &[level; 4]), &relay_addr)
+                        .await
+                        .unwrap();
+                }
+                let mut mixes = Vec::new();
+                for client in &clients[1..] {
+                    mixes.push(recv_audio(client).await);
+                }
+                mixes
+            };
+            futures::pin_mut!(test);
+
+            futures::select! {
+                _ = relay_loop => unreachable!(),
+                mixes = test.fuse() => mixes,
+            }
+        };
+        let expected = [0.4, 0.2];
+        for (mix, expected) in mixes.iter().zip(expected) {
+            assert!(!mix.is_empty());
+            for sample in mix {
+                assert!(
+                    (sample - expected).abs() < 1e-6,
+                    "{} != {}",
+                    sample,
+                    expected
+                );
+            }
+        }
+        assert_eq!(relay.stats.clients_dropped_on_errors, 1);
+        assert_eq!(relay.stats.clients, 2);
+    }
+}
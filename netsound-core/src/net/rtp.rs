@@ -0,0 +1,269 @@
+//! The RTP framing (RFC 3550) with the opus payload format (RFC 7587),
+//! for interoperability with the standard tools.
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |V=2|P|X|  CC   |M|     PT      |       sequence number         |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                           timestamp                           |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |           synchronization source (SSRC) identifier            |
+//! +=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+
+//! |            contributing source (CSRC) identifiers             |
+//! |                             ....                              |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! The opus payload is a single opus packet, and the timestamp counts
+//! the sampling instants at the 48 kHz clock, regardless of the actual
+//! sample rate and the number of channels.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+
+/// The version of the RTP.
+pub const VERSION: u8 = 2;
+
+/// The size of the fixed part of the RTP header, which is what we send.
+pub const HEADER_SIZE: usize = 12;
+
+/// The RTP clock rate of the opus payload format.
+pub const OPUS_CLOCK_RATE: u32 = 48000;
+
+/// The dynamic payload type we use for opus by default.
+pub const DEFAULT_PAYLOAD_TYPE: u8 = 111;
+
+/// The max amount of channels the opus payload format carries. The surround
+/// streams only go with the native framing.
+pub const MAX_CHANNELS: usize = 2;
+
+/// The params of the RTP stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// The dynamic payload type number of the opus stream.
+    pub payload_type: u8,
+    /// The amount of the interleaved channels in the audio we send, used
+    /// for counting the sampling instants for the timestamps.
+    pub channels: usize,
+}
+
+/// The RTP header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+/// An error that can occur while parsing an RTP packet.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("packet is too short: {0} bytes")]
+    TooShort(usize),
+    #[error("unsupported RTP version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("invalid padding")]
+    InvalidPadding,
+}
+
+impl Header {
+    /// Write the fixed header, without CSRCs and extensions, to
+    /// the beginning of the `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `buf` is shorter than [`HEADER_SIZE`], or if
+    /// the payload type does not fit into 7 bits.
+    pub fn write(&self, buf: &mut [u8]) {
+        assert!(self.payload_type < 0x80, "payload type must fit 7 bits");
+        let buf = &mut buf[..HEADER_SIZE];
+        buf[0] = VERSION << 6;
+        buf[1] = (u8::from(self.marker) << 7) | self.payload_type;
+        BigEndian::write_u16(&mut buf[2..4], self.sequence);
+        BigEndian::write_u32(&mut buf[4..8], self.timestamp);
+        BigEndian::write_u32(&mut buf[8..12], self.ssrc);
+    }
+}
+
+/// Split the RTP packet into the header and the payload, skipping
+/// the CSRCs, the header extension and the padding.
+pub fn parse(packet: &[u8]) -> Result<(Header, &[u8]), Error> {
+    if packet.len() < HEADER_SIZE {
+        return Err(Error::TooShort(packet.len()));
+    }
+    let version = packet[0] >> 6;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let has_padding = packet[0] & 0x20 != 0;
+    let has_extension = packet[0] & 0x10 != 0;
+    let csrc_count = usize::from(packet[0] & 0x0F);
+
+    let header = Header {
+        marker: packet[1] & 0x80 != 0,
+        payload_type: packet[1] & 0x7F,
+        sequence: BigEndian::read_u16(&packet[2..4]),
+        timestamp: BigEndian::read_u32(&packet[4..8]),
+        ssrc: BigEndian::read_u32(&packet[8..12]),
+    };
+
+    let mut start = HEADER_SIZE + csrc_count * 4;
+    if has_extension {
+        let extension = packet
+            .get(start..start + 4)
+            .ok_or(Error::TooShort(packet.len()))?;
+        start += 4 + usize::from(BigEndian::read_u16(&extension[2..4])) * 4;
+    }
+    let mut end = packet.len();
+    if has_padding {
+        let padding = usize::from(packet[end - 1]);
+        if padding == 0 {
+            return Err(Error::InvalidPadding);
+        }
+        end = end.checked_sub(padding).ok_or(Error::InvalidPadding)?;
+    }
+    if start > end {
+        return Err(Error::TooShort(packet.len()));
+    }
+
+    Ok((header, &packet[start..end]))
+}
+
+/// Extends the 16-bit RTP sequence numbers to 32 bits, by counting
+/// the wraparounds.
+#[derive(Debug, Default)]
+pub struct SequenceExtender {
+    last: Option<u32>,
+}
+
+impl SequenceExtender {
+    /// Extend the `sequence` to the value closest to the last one seen.
+    pub fn extend(&mut self, sequence: u16) -> u32 {
+        let Some(last) = self.last else {
+            self.last = Some(u32::from(sequence));
+            return u32::from(sequence);
+        };
+        #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+        let delta = sequence.wrapping_sub(last as u16) as i16;
+        let extended = last.wrapping_add_signed(i32::from(delta));
+        if delta > 0 {
+            self.last = Some(extended);
+        }
+        extended
+    }
+}
+
+/// Describe the opus stream we send to the `destination` in the SDP
+/// (RFC 4566), so that the tools listening there can receive it.
+#[must_use]
+pub fn opus_sdp(origin: IpAddr, destination: SocketAddr, config: &Config) -> String {
+    fn addr_type(addr: IpAddr) -> &'static str {
+        match addr {
+            IpAddr::V4(_) => "IP4",
+            IpAddr::V6(_) => "IP6",
+        }
+    }
+
+    let payload_type = config.payload_type;
+    let stereo = u8::from(config.channels > 1);
+
+    let mut sdp = String::new();
+    // Writing to a string never fails.
+    let _ = write!(
+        sdp,
+        "v=0\r\n\
+         o=- 0 0 IN {origin_type} {origin}\r\n\
+         s=netsound\r\n\
+         c=IN {destination_type} {destination_ip}\r\n\
+         t=0 0\r\n\
+         m=audio {destination_port} RTP/AVP {payload_type}\r\n\
+         a=rtpmap:{payload_type} opus/{OPUS_CLOCK_RATE}/2\r\n\
+         a=fmtp:{payload_type} stereo={stereo}; sprop-stereo={stereo}\r\n\
+         a=sendonly\r\n",
+        origin_type = addr_type(origin),
+        destination_type = addr_type(destination.ip()),
+        destination_ip = destination.ip(),
+        destination_port = destination.port(),
+    );
+    sdp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let header = Header {
+            marker: true,
+            payload_type: DEFAULT_PAYLOAD_TYPE,
+            sequence: 0xBEEF,
+            timestamp: 0x0102_0304,
+            ssrc: 0xDEAD_BEEF,
+        };
+        let mut buf = [0_u8; HEADER_SIZE + 2];
+        header.write(&mut buf);
+        buf[HEADER_SIZE..].copy_from_slice(&[7, 8]);
+
+        let (parsed, payload) = parse(&buf).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload, &[7, 8]);
+    }
+
+    #[test]
+    fn csrcs_extension_and_padding() {
+        let mut buf = vec![0x80 | 0x20 | 0x10 | 1, 96, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        buf.extend_from_slice(&[0, 0, 0, 9]); // CSRC
+        buf.extend_from_slice(&[0xBE, 0xDE, 0, 1, 1, 2, 3, 4]); // Extension
+        buf.extend_from_slice(&[7, 8]); // Payload
+        buf.extend_from_slice(&[0, 0, 3]); // Padding
+
+        let (header, payload) = parse(&buf).unwrap();
+        assert_eq!(header.payload_type, 96);
+        assert_eq!(header.sequence, 1);
+        assert_eq!(payload, &[7, 8]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse(&[0x80, 0]), Err(Error::TooShort(2)));
+        assert_eq!(parse(&[0; HEADER_SIZE]), Err(Error::UnsupportedVersion(0)));
+        let mut buf = [0_u8; HEADER_SIZE + 1];
+        buf[0] = 0x80 | 0x20;
+        buf[HEADER_SIZE] = 100;
+        assert_eq!(parse(&buf), Err(Error::InvalidPadding));
+    }
+
+    #[test]
+    fn sequence_extension() {
+        let mut extender = SequenceExtender::default();
+        assert_eq!(extender.extend(65534), 65534);
+        assert_eq!(extender.extend(65535), 65535);
+        assert_eq!(extender.extend(1), 65537);
+        assert_eq!(extender.extend(0), 65536);
+        assert_eq!(extender.extend(2), 65538);
+    }
+
+    #[test]
+    fn sdp() {
+        let config = Config {
+            payload_type: 111,
+            channels: 2,
+        };
+        let sdp = opus_sdp(
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2:5004".parse().unwrap(),
+            &config,
+        );
+        assert!(sdp.contains("c=IN IP4 10.0.0.2\r\n"));
+        assert!(sdp.contains("m=audio 5004 RTP/AVP 111\r\n"));
+        assert!(sdp.contains("a=rtpmap:111 opus/48000/2\r\n"));
+        assert!(sdp.contains("a=fmtp:111 stereo=1; sprop-stereo=1\r\n"));
+    }
+}
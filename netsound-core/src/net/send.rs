@@ -1,17 +1,35 @@
-use crate::codec::{self, Encoder};
+use crate::codec::{self, Adaptation, Encoder};
 use crate::io::AsyncReadItems;
-use crate::log::{debug, error, trace, warn, KV};
+use crate::log::{debug, error, info, trace, warn, KV};
+use crate::metrics;
 use crate::pcm::Sample;
-use anyhow::format_err;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::hash::Hash;
 use std::net::SocketAddr;
+use std::time::{Instant, SystemTime};
 use std::{marker::PhantomData, sync::Arc};
-use tokio::net::UdpSocket;
 
-use super::SIZE;
+use super::crypto;
+use super::feedback;
+use super::handshake;
+use super::mtu;
+use super::packet;
+use super::rtp;
+use super::stream;
+use super::{Framing, PeerRegistry, Transport};
 
 mod multisend;
 
+use multisend::Backoff;
+
+/// Builds the encoders for the streams the sender switches to.
+#[allow(clippy::module_name_repetitions)]
+pub type SendEncoderFactory<'a, TEncoder> =
+    Box<dyn FnMut(&handshake::Params) -> Result<Box<TEncoder>, crate::Error> + Send + 'a>;
+
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug, Default, Clone, Serialize, Deserialize, KV)]
 pub struct SendStats {
@@ -21,11 +39,36 @@ pub struct SendStats {
     pub packets_sent: usize,
     pub bytes_sent: usize,
     pub bytes_sent_mismatches: usize,
+    pub encryption_errors: usize,
+    pub peers: usize,
+    pub peers_backing_off: usize,
+    pub send_errors: usize,
+    /// The bitrate the encoder targets, in bits per second, if it adapts
+    /// to the network conditions.
+    pub target_bitrate: usize,
+    pub adaptations: usize,
+    pub adaptations_failed: usize,
+    pub stream_configs_sent: usize,
+    pub encoder_switches: usize,
+    pub encoder_switches_failed: usize,
 }
 
+/// The stats of sending to a single peer.
 #[allow(clippy::module_name_repetitions)]
-#[derive(Debug)]
-pub struct SendService<'a, TCaptureSample, TCaptureDataReader, TEncoder>
+#[derive(Debug, Default, Clone, Serialize, Deserialize, KV)]
+pub struct SendPeerStats {
+    pub packets_sent: usize,
+    pub bytes_sent: usize,
+    pub bytes_sent_mismatches: usize,
+    pub send_errors: usize,
+    pub consecutive_send_errors: usize,
+    pub packets_skipped_while_backing_off: usize,
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct SendService<'a, TCaptureSample, TCaptureDataReader, TEncoder, TAddr = SocketAddr>
 where
     TCaptureSample: Sample,
     TCaptureDataReader: AsyncReadItems<TCaptureSample>,
@@ -33,59 +76,132 @@ where
 {
     pub capture_sample: PhantomData<TCaptureSample>,
     pub capture_data_reader: TCaptureDataReader,
-    pub encoder: &'a mut TEncoder,
+    pub encoder: Box<TEncoder>,
+    /// Builds the encoders for the codecs we're asked to switch to over
+    /// the `codec_switch`, if set.
+    #[derivative(Debug = "ignore")]
+    pub encoder_factory: Option<SendEncoderFactory<'a, TEncoder>>,
+    /// The requests to switch the codec, if we take any. Only the described
+    /// streams can switch, see [`stream`].
+    pub codec_switch: Option<stream::CodecSwitch>,
+    /// Encrypts the packets, if set.
+    pub cipher: Option<crypto::Sealer>,
+    pub framing: Framing,
+    /// The max size of the datagrams to send, see [`mtu`].
+    pub max_payload_size: usize,
     pub stats: SendStats,
+    /// The stats of every peer we're currently sending to.
+    pub peer_stats: HashMap<TAddr, SendPeerStats>,
+    /// Adapts the encoding to the reports of the receivers, if set.
+    pub bitrate_controller: Option<feedback::Controller<TAddr>>,
+    /// Describes the stream to the receivers, if set. Only the native
+    /// framing can carry the stream configs.
+    pub announcer: Option<stream::Announcer>,
+    /// Where to publish the stats for the metrics, if anywhere.
+    pub metrics: Option<metrics::Registry>,
 }
 
-impl<'a, TCaptureSample, TCaptureDataReader, TEncoder>
-    SendService<'a, TCaptureSample, TCaptureDataReader, TEncoder>
+impl<'a, TCaptureSample, TCaptureDataReader, TEncoder, TAddr>
+    SendService<'a, TCaptureSample, TCaptureDataReader, TEncoder, TAddr>
 where
     TCaptureSample: Sample,
     TCaptureDataReader: AsyncReadItems<TCaptureSample>,
     TEncoder: Encoder<TCaptureSample, TCaptureDataReader> + ?Sized,
+    TAddr: Clone + Eq + Hash + Debug,
 {
-    pub async fn send_loop(
+    pub async fn send_loop<T: Transport<Addr = TAddr> + ?Sized>(
         &mut self,
-        socket: Arc<UdpSocket>,
-        peer_addrs: Vec<SocketAddr>,
+        socket: Arc<T>,
+        peers: PeerRegistry<TAddr>,
     ) -> Result<futures::never::Never, crate::Error> {
-        let mut send_buf = [0_u8; SIZE];
+        let mut backoffs: HashMap<TAddr, Backoff> = HashMap::new();
+        let mut send_buf = vec![0_u8; self.max_payload_size];
+        let payload_end = packet::HEADER_SIZE
+            + mtu::audio_payload_size(self.max_payload_size, self.cipher.is_some())?;
+        let mut header = HeaderWriter::new(self.framing);
+        let mut publishing = metrics::Throttle::default();
+        self.apply_current_adaptation();
         loop {
             trace!("Send loop begin");
+            self.carry_out_codec_switch();
 
             trace!("Send: before encode");
             match self
                 .encoder
-                .encode(&mut self.capture_data_reader, &mut send_buf)
+                .encode(
+                    &mut self.capture_data_reader,
+                    &mut send_buf[packet::HEADER_SIZE..payload_end],
+                )
                 .await
             {
-                Ok(bytes_to_send) => {
-                    trace!("Send: after encode, bytes to send: {}", bytes_to_send);
+                Ok(encoded) => {
+                    trace!("Send: after encode, bytes encoded: {}", encoded.bytes);
                     self.stats.frames_encoded += 1;
-                    self.stats.bytes_encoded += bytes_to_send;
+                    self.stats.bytes_encoded += encoded.bytes;
+
+                    let stream = self.announcer.as_ref().map_or(0, stream::Announcer::id);
+                    header.write(&mut send_buf, encoded.samples, stream);
+                    let mut bytes_to_send = packet::HEADER_SIZE + encoded.bytes;
+
+                    if let Some(cipher) = &mut self.cipher {
+                        match cipher.seal(&mut send_buf, bytes_to_send, SystemTime::now()) {
+                            Ok(sealed) => bytes_to_send = sealed,
+                            Err(err) => {
+                                error!("Send: encryption failed: {}", err);
+                                self.stats.encryption_errors += 1;
+                                continue;
+                            }
+                        }
+                    }
+
+                    let now = Instant::now();
+                    let peer_addrs = peers.current(now);
+                    self.forget_gone_peers(&peer_addrs, &mut backoffs);
+                    self.adapt(&peer_addrs, now);
+
+                    let ready_addrs: Vec<&TAddr> = peer_addrs
+                        .iter()
+                        .filter(|addr| {
+                            let ready = backoffs
+                                .get(*addr)
+                                .map_or(true, |backoff| backoff.is_ready(now));
+                            if !ready {
+                                self.peer_stats
+                                    .entry((*addr).clone())
+                                    .or_default()
+                                    .packets_skipped_while_backing_off += 1;
+                            }
+                            ready
+                        })
+                        .collect();
+                    self.stats.peers_backing_off = peer_addrs.len() - ready_addrs.len();
+                    if ready_addrs.is_empty() {
+                        trace!("Send: no peers to send to");
+                        if publishing.due(now) {
+                            self.publish_stats();
+                        }
+                        continue;
+                    }
+
+                    self.announce(socket.as_ref(), &ready_addrs, now).await;
 
                     trace!("Send: before send_to");
-                    let bytes_sent = multisend::ensure_same_sizes(
-                        multisend::multisend(
-                            socket.as_ref(),
-                            &send_buf[..bytes_to_send],
-                            peer_addrs.iter(),
-                        )
-                        .await?,
+                    let results = multisend::multisend(
+                        socket.as_ref(),
+                        &send_buf[..bytes_to_send],
+                        ready_addrs,
                     )
-                    .ok_or_else(|| format_err!("multisend has various results"))?;
+                    .await;
                     trace!("Send: after send_to");
-                    self.stats.packets_sent += 1;
-                    self.stats.bytes_sent += bytes_sent;
-
-                    if bytes_sent != bytes_to_send {
-                        warn!(
-                            "Send: sent {} bytes while expecting to send {} bytes ({} buffer size)",
-                            bytes_sent,
-                            bytes_to_send,
-                            send_buf.len(),
-                        );
-                        self.stats.bytes_sent_mismatches += 1;
+
+                    let mut sent = false;
+                    for (addr, result) in results {
+                        let backoff = backoffs.entry(addr.clone()).or_default();
+                        sent |= self.record_send(addr, result, bytes_to_send, backoff, now);
+                    }
+                    if sent {
+                        self.stats.packets_sent += 1;
+                        self.stats.bytes_sent += bytes_to_send;
                     }
 
                     trace!("Send: sent a non-empty packet");
@@ -99,6 +215,257 @@ where
                 }
             };
             debug!("network send"; &self.stats);
+            if publishing.due(Instant::now()) {
+                self.publish_stats();
+            }
+        }
+    }
+
+    /// Replace the encoder with the one for the stream with the `params`,
+    /// and announce the new stream, so that the receivers switch their
+    /// decoders along. The current adaptation, if any, carries over.
+    pub fn switch_encoder(&mut self, encoder: Box<TEncoder>, params: handshake::Params) {
+        info!("Send: switching to {}", params);
+        self.encoder = encoder;
+        if let Some(announcer) = &mut self.announcer {
+            announcer.switch(params);
+        }
+        self.stats.encoder_switches += 1;
+        self.apply_current_adaptation();
+    }
+
+    /// Switch to the codec we're asked to over the `codec_switch`, if any.
+    /// If the encoder can't be built, we keep the current one.
+    fn carry_out_codec_switch(&mut self) {
+        let (Some(codec_switch), Some(announcer)) = (&self.codec_switch, &self.announcer) else {
+            return;
+        };
+        let Some(codec) = codec_switch.take() else {
+            return;
+        };
+        let params = handshake::Params {
+            codec,
+            ..announcer.params().clone()
+        };
+        let Some(encoder_factory) = &mut self.encoder_factory else {
+            return;
+        };
+        match encoder_factory(&params) {
+            Ok(encoder) => self.switch_encoder(encoder, params),
+            Err(err) => {
+                warn!(
+                    "Send: failed to switch to {}, keeping the current codec: {}",
+                    params, err
+                );
+                self.stats.encoder_switches_failed += 1;
+            }
+        }
+    }
+
+    /// Send the config of the stream to the `peer_addrs`, if it's due.
+    async fn announce<T: Transport<Addr = TAddr> + ?Sized>(
+        &mut self,
+        socket: &T,
+        peer_addrs: &[&TAddr],
+        now: Instant,
+    ) {
+        let Some(announcer) = &mut self.announcer else {
+            return;
+        };
+        if !announcer.due(now) {
+            return;
+        }
+        let packet = match announcer.packet(self.cipher.as_mut()) {
+            Ok(packet) => packet,
+            Err(err) => {
+                error!("Send: encryption failed: {}", err);
+                self.stats.encryption_errors += 1;
+                return;
+            }
+        };
+        trace!("Send: announcing stream {}", announcer.id());
+        for addr in peer_addrs {
+            match socket.send_to(&packet, addr).await {
+                Ok(_) => self.stats.stream_configs_sent += 1,
+                Err(err) => warn!("Send: failed to announce the stream to {:?}: {}", addr, err),
+            }
+        }
+    }
+
+    /// Drop the state of the peers that are no longer in the registry.
+    fn forget_gone_peers(&mut self, peer_addrs: &[TAddr], backoffs: &mut HashMap<TAddr, Backoff>) {
+        self.stats.peers = peer_addrs.len();
+        self.peer_stats.retain(|addr, _| peer_addrs.contains(addr));
+        backoffs.retain(|addr, _| peer_addrs.contains(addr));
+    }
+
+    /// Revise the encoding according to the reports of the `peer_addrs`.
+    fn adapt(&mut self, peer_addrs: &[TAddr], now: Instant) {
+        let Some(controller) = &mut self.bitrate_controller else {
+            return;
+        };
+        if let Some(adaptation) = controller.update(peer_addrs, now) {
+            self.apply_adaptation(adaptation);
+        }
+    }
+
+    /// Apply the current adaptation, if we adapt, to the encoder.
+    fn apply_current_adaptation(&mut self) {
+        if let Some(controller) = &self.bitrate_controller {
+            let adaptation = controller.current();
+            self.apply_adaptation(adaptation);
+        }
+    }
+
+    /// Apply the `adaptation` to the encoder. If the encoder rejects it,
+    /// it keeps encoding with the current settings.
+    fn apply_adaptation(&mut self, adaptation: Adaptation) {
+        match self.encoder.adapt(&adaptation) {
+            Ok(true) => {
+                info!("Send: adapted the encoding: {:?}", adaptation);
+                self.stats.target_bitrate =
+                    usize::try_from(adaptation.bitrate).unwrap_or(usize::MAX);
+                self.stats.adaptations += 1;
+            }
+            Ok(false) => {
+                warn!("Send: the codec can't adapt to the network conditions, not trying anymore");
+                self.bitrate_controller = None;
+            }
+            Err(err) => {
+                warn!(
+                    "Send: failed to adapt the encoding to {:?}, keeping the current one: {}",
+                    adaptation, err
+                );
+                self.stats.adaptations_failed += 1;
+            }
+        }
+    }
+
+    /// Record the result of sending the packet to the peer, and return
+    /// whether it was sent.
+    fn record_send(
+        &mut self,
+        addr: &TAddr,
+        result: std::io::Result<usize>,
+        bytes_to_send: usize,
+        backoff: &mut Backoff,
+        now: Instant,
+    ) -> bool {
+        let peer_stats = self.peer_stats.entry(addr.clone()).or_default();
+        let sent = match result {
+            Ok(bytes_sent) => {
+                backoff.succeeded();
+                peer_stats.packets_sent += 1;
+                peer_stats.bytes_sent += bytes_sent;
+                if bytes_sent != bytes_to_send {
+                    warn!(
+                        "Send: sent {} bytes to {:?} while expecting to send {} bytes",
+                        bytes_sent, addr, bytes_to_send,
+                    );
+                    peer_stats.bytes_sent_mismatches += 1;
+                    self.stats.bytes_sent_mismatches += 1;
+                }
+                true
+            }
+            Err(err) => {
+                let delay = backoff.failed(now);
+                warn!(
+                    "Send: failed to send to {:?}: {}, backing off for {:?}",
+                    addr, err, delay
+                );
+                peer_stats.send_errors += 1;
+                self.stats.send_errors += 1;
+                false
+            }
+        };
+        peer_stats.consecutive_send_errors = backoff.failures() as usize;
+        sent
+    }
+
+    fn publish_stats(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_stats("send", &self.stats);
+            metrics.set_peer_stats("send_peer", &self.peer_stats);
+        }
+    }
+
+    /// Log the final stats, and publish them for the metrics. Meant to be
+    /// called once the send loop is stopped.
+    pub fn flush_stats(&self) {
+        info!("network send totals"; &self.stats);
+        for (addr, peer_stats) in &self.peer_stats {
+            info!("network send peer totals"; "peer" => ?addr, peer_stats);
+        }
+        self.publish_stats();
+    }
+}
+
+// The headers of both framings take the same room.
+#[allow(clippy::assertions_on_constants)]
+const _: () = assert!(packet::HEADER_SIZE == rtp::HEADER_SIZE);
+
+/// Writes the headers of the consecutive packets.
+#[derive(Debug)]
+pub(super) struct HeaderWriter {
+    framing: Framing,
+    sequence: u32,
+    timestamp: u32,
+    ssrc: u32,
+    first: bool,
+}
+
+impl HeaderWriter {
+    pub(super) fn new(framing: Framing) -> Self {
+        match framing {
+            Framing::Native => Self {
+                framing,
+                sequence: 0,
+                timestamp: 0,
+                ssrc: 0,
+                first: true,
+            },
+            // RTP wants the initial values to be random.
+            Framing::Rtp(_) => Self {
+                framing,
+                sequence: rand::random(),
+                timestamp: rand::random(),
+                ssrc: rand::random(),
+                first: true,
+            },
         }
     }
+
+    /// Write the header of the packet carrying the given amount of
+    /// interleaved `samples` of the `stream`, see [`stream`]. The RTP
+    /// headers have no room for the stream.
+    pub(super) fn write(&mut self, buf: &mut [u8], samples: usize, stream: u16) {
+        // The sequence and the timestamp wrap around by design.
+        #[allow(clippy::cast_possible_truncation)]
+        let ticks = match self.framing {
+            Framing::Native => {
+                packet::Header {
+                    payload_type: packet::PayloadType::Audio,
+                    stream,
+                    sequence: self.sequence,
+                    timestamp: self.timestamp,
+                }
+                .write(buf);
+                samples as u32
+            }
+            Framing::Rtp(config) => {
+                rtp::Header {
+                    marker: self.first,
+                    payload_type: config.payload_type,
+                    sequence: self.sequence as u16,
+                    timestamp: self.timestamp,
+                    ssrc: self.ssrc,
+                }
+                .write(buf);
+                (samples / config.channels.max(1)) as u32
+            }
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(ticks);
+        self.first = false;
+    }
 }
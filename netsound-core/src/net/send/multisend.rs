@@ -1,54 +1,117 @@
-use std::borrow::Borrow;
-use std::net::SocketAddr;
-use tokio::net::UdpSocket;
+use std::cmp::min;
+use std::io;
+use std::time::{Duration, Instant};
 
-pub async fn multisend<'a, I>(
-    socket: &UdpSocket,
+use super::super::Transport;
+
+/// The delay before retrying a peer after the first failure. Doubles with
+/// every consecutive failure.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest we back off from a failing peer for.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Send the `buf` to all the `peer_addrs` at once, and return the result of
+/// sending to each of them.
+pub async fn multisend<'a, T, I>(
+    socket: &T,
     buf: &[u8],
     peer_addrs: I,
-) -> std::io::Result<Vec<usize>>
+) -> Vec<(&'a T::Addr, io::Result<usize>)>
 where
-    I: IntoIterator<Item = &'a SocketAddr>,
+    T: Transport + ?Sized,
+    I: IntoIterator<Item = &'a T::Addr>,
 {
-    let peer_addrs_iter = peer_addrs.into_iter();
-    let mut sizes = {
-        let (lower, upper) = peer_addrs_iter.size_hint();
-        Vec::with_capacity(match upper {
-            Some(upper) => upper,
-            None => lower,
-        })
-    };
-
-    for peer_addr in peer_addrs_iter {
-        let size = socket.send_to(buf, peer_addr).await?;
-        sizes.push(size);
-    }
+    futures::future::join_all(
+        peer_addrs
+            .into_iter()
+            .map(|peer_addr| async move { (peer_addr, socket.send_to(buf, peer_addr).await) }),
+    )
+    .await
+}
 
-    Ok(sizes)
+/// Keeps us from trying to send to a failing peer for a while, backing off
+/// exponentially while the failures continue.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
 }
 
-pub fn ensure_same_sizes<I, S>(sizes: I) -> Option<usize>
-where
-    S: Borrow<usize>,
-    I: IntoIterator<Item = S>,
-{
-    let mut iter = sizes.into_iter();
-    let size = iter.next()?;
-    let size = *size.borrow();
-    if iter.all(|e| *e.borrow() == size) {
-        return Some(size);
+impl Backoff {
+    /// Whether we can try sending at `now`.
+    pub fn is_ready(&self, now: Instant) -> bool {
+        self.retry_at.map_or(true, |retry_at| now >= retry_at)
+    }
+
+    /// Record the successful send.
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Record the failed send, and return how long we back off for.
+    pub fn failed(&mut self, now: Instant) -> Duration {
+        let delay = min(
+            MIN_BACKOFF.saturating_mul(1 << min(self.failures, 16)),
+            MAX_BACKOFF,
+        );
+        self.failures = self.failures.saturating_add(1);
+        self.retry_at = Some(now + delay);
+        delay
+    }
+
+    /// The amount of the consecutive failures.
+    pub fn failures(&self) -> u32 {
+        self.failures
     }
-    None
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ensure_same_sizes;
+    use super::*;
+    use crate::net::transport::memory;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::default();
+        let now = Instant::now();
+        assert!(backoff.is_ready(now));
+
+        assert_eq!(backoff.failed(now), MIN_BACKOFF);
+        assert!(!backoff.is_ready(now));
+        assert!(backoff.is_ready(now + MIN_BACKOFF));
+        assert_eq!(backoff.failed(now), MIN_BACKOFF * 2);
+        for _ in 0..40 {
+            backoff.failed(now);
+        }
+        assert_eq!(backoff.failed(now), MAX_BACKOFF);
+        assert_eq!(backoff.failures(), 43);
+
+        backoff.succeeded();
+        assert!(backoff.is_ready(now));
+        assert_eq!(backoff.failures(), 0);
+    }
 
     #[test]
-    fn ensure_same_sizes_test() {
-        assert_eq!(ensure_same_sizes([1, 1, 1]), Some(1));
-        assert_eq!(ensure_same_sizes([] as [usize; 0]), None);
-        assert_eq!(ensure_same_sizes([1, 2]), None);
+    fn sends_to_all() {
+        futures::executor::block_on(async {
+            let network = memory::Network::new();
+            let sender = network.bind(memory::Addr(0)).unwrap();
+            let first = network.bind(memory::Addr(1)).unwrap();
+            let second = network.bind(memory::Addr(2)).unwrap();
+
+            let peers = [first.local_addr(), second.local_addr()];
+            let results = multisend(&sender, &[1, 2, 3], &peers).await;
+            assert_eq!(results.len(), 2);
+            assert!(results
+                .iter()
+                .all(|(_, result)| *result.as_ref().unwrap() == 3));
+
+            let mut buf = [0_u8; 3];
+            for receiver in [&first, &second] {
+                assert_eq!(receiver.recv_from(&mut buf).await.unwrap().0, 3);
+            }
+        });
     }
 }
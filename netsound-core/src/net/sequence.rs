@@ -0,0 +1,153 @@
+//! Tracking of the sequence numbers of the incoming packets.
+
+/// How many of the most recent sequence numbers we remember, for detecting
+/// duplicates.
+const WINDOW: u32 = 64;
+
+/// How far ahead the sequence number is allowed to jump before we consider
+/// the stream broken.
+const MAX_DROPOUT: u32 = 3000;
+
+/// How far behind the sequence number is allowed to be before we consider
+/// the stream broken.
+const MAX_MISORDER: u32 = 100;
+
+/// The classification of the packet arrival.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// The packet is newer than any packet seen before, and `lost` packets
+    /// were skipped in between.
+    InOrder { lost: u32 },
+    /// The packet is older than the newest packet seen, but we haven't seen
+    /// it before.
+    Reordered,
+    /// The packet is too far behind the newest packet seen to tell whether
+    /// we've seen it before.
+    TooOld,
+    /// The packet was seen before.
+    Duplicate,
+    /// The sequence number is way off of what we expected. Such a packet
+    /// should be dropped, but if the next packet continues from it, we
+    /// resynchronize to the new sequence.
+    OutOfRange,
+    /// The sequence was restarted (i.e. the sender has restarted).
+    Resync,
+}
+
+/// Tracks the sequence numbers of the incoming packets to detect loss,
+/// reordering and duplicates.
+///
+/// Loosely follows the algorithm from the RFC 3550, appendix A.1.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    highest: Option<u32>,
+    /// Bit `n` is set if the packet `highest - n` was seen.
+    seen: u64,
+    /// The sequence number that would confirm the resync.
+    bad_seq: Option<u32>,
+}
+
+impl SequenceTracker {
+    /// Record the packet arrival.
+    pub fn track(&mut self, seq: u32) -> Arrival {
+        let highest = match self.highest {
+            None => {
+                self.restart(seq);
+                return Arrival::InOrder { lost: 0 };
+            }
+            Some(highest) => highest,
+        };
+
+        let ahead = seq.wrapping_sub(highest);
+        if ahead == 0 {
+            return Arrival::Duplicate;
+        }
+        if ahead < MAX_DROPOUT {
+            self.seen = if ahead < WINDOW {
+                self.seen << ahead
+            } else {
+                0
+            };
+            self.seen |= 1;
+            self.highest = Some(seq);
+            self.bad_seq = None;
+            return Arrival::InOrder { lost: ahead - 1 };
+        }
+
+        let behind = highest.wrapping_sub(seq);
+        if behind <= MAX_MISORDER {
+            if behind >= WINDOW {
+                return Arrival::TooOld;
+            }
+            let bit = 1 << behind;
+            if self.seen & bit != 0 {
+                return Arrival::Duplicate;
+            }
+            self.seen |= bit;
+            return Arrival::Reordered;
+        }
+
+        if self.bad_seq == Some(seq) {
+            self.restart(seq);
+            return Arrival::Resync;
+        }
+        self.bad_seq = Some(seq.wrapping_add(1));
+        Arrival::OutOfRange
+    }
+
+    fn restart(&mut self, seq: u32) {
+        self.highest = Some(seq);
+        self.seen = 1;
+        self.bad_seq = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order_and_loss() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.track(10), Arrival::InOrder { lost: 0 });
+        assert_eq!(tracker.track(11), Arrival::InOrder { lost: 0 });
+        assert_eq!(tracker.track(14), Arrival::InOrder { lost: 2 });
+    }
+
+    #[test]
+    fn reorder_and_duplicates() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.track(1), Arrival::InOrder { lost: 0 });
+        assert_eq!(tracker.track(3), Arrival::InOrder { lost: 1 });
+        assert_eq!(tracker.track(2), Arrival::Reordered);
+        assert_eq!(tracker.track(2), Arrival::Duplicate);
+        assert_eq!(tracker.track(3), Arrival::Duplicate);
+    }
+
+    #[test]
+    fn too_old() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.track(0), Arrival::InOrder { lost: 0 });
+        assert_eq!(tracker.track(WINDOW + 1), Arrival::InOrder { lost: WINDOW });
+        assert_eq!(tracker.track(1), Arrival::TooOld);
+        assert_eq!(tracker.track(2), Arrival::Reordered);
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.track(u32::MAX), Arrival::InOrder { lost: 0 });
+        assert_eq!(tracker.track(1), Arrival::InOrder { lost: 1 });
+        assert_eq!(tracker.track(0), Arrival::Reordered);
+    }
+
+    #[test]
+    fn resync() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.track(50_000), Arrival::InOrder { lost: 0 });
+        assert_eq!(tracker.track(0), Arrival::OutOfRange);
+        assert_eq!(tracker.track(1), Arrival::Resync);
+        assert_eq!(tracker.track(2), Arrival::InOrder { lost: 0 });
+    }
+}
@@ -0,0 +1,252 @@
+//! The stream configs, which make the audio packets self-describing.
+//!
+//! The sender gives every codec and stream params it encodes with an id,
+//! and stamps the id in the [`packet::Header::stream`] of every audio packet.
+//! The params behind the id are announced in the packets with
+//! the [`packet::PayloadType::StreamConfig`] payload type, carrying the id in
+//! the header too:
+//!
+//! ```text
+//! stream config = params
+//! ```
+//!
+//! See [`super::handshake`] for the encoding of the params.
+//!
+//! The config is announced as soon as the stream starts, and then
+//! periodically, so that the receivers that missed it, or joined later, can
+//! pick it up. Once the sender switches to another codec or params, it takes
+//! the next id, and the receivers switch their decoders as the audio with
+//! the new id comes up for playout.
+//!
+//! The id 0 is reserved for the streams that aren't described, like
+//! the RTP ones. The receivers decode them with the params they know from
+//! elsewhere, like the handshake.
+//!
+//! The sender switches the codecs as asked to over the [`CodecSwitch`],
+//! see [`crate::control`].
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+use super::crypto;
+use super::handshake::Params;
+use super::packet;
+
+/// How often we repeat the config of the current stream.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many of the latest configs of a sender we remember. The audio of
+/// the previous streams may still be in the jitter buffer when the config
+/// of the next one arrives.
+const REMEMBERED_CONFIGS: usize = 4;
+
+/// Tracks the stream the sender encodes, and tells when to announce it.
+#[derive(Debug)]
+pub struct Announcer {
+    id: u16,
+    params: Params,
+    next_announcement: Option<Instant>,
+}
+
+impl Announcer {
+    /// Create a new [`Announcer`] for the stream encoded with the `params`.
+    /// The ids start at random, so that the receivers don't mistake the
+    /// streams of a restarted sender for the ones they know.
+    #[must_use]
+    pub fn new(params: Params) -> Self {
+        Self {
+            id: rand::random::<u16>().max(1),
+            params,
+            next_announcement: None,
+        }
+    }
+
+    /// Move on to the stream encoded with the `params`. Meant to be called
+    /// along with replacing the encoder, and announced right away.
+    pub fn switch(&mut self, params: Params) {
+        self.id = match self.id.wrapping_add(1) {
+            0 => 1,
+            id => id,
+        };
+        self.params = params;
+        self.next_announcement = None;
+    }
+
+    /// The id of the current stream.
+    #[must_use]
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// The params of the current stream.
+    #[must_use]
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Whether the config is due to be announced at `now`. Once it is,
+    /// the next announcement is scheduled.
+    pub fn due(&mut self, now: Instant) -> bool {
+        if self.next_announcement.map_or(false, |at| now < at) {
+            return false;
+        }
+        self.next_announcement = Some(now + ANNOUNCE_INTERVAL);
+        true
+    }
+
+    /// Build the packet announcing the config of the current stream,
+    /// encrypted if the `cipher` is set.
+    pub fn packet(&self, cipher: Option<&mut crypto::Sealer>) -> Result<Vec<u8>, crypto::Error> {
+        let mut buf = vec![0; packet::HEADER_SIZE];
+        packet::Header {
+            payload_type: packet::PayloadType::StreamConfig,
+            stream: self.id,
+            sequence: 0,
+            timestamp: 0,
+        }
+        .write(&mut buf);
+        self.params.write(&mut buf);
+
+        if let Some(cipher) = cipher {
+            let len = buf.len();
+            buf.resize(len + crypto::OVERHEAD, 0);
+            let sealed = cipher.seal(&mut buf, len, SystemTime::now())?;
+            buf.truncate(sealed);
+        }
+        Ok(buf)
+    }
+}
+
+/// The codec isn't one we can switch to.
+#[derive(Error, Debug)]
+#[error("no such codec: {0:?}")]
+pub struct UnknownCodec(pub String);
+
+/// The shared request to switch the stream to another codec, made by
+/// the control interface and carried out by the send loop.
+#[derive(Debug, Clone)]
+pub struct CodecSwitch {
+    codecs: Arc<[String]>,
+    requested: Arc<Mutex<Option<String>>>,
+}
+
+impl CodecSwitch {
+    /// Create a new [`CodecSwitch`] to any of the `codecs`.
+    #[must_use]
+    pub fn new(codecs: Vec<String>) -> Self {
+        Self {
+            codecs: codecs.into(),
+            requested: Arc::default(),
+        }
+    }
+
+    /// Ask to switch to the `codec`, replacing the request not carried out
+    /// yet, if any.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `codec` isn't one of the ones we can switch to.
+    pub fn request(&self, codec: &str) -> Result<(), UnknownCodec> {
+        if !self.codecs.iter().any(|known| known == codec) {
+            return Err(UnknownCodec(codec.to_owned()));
+        }
+        *self.lock() = Some(codec.to_owned());
+        Ok(())
+    }
+
+    /// Take the codec we're asked to switch to, if any.
+    #[must_use]
+    pub fn take(&self) -> Option<String> {
+        self.lock().take()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<String>> {
+        self.requested
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The latest stream configs announced by a sender.
+#[derive(Debug, Default)]
+pub(super) struct Announced(VecDeque<(u16, Params)>);
+
+impl Announced {
+    /// Remember the config, and tell whether it's new to us.
+    pub fn insert(&mut self, id: u16, params: Params) -> bool {
+        if let Some(position) = self.0.iter().position(|(known, _)| *known == id) {
+            let (_, known_params) = &self.0[position];
+            if *known_params == params {
+                return false;
+            }
+            self.0.remove(position);
+        }
+        if self.0.len() == REMEMBERED_CONFIGS {
+            self.0.pop_front();
+        }
+        self.0.push_back((id, params));
+        true
+    }
+
+    /// The params of the stream with the `id`, if it was announced.
+    pub fn get(&self, id: u16) -> Option<&Params> {
+        self.0
+            .iter()
+            .find(|(known, _)| *known == id)
+            .map(|(_, params)| params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(codec: &str) -> Params {
+        Params {
+            codec: codec.to_owned(),
+            sample_rate: 48000,
+            channels: 2,
+        }
+    }
+
+    #[test]
+    fn announcement() {
+        let mut announcer = Announcer::new(params("opus"));
+        let first = announcer.id();
+        assert_ne!(first, 0);
+
+        let now = Instant::now();
+        assert!(announcer.due(now));
+        assert!(!announcer.due(now + ANNOUNCE_INTERVAL / 2));
+        assert!(announcer.due(now + ANNOUNCE_INTERVAL));
+
+        announcer.switch(params("adpcm"));
+        assert_ne!(announcer.id(), first);
+        assert_ne!(announcer.id(), 0);
+        assert!(announcer.due(now + ANNOUNCE_INTERVAL));
+
+        let packet = announcer.packet(None).unwrap();
+        let (header, payload) = packet::parse(&packet).unwrap();
+        assert_eq!(header.payload_type, packet::PayloadType::StreamConfig);
+        assert_eq!(header.stream, announcer.id());
+        assert_eq!(Params::read(payload), Ok(params("adpcm")));
+    }
+
+    #[test]
+    fn remembers_the_latest() {
+        let mut announced = Announced::default();
+        assert!(announced.insert(1, params("opus")));
+        assert!(!announced.insert(1, params("opus")));
+        assert!(announced.insert(1, params("raw")));
+        assert_eq!(announced.get(1), Some(&params("raw")));
+
+        for id in 2..=5 {
+            assert!(announced.insert(id, params("adpcm")));
+        }
+        assert_eq!(announced.get(1), None);
+        assert_eq!(announced.get(5), Some(&params("adpcm")));
+        assert_eq!(announced.get(0), None);
+    }
+}
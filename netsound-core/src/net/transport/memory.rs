@@ -0,0 +1,159 @@
+//! An in-memory transport, for running the net services within a single
+//! process without binding the real ports.
+//!
+//! Like with UDP, the datagrams sent to an address nobody is bound to, or to
+//! an endpoint that has too many datagrams queued, are silently dropped.
+
+use async_trait::async_trait;
+use std::collections::{hash_map::Entry, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::mpsc;
+
+use super::Transport;
+
+/// How many datagrams can be queued at an endpoint before we start dropping
+/// them.
+const QUEUE_SIZE: usize = 1024;
+
+type Datagram = (Vec<u8>, Addr);
+
+/// The address of an in-memory endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Addr(pub u32);
+
+/// The in-memory network the endpoints are bound to.
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    endpoints: Arc<Mutex<HashMap<Addr, mpsc::Sender<Datagram>>>>,
+}
+
+impl Network {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a new endpoint to the `addr`.
+    pub fn bind(&self, addr: Addr) -> io::Result<Endpoint> {
+        let mut endpoints = self.lock();
+        let Entry::Vacant(entry) = endpoints.entry(addr) else {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{addr:?} is already bound"),
+            ));
+        };
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        entry.insert(sender);
+        drop(endpoints);
+        Ok(self.endpoint(addr, receiver))
+    }
+
+    /// Bind the `addr` without checking whether it's taken.
+    fn register(&self, addr: Addr) -> Endpoint {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        self.lock().insert(addr, sender);
+        self.endpoint(addr, receiver)
+    }
+
+    fn endpoint(&self, addr: Addr, receiver: mpsc::Receiver<Datagram>) -> Endpoint {
+        Endpoint {
+            addr,
+            network: self.clone(),
+            inbox: tokio::sync::Mutex::new(receiver),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Addr, mpsc::Sender<Datagram>>> {
+        self.endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Two endpoints on a network of their own, at `Addr(0)` and `Addr(1)`.
+#[must_use]
+pub fn pair() -> (Endpoint, Endpoint) {
+    let network = Network::new();
+    (network.register(Addr(0)), network.register(Addr(1)))
+}
+
+/// An endpoint bound to the in-memory network.
+#[derive(Debug)]
+pub struct Endpoint {
+    addr: Addr,
+    network: Network,
+    inbox: tokio::sync::Mutex<mpsc::Receiver<Datagram>>,
+}
+
+impl Endpoint {
+    #[must_use]
+    pub fn local_addr(&self) -> Addr {
+        self.addr
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.network.lock().remove(&self.addr);
+    }
+}
+
+#[async_trait]
+impl Transport for Endpoint {
+    type Addr = Addr;
+
+    async fn send_to(&self, buf: &[u8], target: &Self::Addr) -> io::Result<usize> {
+        let sender = self.network.lock().get(target).cloned();
+        if let Some(sender) = sender {
+            // A full queue means the datagram is lost.
+            let _ = sender.try_send((buf.to_vec(), self.addr));
+        }
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Self::Addr)> {
+        let (datagram, from) = self
+            .inbox
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        // Like with UDP, the excess is discarded.
+        let len = std::cmp::min(buf.len(), datagram.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok((len, from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_exchange() {
+        futures::executor::block_on(async {
+            let (first, second) = pair();
+            first
+                .send_to(&[1, 2, 3], &second.local_addr())
+                .await
+                .unwrap();
+
+            let mut buf = [0_u8; 2];
+            let (len, from) = second.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 2);
+            assert_eq!(buf, [1, 2]);
+            assert_eq!(from, first.local_addr());
+        });
+    }
+
+    #[test]
+    fn bind_and_drop() {
+        let network = Network::new();
+        let endpoint = network.bind(Addr(7)).unwrap();
+        assert!(network.bind(Addr(7)).is_err());
+        drop(endpoint);
+        assert!(network.bind(Addr(7)).is_ok());
+    }
+}
@@ -0,0 +1,29 @@
+//! The datagram transports the net services run over.
+
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io;
+
+pub mod memory;
+mod udp;
+#[cfg(unix)]
+mod unix;
+
+/// A datagram transport: an unreliable, message-oriented channel between
+/// the addressed endpoints, like UDP.
+///
+/// The transport is shared between the send and the receive loops, so both
+/// operations take `&self`.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// The address of an endpoint.
+    type Addr: Clone + Eq + Hash + Debug + Send + Sync + 'static;
+
+    /// Send the datagram to the `target`, returning the amount of bytes sent.
+    async fn send_to(&self, buf: &[u8], target: &Self::Addr) -> io::Result<usize>;
+
+    /// Receive a datagram into the `buf`, returning the amount of bytes
+    /// received and the address of the sender.
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Self::Addr)>;
+}
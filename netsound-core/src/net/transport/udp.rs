@@ -0,0 +1,19 @@
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+use super::Transport;
+
+#[async_trait]
+impl Transport for UdpSocket {
+    type Addr = SocketAddr;
+
+    async fn send_to(&self, buf: &[u8], target: &Self::Addr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Self::Addr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+}
@@ -0,0 +1,50 @@
+use async_trait::async_trait;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::net::UnixDatagram;
+
+use super::Transport;
+
+/// The datagrams from the unbound sockets come from an empty path, and can't
+/// be replied to.
+#[async_trait]
+impl Transport for UnixDatagram {
+    type Addr = PathBuf;
+
+    async fn send_to(&self, buf: &[u8], target: &Self::Addr) -> io::Result<usize> {
+        UnixDatagram::send_to(self, buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Self::Addr)> {
+        let (len, addr) = UnixDatagram::recv_from(self, buf).await?;
+        let path = addr
+            .as_pathname()
+            .map_or_else(PathBuf::new, Path::to_path_buf);
+        Ok((len, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn exchange() {
+        let dir = std::env::temp_dir().join(format!("netsound-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first_path = dir.join("first");
+        let second_path = dir.join("second");
+        let first = UnixDatagram::bind(&first_path).unwrap();
+        let second = UnixDatagram::bind(&second_path).unwrap();
+
+        Transport::send_to(&first, &[1, 2, 3], &second_path)
+            .await
+            .unwrap();
+        let mut buf = [0_u8; 8];
+        let (len, from) = Transport::recv_from(&second, &mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        assert_eq!(from, first_path);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+//! The shared trigger for stopping the service loops.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Tells the service loops to stop.
+///
+/// The clones share the state, so that whoever triggers the shutdown, be it
+/// a signal handler or the control interface, stops everyone waiting on it.
+/// Once triggered, it stays triggered.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Shutdown {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tell everyone waiting to stop.
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    #[must_use]
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Wait until the shutdown is triggered. Returns right away if it
+    /// already is.
+    pub async fn triggered(&self) {
+        let mut rx = self.tx.subscribe();
+        // The sender lives as long as we do, so this never fails.
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn wakes_up_the_waiters() {
+        let shutdown = Shutdown::new();
+        let waiter = shutdown.clone();
+        assert!(!waiter.is_triggered());
+        assert!(waiter.triggered().now_or_never().is_none());
+
+        let waiting = tokio::spawn(async move { waiter.triggered().await });
+        shutdown.trigger();
+        waiting.await.unwrap();
+
+        assert!(shutdown.is_triggered());
+        assert!(shutdown.triggered().now_or_never().is_some());
+    }
+}
@@ -0,0 +1,27 @@
+//! The bits shared by the TCP servers, like the control interface and
+//! the metrics.
+
+use crate::log::warn;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long to wait before accepting again after a failure.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accept the next connection to the `listener`, named `server` in the logs.
+///
+/// The failures to accept are logged and retried, as things like running
+/// out of file descriptors are temporary. We back off a little, so as not
+/// to spin while they last.
+pub(crate) async fn accept(listener: &TcpListener, server: &str) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(err) => {
+                warn!("{}: failed to accept a connection: {}", server, err);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Compensation of the drift between the clocks of the audio devices at
+//! the two ends of the stream.
+//!
+//! No two sound cards run at exactly the nominal rate, so the buffer in
+//! front of the playback device slowly fills up or runs dry. We watch its
+//! fill level, and nudge the resampling ratio to keep the level, and thus
+//! the latency, where it was.
+
+use crate::buf::FillLevel;
+use crate::log::{debug, KV};
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How long we watch the fill level before settling on the target, if it's
+/// not set.
+const WARMUP: Duration = Duration::from_secs(5);
+
+/// The time constant of the smoothing of the fill level. Has to be long
+/// enough to smooth out the bursts of the packets and the device callbacks.
+const SMOOTHING: Duration = Duration::from_secs(2);
+
+/// The correction per the relative deviation of the fill level from
+/// the target.
+const GAIN: f64 = 0.02;
+
+/// The max correction. The real drift is within a few hundred ppm.
+const MAX_CORRECTION: f64 = 0.002;
+
+/// How fast the correction can change, per second, so that the pitch
+/// change is never audible.
+const MAX_SLEW: f64 = 0.0001;
+
+/// The lowest fill level to keep, in samples. There must be some room for
+/// the correction to work with.
+const MIN_TARGET: f64 = 1.0;
+
+/// How often we publish the stats.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, KV)]
+pub struct DriftStats {
+    pub target_fill: usize,
+    pub smoothed_fill: usize,
+    /// The ratio of the output rate to the nominal one, in ppm; one million
+    /// means no correction.
+    pub rate_ratio_ppm: usize,
+}
+
+/// Estimates the drift from the fill level of the buffer the resampler
+/// writes to, and tells how to correct the resampling ratio.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug)]
+pub struct DriftEstimator {
+    fill_level: FillLevel,
+    /// The fill level to keep, in samples. Learned at the start if not set.
+    target: Option<f64>,
+    started_at: Option<Instant>,
+    updated_at: Option<Instant>,
+    smoothed: f64,
+    correction: f64,
+    stats: DriftStats,
+    /// Where to publish the stats for the metrics, if anywhere.
+    pub metrics: Option<metrics::Registry>,
+    published_at: Option<Instant>,
+}
+
+impl DriftEstimator {
+    /// Create the estimator watching the `fill_level`, to keep it at
+    /// the `target` amount of samples if set, or at the level it settles at
+    /// during the first seconds otherwise.
+    #[must_use]
+    pub fn new(fill_level: FillLevel, target: Option<usize>) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let target = target.map(|target| (target as f64).max(MIN_TARGET));
+        Self {
+            fill_level,
+            target,
+            started_at: None,
+            updated_at: None,
+            smoothed: 0.0,
+            correction: 0.0,
+            stats: DriftStats::default(),
+            metrics: None,
+            published_at: None,
+        }
+    }
+
+    /// Observe the fill level at `now`, and return the ratio to multiply
+    /// the output rate by.
+    pub fn update(&mut self, now: Instant) -> f64 {
+        #[allow(clippy::cast_precision_loss)]
+        let fill = self.fill_level.get() as f64;
+        let started_at = *self.started_at.get_or_insert(now);
+        let dt = match self.updated_at.replace(now) {
+            None => {
+                self.smoothed = fill;
+                return self.ratio();
+            }
+            Some(updated_at) => now.saturating_duration_since(updated_at).as_secs_f64(),
+        };
+
+        let alpha = 1.0 - (-dt / SMOOTHING.as_secs_f64()).exp();
+        self.smoothed += alpha * (fill - self.smoothed);
+
+        let target = match self.target {
+            Some(target) => target,
+            None if now.saturating_duration_since(started_at) < WARMUP => return self.ratio(),
+            None => {
+                let target = self.smoothed.max(MIN_TARGET);
+                debug!("Drift: keeping the fill level at {} samples", target);
+                *self.target.insert(target)
+            }
+        };
+
+        let deviation = (self.smoothed - target) / target;
+        let wanted = (-GAIN * deviation).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        let max_step = MAX_SLEW * dt;
+        self.correction += (wanted - self.correction).clamp(-max_step, max_step);
+
+        self.publish(now, target);
+        self.ratio()
+    }
+
+    /// The ratio to multiply the output rate by.
+    #[must_use]
+    pub fn ratio(&self) -> f64 {
+        1.0 + self.correction
+    }
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn publish(&mut self, now: Instant, target: f64) {
+        if self
+            .published_at
+            .map_or(false, |published_at| now < published_at + PUBLISH_INTERVAL)
+        {
+            return;
+        }
+        self.published_at = Some(now);
+        self.stats = DriftStats {
+            target_fill: target as usize,
+            smoothed_fill: self.smoothed as usize,
+            rate_ratio_ppm: (self.ratio() * 1_000_000.0).round() as usize,
+        };
+        debug!("playback drift"; &self.stats);
+        if let Some(metrics) = &self.metrics {
+            metrics.set_stats("playback_drift", &self.stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buf;
+    use crate::io::{AsyncReadItemsExt, AsyncWriteItemsExt, WaitMode};
+
+    /// Simulate five minutes of the playback buffer starting with `start`
+    /// samples, fed at the nominal rate corrected if `correct` is set, and
+    /// drained by the device running `device_drift` faster. Return the final
+    /// fill level.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn simulate(device_drift: f64, correct: bool, start: usize) -> usize {
+        futures::executor::block_on(async {
+            let (mut writer, mut reader) = buf::vec_deque_buffer_with_capacity(200_000);
+            let mut estimator = DriftEstimator::new(writer.fill_level(), None);
+            writer
+                .write_items(&vec![0.0_f32; start], WaitMode::NoWait)
+                .await
+                .unwrap();
+
+            let mut now = Instant::now();
+            let step = Duration::from_millis(10);
+            let nominal = 480.0;
+            let (mut produced, mut consumed) = (0.0, 0.0);
+            let mut drain = vec![0.0_f32; 1000];
+            for _ in 0..30_000 {
+                now += step;
+                let ratio = if correct { estimator.update(now) } else { 1.0 };
+                produced += nominal * ratio;
+                let to_write = produced as usize;
+                produced -= to_write as f64;
+                writer
+                    .write_items(&vec![0.0_f32; to_write], WaitMode::NoWait)
+                    .await
+                    .unwrap();
+
+                consumed += nominal * (1.0 + device_drift);
+                let to_read = consumed as usize;
+                consumed -= to_read as f64;
+                reader
+                    .read_items(&mut drain[..to_read], WaitMode::NoWait)
+                    .await
+                    .unwrap();
+            }
+            writer.fill_level().get()
+        })
+    }
+
+    #[test]
+    fn keeps_the_fill_level() {
+        // 200 ppm over five minutes at 48 kHz is 2880 samples.
+        let uncorrected = simulate(0.0002, false, 20_000);
+        assert!((17_000..=17_200).contains(&uncorrected), "{}", uncorrected);
+
+        for device_drift in [0.0002, -0.0002] {
+            let fill = simulate(device_drift, true, 20_000);
+            assert!((19_600..=20_400).contains(&fill), "{}", fill);
+        }
+    }
+
+    #[test]
+    fn zero_target() {
+        let (mut writer, _reader) = buf::vec_deque_buffer_with_capacity(1000);
+        let mut estimator = DriftEstimator::new(writer.fill_level(), Some(0));
+        futures::executor::block_on(writer.write_items(&[0.0_f32; 100], WaitMode::NoWait)).unwrap();
+
+        let mut now = Instant::now();
+        for _ in 0..100 {
+            now += Duration::from_millis(10);
+            let ratio = estimator.update(now);
+            assert!(ratio.is_finite(), "{}", ratio);
+        }
+        // Too full, so slow the feeding down.
+        assert!(estimator.ratio() < 1.0);
+    }
+}
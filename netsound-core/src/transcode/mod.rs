@@ -1,5 +1,6 @@
 use async_trait::async_trait;
 
+pub mod drift;
 pub mod noop;
 pub mod resampler;
 
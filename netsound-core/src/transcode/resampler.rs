@@ -1,14 +1,14 @@
+use super::drift::DriftEstimator;
 use super::Transcode;
 use crate::buf::{VecDequeBufferReader, VecDequeBufferWriter};
 use crate::io::{AsyncReadItemsExt, WaitMode};
 use crate::log::trace;
-use crate::match_channels;
 use crate::pcm::{self, Sample};
 use crate::samples_filter::NormalizeChannelsExt;
 use async_trait::async_trait;
-use dasp_frame::Frame;
 use dasp_sample::Duplex;
-use dasp_signal::{interpolate, Signal};
+use std::collections::VecDeque;
+use std::time::Instant;
 
 #[derive(Debug)]
 pub struct Resampler<S: Sample> {
@@ -20,6 +20,13 @@ pub struct Resampler<S: Sample> {
 
     pub from_buf: VecDequeBufferReader<S>,
     pub to_buf: VecDequeBufferWriter<S>,
+
+    /// Corrects the output rate for the clock drift, if set.
+    pub drift: Option<DriftEstimator>,
+
+    /// The first frame of the chunk to resample, read to wait for it.
+    head: Vec<S>,
+    interpolator: Interpolator<S>,
 }
 
 impl<S: Sample> Resampler<S> {
@@ -39,10 +46,81 @@ impl<S: Sample> Resampler<S> {
             to_hz,
             from_buf,
             to_buf,
+            drift: None,
+            head: vec![S::EQUILIBRIUM; from_channels],
+            interpolator: Interpolator::new(to_channels),
+        }
+    }
+
+    /// Correct the output rate with the `drift` estimator.
+    #[must_use]
+    pub fn with_drift(self, drift: DriftEstimator) -> Self {
+        Self {
+            drift: Some(drift),
+            ..self
         }
     }
 }
 
+impl<S> Resampler<S>
+where
+    S: Sample + Duplex<f64> + Unpin,
+{
+    /// Wait for the next chunk to resample, reading its first frame.
+    async fn read_head(&mut self) -> Result<(), crate::Error> {
+        trace!("Resampler: before read_exact_items");
+        self.from_buf
+            .read_exact_items(&mut self.head, WaitMode::WaitForReady)
+            .await?;
+        trace!("Resampler: after read_exact_items");
+        Ok(())
+    }
+
+    /// Resample the head of the chunk, and all the whole frames that follow
+    /// it in the `from_buf`, with the output rate corrected for the drift as
+    /// of `now`.
+    async fn resample(&mut self, now: Instant) {
+        trace!("Resampler: before locks");
+        let mut from_buf = self.from_buf.lock().await;
+        let mut to_buf = self.to_buf.lock().await;
+        trace!("Resampler: locks taken");
+
+        let to_hz = match &mut self.drift {
+            Some(drift) => self.to_hz * drift.update(now),
+            None => self.to_hz,
+        };
+
+        let from_buf_size_before = from_buf.len() + self.head.len();
+        let to_buf_size_before = to_buf.len();
+
+        let whole_frames_len = from_buf.len() / self.from_channels * self.from_channels;
+        let iter = self.head.iter().copied();
+        let iter = iter.chain(from_buf.drain(..whole_frames_len));
+        let iter = iter.normalize_channels(self.from_channels, self.to_channels);
+
+        // TODO: this extend may cause an unexpected `to_buf`
+        // capacity growth. We should provide a better API,
+        // involving waiting for write readiness.
+        self.interpolator
+            .process(iter, self.from_hz / to_hz, &mut to_buf);
+
+        let from_buf_size_after = from_buf.len();
+        let to_buf_size_after = to_buf.len();
+
+        drop(to_buf);
+        drop(from_buf);
+        trace!("Resampler: after locks");
+
+        trace!(
+            "Resampler: {} -> {}  =>  {} -> {}",
+            from_buf_size_before,
+            to_buf_size_before,
+            from_buf_size_after,
+            to_buf_size_after
+        );
+    }
+}
+
 #[async_trait]
 impl<S> Transcode for Resampler<S>
 where
@@ -50,62 +128,151 @@ where
 {
     type Ok = futures::never::Never;
 
-    // We're using macros here that unwrap the same code 32 times. Clippy seems
-    // to interpret this incorrectly. Disabling it as a palse-positive.
-    #[allow(clippy::cognitive_complexity)]
     async fn transcode_loop(&mut self) -> Result<Self::Ok, crate::Error> {
-        let to_channels = self.to_channels;
-        let this = &mut *self;
-
-        match_channels! {
-            F => [to_channels] => {
-                let mut first_frame_data = F::<S>::EQUILIBRIUM;
-                loop {
-                    trace!("Resampler: before read_exact_items");
-                    this.from_buf.read_exact_items(&mut first_frame_data, WaitMode::WaitForReady).await?;
-                    trace!("Resampler: after read_exact_items");
-
-                    trace!("Resampler: before locks");
-                    let mut from_buf = this.from_buf.lock().await;
-                    let mut to_buf = this.to_buf.lock().await;
-                    trace!("Resampler: locks taken");
-
-                    let from_buf_size_before = from_buf.len() + first_frame_data.len();
-                    let to_buf_size_before = to_buf.len();
-
-                    let iter = first_frame_data.iter().copied();
-                    let iter = iter.chain(from_buf.drain(..));
-                    let iter = iter.normalize_channels(this.from_channels, to_channels);
-                    let mut from_signal = dasp_signal::from_interleaved_samples_iter::<_, F<S>>(iter);
-                    let left = from_signal.next();
-                    let right = from_signal.next();
-                    let interpolator = dasp_interpolate::linear::Linear::new(left, right);
-                    let converter = interpolate::Converter::from_hz_to_hz(
-                        from_signal,
-                        interpolator,
-                        this.from_hz,
-                        this.to_hz,
-                    );
-
-                    // TODO: this extend may cause an unexpected `to_buf`
-                    // capacity growth. We should provide a better API,
-                    // involving waiting for write readiness.
-                    to_buf.extend(
-                        converter
-                            .until_exhausted()
-                            .flat_map(Frame::channels),
-                    );
-
-                    let from_buf_size_after = from_buf.len();
-                    let to_buf_size_after = to_buf.len();
-
-                    drop(to_buf);
-                    drop(from_buf);
-                    trace!("Resampler: after locks");
-
-                    trace!("Resampler: {} -> {}  =>  {} -> {}", from_buf_size_before, to_buf_size_before, from_buf_size_after, to_buf_size_after);
-                }
+        loop {
+            self.read_head().await?;
+            self.resample(Instant::now()).await;
+        }
+    }
+}
+
+/// The linear interpolation of the stream that comes in chunks. It carries
+/// the phase and the last source frame over from one chunk to the next, so
+/// that the chunks join seamlessly, and at the ratio of one the samples pass
+/// through unchanged, a frame late.
+#[derive(Debug)]
+struct Interpolator<S> {
+    channels: pcm::Channels,
+    /// The last source frame, empty until the first one comes in.
+    last: Vec<S>,
+    /// The frame being collected from the source.
+    next: Vec<S>,
+    /// The position of the next output frame between the `last` source
+    /// frame and the `next` one.
+    phase: f64,
+}
+
+impl<S: Sample> Interpolator<S> {
+    fn new(channels: pcm::Channels) -> Self {
+        Self {
+            channels,
+            last: Vec::with_capacity(channels),
+            next: Vec::with_capacity(channels),
+            phase: 0.0,
+        }
+    }
+}
+
+impl<S> Interpolator<S>
+where
+    S: Sample + Duplex<f64>,
+{
+    /// Interpolate the interleaved `samples`, taking `step` source frames per
+    /// output frame, and append the output to `output`.
+    fn process(&mut self, samples: impl Iterator<Item = S>, step: f64, output: &mut VecDeque<S>) {
+        for sample in samples {
+            self.next.push(sample);
+            if self.next.len() < self.channels {
+                continue;
+            }
+            if self.last.is_empty() {
+                std::mem::swap(&mut self.last, &mut self.next);
+                continue;
+            }
+            while self.phase < 1.0 {
+                output.extend(self.last.iter().zip(&self.next).map(|(&last, &next)| {
+                    let last = last.to_sample::<f64>();
+                    let next = next.to_sample::<f64>();
+                    S::from_sample(last + (next - last) * self.phase)
+                }));
+                self.phase += step;
+            }
+            self.phase -= 1.0;
+            std::mem::swap(&mut self.last, &mut self.next);
+            self.next.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buf;
+    use crate::io::{AsyncReadItemsExt, AsyncWriteItemsExt};
+    use std::time::Duration;
+
+    #[test]
+    fn passes_through_unchanged() {
+        futures::executor::block_on(async {
+            let (mut net, from_buf) = buf::vec_deque_buffer_with_capacity(1000);
+            let (to_buf, mut device) = buf::vec_deque_buffer_with_capacity(1000);
+            let mut resampler = Resampler::new(2, 2, 48000.0, 48000.0, from_buf, to_buf);
+
+            let samples: Vec<f32> = (0..1000_u16).map(|n| f32::from(n) / 1000.0).collect();
+            for chunk in samples.chunks(98) {
+                net.write_items(chunk, WaitMode::NoWait).await.unwrap();
+                resampler.read_head().await.unwrap();
+                resampler.resample(Instant::now()).await;
             }
+
+            // A frame late.
+            let mut played = vec![0.0; samples.len() - 2];
+            device
+                .read_exact_items(&mut played, WaitMode::NoWait)
+                .await
+                .unwrap();
+            assert_eq!(played, samples[..samples.len() - 2]);
+        });
+    }
+
+    /// Simulate two minutes of the playback starting with `start` samples
+    /// buffered, fed at the nominal rate through the resampler keeping
+    /// the fill level at `start`, and drained by the device running
+    /// `device_drift` faster. Return the final fill level.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn simulate(device_drift: f64, start: usize) -> usize {
+        futures::executor::block_on(async {
+            let (mut net, from_buf) = buf::vec_deque_buffer_with_capacity(200_000);
+            let (to_buf, mut device) = buf::vec_deque_buffer_with_capacity(200_000);
+            let drift = DriftEstimator::new(device.fill_level(), Some(start));
+            let mut resampler =
+                Resampler::new(1, 1, 48000.0, 48000.0, from_buf, to_buf).with_drift(drift);
+            net.write_items(&vec![0.0_f32; start], WaitMode::NoWait)
+                .await
+                .unwrap();
+
+            let mut now = Instant::now();
+            let packet = vec![0.0_f32; 480];
+            let mut consumed = 0.0;
+            let mut drain = vec![0.0_f32; 1000];
+            for _ in 0..12_000 {
+                now += Duration::from_millis(10);
+                net.write_items(&packet, WaitMode::NoWait).await.unwrap();
+                resampler.read_head().await.unwrap();
+                resampler.resample(now).await;
+
+                consumed += 480.0 * (1.0 + device_drift);
+                let to_read = consumed as usize;
+                consumed -= to_read as f64;
+                device
+                    .read_items(&mut drain[..to_read], WaitMode::NoWait)
+                    .await
+                    .unwrap();
+            }
+            device.fill_level().get()
+        })
+    }
+
+    #[test]
+    fn keeps_the_fill_level() {
+        // 200 ppm over two minutes at 48 kHz is 1152 samples.
+        for device_drift in [0.0002, -0.0002] {
+            let fill = simulate(device_drift, 20_000);
+            assert!((19_600..=20_400).contains(&fill), "{}", fill);
         }
     }
 }
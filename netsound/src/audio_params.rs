@@ -1,22 +1,27 @@
 use super::pcm;
 
-static AUDIO_PARAMS: &[(pcm::SampleRate, pcm::Channels)] = &[
-    (pcm::SampleRate::from_usize(48000), 2),
-    (pcm::SampleRate::from_usize(48000), 1),
-    (pcm::SampleRate::from_usize(24000), 2),
-    (pcm::SampleRate::from_usize(24000), 1),
-    (pcm::SampleRate::from_usize(16000), 2),
-    (pcm::SampleRate::from_usize(16000), 1),
-    (pcm::SampleRate::from_usize(12000), 2),
-    (pcm::SampleRate::from_usize(12000), 1),
-    (pcm::SampleRate::from_usize(8000), 2),
-    (pcm::SampleRate::from_usize(8000), 1),
+static SAMPLE_RATES: &[pcm::SampleRate] = &[
+    pcm::SampleRate::from_usize(48000),
+    pcm::SampleRate::from_usize(24000),
+    pcm::SampleRate::from_usize(16000),
+    pcm::SampleRate::from_usize(12000),
+    pcm::SampleRate::from_usize(8000),
 ];
 
-pub fn input() -> &'static [(pcm::SampleRate, pcm::Channels)] {
-    AUDIO_PARAMS
+/// The params to ask the audio devices for, in the order of preference:
+/// the `channels`, if set, at every sample rate, and then stereo and mono.
+fn params(channels: Option<pcm::Channels>) -> Vec<(pcm::SampleRate, pcm::Channels)> {
+    let requested = channels
+        .into_iter()
+        .flat_map(|channels| SAMPLE_RATES.iter().map(move |&rate| (rate, channels)));
+    let fallback = SAMPLE_RATES.iter().flat_map(|&rate| [(rate, 2), (rate, 1)]);
+    requested.chain(fallback).collect()
 }
 
-pub fn output() -> &'static [(pcm::SampleRate, pcm::Channels)] {
-    AUDIO_PARAMS
+pub fn input(channels: Option<pcm::Channels>) -> Vec<(pcm::SampleRate, pcm::Channels)> {
+    params(channels)
+}
+
+pub fn output(channels: Option<pcm::Channels>) -> Vec<(pcm::SampleRate, pcm::Channels)> {
+    params(channels)
 }
@@ -1,17 +1,28 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
+use netsound_core::net::{crypto, multicast};
 use structopt::StructOpt;
 
-use crate::{audio_backend_config::AnyAudioBackendVariant, codec_config::CodecToUse};
+use crate::audio_backend_config::AnyAudioBackendVariant;
 
+// Parsed once at the startup, the size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt)]
 pub enum Command {
     /// Run the app.
     Run(RunParams),
+    /// Run the relay that sends every client the mix of all the others.
+    /// The clients connect to it as to a regular peer.
+    Relay(RelayParams),
     /// List available audio backends.
     ListAudioBackends,
+    /// List available codecs, with the sample rates and the channels they
+    /// support.
+    ListCodecs,
 }
 
+// The bools are the command line flags.
+#[allow(clippy::struct_excessive_bools)]
 #[derive(StructOpt)]
 pub struct RunParams {
     /// Audio backend to use.
@@ -22,9 +33,82 @@ pub struct RunParams {
         env = "AUDIO_BACKEND"
     )]
     pub audio_backend_variant: AnyAudioBackendVariant,
-    /// Audio codec to use.
-    #[structopt(short = "c", long = "codec", default_value = "opus", env = "CODEC")]
-    pub codec_to_use: CodecToUse,
+    /// The amount of channels to ask the audio devices for first, like 6
+    /// for 5.1 or 8 for 7.1, in the WAVE channel order. The devices that
+    /// can't do it fall back to stereo or mono.
+    #[structopt(long = "audio-channels", env = "AUDIO_CHANNELS")]
+    pub audio_channels: Option<usize>,
+    /// Audio codecs to offer to the peers in the handshake, in the order
+    /// of preference. The raw PCM comes as `raw` for the `f32` samples, and
+    /// `raw-s16`, `raw-s24` and `raw-s32` for the integer ones, all
+    /// little-endian, or big-endian with the `be` suffix, like `raw-s16be`.
+    /// The G.711 μ-law and A-law come as `pcmu` and `pcma`, at 8 kHz mono,
+    /// and the IMA ADPCM, a quarter of the 16-bit PCM, as `adpcm`. The opus
+    /// carries up to 7.1 surround. See the `list-codecs` command.
+    #[structopt(
+        short = "c",
+        long = "codec",
+        default_value = "opus,raw",
+        env = "CODEC",
+        use_delimiter = true
+    )]
+    pub codecs_to_use: Vec<String>,
+    /// Enable the opus in-band forward error correction, tuned for
+    /// the given expected packet loss percentage.
+    #[structopt(long = "fec", env = "FEC")]
+    pub fec_expected_packet_loss: Option<u8>,
+    /// Adapt the opus bitrate, the expected packet loss and the FEC to
+    /// the loss and the jitter the receivers report. The `--fec` value
+    /// becomes the lowest expected packet loss.
+    #[structopt(long = "adaptive-bitrate")]
+    pub adaptive_bitrate: bool,
+    /// The lowest bitrate to adapt to, in bits per second.
+    #[structopt(long = "min-bitrate", default_value = "16000", env = "MIN_BITRATE")]
+    pub min_bitrate: u32,
+    /// The highest bitrate to adapt to, in bits per second.
+    #[structopt(long = "max-bitrate", default_value = "128000", env = "MAX_BITRATE")]
+    pub max_bitrate: u32,
+    /// The bitrate to start the adaptation with, in bits per second.
+    #[structopt(long = "start-bitrate", default_value = "64000", env = "START_BITRATE")]
+    pub start_bitrate: u32,
+    #[structopt(flatten)]
+    pub opus: OpusParams,
+    /// Encrypt and authenticate the packets with this pre-shared key,
+    /// given as 64 hex digits. All the peers must use the same key.
+    #[structopt(long = "psk", env = "PSK", hide_env_values = true)]
+    pub psk: Option<crypto::Key>,
+    /// The max size of the UDP payload to send, in bytes. The default fits
+    /// the 1500-byte Ethernet MTU. The bigger datagrams we receive are
+    /// dropped, so all the peers should use the same value.
+    #[structopt(
+        long = "max-payload-size",
+        default_value = "1452",
+        env = "MAX_PAYLOAD_SIZE"
+    )]
+    pub max_payload_size: usize,
+    /// How long, in seconds, to wait for all the peers to answer
+    /// the handshake at the startup before giving up.
+    #[structopt(
+        long = "handshake-timeout",
+        default_value = "60",
+        env = "HANDSHAKE_TIMEOUT"
+    )]
+    pub handshake_timeout: u64,
+    /// Send and receive the opus stream as RTP (RFC 3550, RFC 7587), for
+    /// interoperability with the standard tools.
+    #[structopt(long = "rtp")]
+    pub rtp: bool,
+    /// The RTP payload type of the opus stream.
+    #[structopt(
+        long = "rtp-payload-type",
+        default_value = "111",
+        env = "RTP_PAYLOAD_TYPE"
+    )]
+    pub rtp_payload_type: u8,
+    /// Print the SDP description of the RTP stream sent to every address,
+    /// for the tools receiving it.
+    #[structopt(long = "print-sdp", requires = "rtp")]
+    pub print_sdp: bool,
 
     /// Interface address and the port to bind to.
     #[structopt(
@@ -34,7 +118,162 @@ pub struct RunParams {
         env = "BIND_ADDR"
     )]
     pub bind_addr: SocketAddr,
+    /// Join the multicast group to receive the audio sent to it. Can be
+    /// given multiple times. To send to a group, add its address to
+    /// the addresses to send to.
+    #[structopt(long = "multicast-group", number_of_values = 1)]
+    pub multicast_groups: Vec<IpAddr>,
+    /// The interface to use for the multicast: the address of the interface
+    /// for IPv4, or the index of the interface for IPv6.
+    #[structopt(long = "multicast-interface", env = "MULTICAST_INTERFACE")]
+    pub multicast_interface: Option<multicast::Interface>,
+    /// How many hops the multicast audio packets can make.
+    #[structopt(long = "multicast-ttl", default_value = "1", env = "MULTICAST_TTL")]
+    pub multicast_ttl: u32,
+    /// Deliver the multicast audio packets we send to the listeners on this
+    /// host too, including ourselves.
+    #[structopt(long = "multicast-loopback")]
+    pub multicast_loopback: bool,
+
+    /// Serve the control interface for adding and removing the peers at
+    /// runtime at this address. Connect with `nc` and type `help`. It has
+    /// no authentication, so only the loopback addresses are accepted.
+    #[structopt(long = "control-addr", env = "CONTROL_ADDR")]
+    pub control_addr: Option<SocketAddr>,
+    /// Start sending to the peers that greet us with the handshake, for as
+    /// long as they keep sending to us. Needs the `--psk`, so that only
+    /// the peers that know the key can join.
+    #[structopt(long = "auto-join", requires = "psk")]
+    pub auto_join: bool,
+    /// How long, in seconds, an automatically joined peer stays after the
+    /// last packet from it.
+    #[structopt(long = "auto-join-ttl", default_value = "30", env = "AUTO_JOIN_TTL")]
+    pub auto_join_ttl: u64,
+
+    /// Compensate the drift between the clocks of our playback device and
+    /// the senders' capture devices, to keep the latency constant over long
+    /// sessions.
+    #[structopt(long = "drift-compensation")]
+    pub drift_compensation: bool,
+    /// How much audio, in milliseconds, to keep buffered for the playback
+    /// when compensating the drift. By default, the amount that's buffered
+    /// during the first seconds is kept.
+    #[structopt(
+        long = "drift-target-ms",
+        env = "DRIFT_TARGET_MS",
+        requires = "drift-compensation"
+    )]
+    pub drift_target_ms: Option<usize>,
+
+    /// Serve the metrics in the Prometheus text format over HTTP at this
+    /// address, under `/metrics`.
+    #[structopt(long = "metrics-addr", env = "METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
     /// A list of host:port pairs to send the audio packets to.
-    /// If not set, data is sent to the binded address (loopback).
+    /// If not set, data is sent to the binded address (loopback), unless
+    /// the peers join automatically.
     pub send_addrs: Vec<SocketAddr>,
 }
+
+/// The opus encoder settings.
+#[derive(Debug, StructOpt)]
+pub struct OpusParams {
+    /// What the opus encoder is tuned for: `voip` for the speech, `audio`
+    /// for the music, or `lowdelay` for the lowest latency.
+    #[structopt(
+        long = "opus-application",
+        default_value = "audio",
+        env = "OPUS_APPLICATION"
+    )]
+    pub application: netsound_codec_opus::Application,
+    /// The opus bitrate, in bits per second. Picked by the encoder if not
+    /// set.
+    #[structopt(
+        long = "opus-bitrate",
+        env = "OPUS_BITRATE",
+        conflicts_with = "adaptive-bitrate"
+    )]
+    pub bitrate: Option<u32>,
+    /// The opus encoder complexity, from 0 for the lowest CPU usage to 10
+    /// for the best quality.
+    #[structopt(long = "opus-complexity", env = "OPUS_COMPLEXITY")]
+    pub complexity: Option<u8>,
+    /// The opus variable bitrate: `on`, `constrained` to stay close to
+    /// the target bitrate, or `off` for the constant bitrate.
+    #[structopt(long = "opus-vbr", default_value = "constrained", env = "OPUS_VBR")]
+    pub vbr: netsound_codec_opus::Vbr,
+    /// Enable the opus discontinuous transmission, sending the tiny packets
+    /// during the silence.
+    #[structopt(long = "opus-dtx")]
+    pub dtx: bool,
+    /// The kind of the signal to tune the opus encoder for: `auto`, `voice`
+    /// or `music`.
+    #[structopt(long = "opus-signal", default_value = "auto", env = "OPUS_SIGNAL")]
+    pub signal: netsound_codec_opus::Signal,
+    /// The duration of the opus frames, in milliseconds: 2.5, 5, 10, 20, 40,
+    /// 60, 80, 100 or 120. The shorter frames lower the latency at the cost
+    /// of the bandwidth. By default, the longest frames that fit a packet
+    /// are used.
+    #[structopt(
+        long = "opus-frame-duration",
+        env = "OPUS_FRAME_DURATION",
+        parse(try_from_str = netsound_codec_opus::parse_frame_duration)
+    )]
+    pub frame_duration: Option<std::time::Duration>,
+}
+
+impl From<OpusParams> for netsound_codec_opus::Settings {
+    fn from(params: OpusParams) -> Self {
+        Self {
+            application: params.application,
+            bitrate: params.bitrate,
+            complexity: params.complexity,
+            vbr: params.vbr,
+            dtx: params.dtx,
+            signal: params.signal,
+            frame_duration: params.frame_duration,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct RelayParams {
+    /// Interface address and the port to bind to.
+    #[structopt(
+        short = "b",
+        long = "bind",
+        default_value = "0.0.0.0:8080",
+        env = "BIND_ADDR"
+    )]
+    pub bind_addr: SocketAddr,
+    /// The audio codec all the clients have to use, see the `run`
+    /// command.
+    #[structopt(short = "c", long = "codec", default_value = "opus", env = "CODEC")]
+    pub codec_to_use: String,
+    /// The sample rate all the clients have to use.
+    #[structopt(long = "sample-rate", default_value = "48000", env = "SAMPLE_RATE")]
+    pub sample_rate: u32,
+    /// The amount of channels all the clients have to use.
+    #[structopt(long = "channels", default_value = "2", env = "CHANNELS")]
+    pub channels: u16,
+    /// Encrypt and authenticate the packets with this pre-shared key,
+    /// given as 64 hex digits. All the clients must use the same key.
+    #[structopt(long = "psk", env = "PSK", hide_env_values = true)]
+    pub psk: Option<crypto::Key>,
+    /// The max size of the UDP payload to send, in bytes. The default fits
+    /// the 1500-byte Ethernet MTU. The bigger datagrams we receive are
+    /// dropped, so all the clients should use the same value.
+    #[structopt(
+        long = "max-payload-size",
+        default_value = "1452",
+        env = "MAX_PAYLOAD_SIZE"
+    )]
+    pub max_payload_size: usize,
+    /// How long, in seconds, a client stays after the last packet from it.
+    #[structopt(long = "client-timeout", default_value = "10", env = "CLIENT_TIMEOUT")]
+    pub client_timeout: u64,
+    /// Serve the metrics in the Prometheus text format over HTTP at this
+    /// address, under `/metrics`.
+    #[structopt(long = "metrics-addr", env = "METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+}
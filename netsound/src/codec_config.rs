@@ -1,17 +1,58 @@
-#[derive(Debug)]
-pub enum CodecToUse {
-    Opus,
-    Raw,
+use netsound_core::codec::registry::{DynDecoder, Factory, Registry, Settings};
+use netsound_core::{net::handshake, pcm};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// The codecs we can use: the ones of the core, and the opus building
+/// the encoders with the `opus_settings`.
+pub fn registry(opus_settings: netsound_codec_opus::Settings) -> Result<Registry, anyhow::Error> {
+    let mut registry = Registry::with_builtin();
+    registry.register(netsound_codec_opus::Factory::new(opus_settings))?;
+    Ok(registry)
+}
+
+/// The factory of the codec with the `name`.
+pub fn find(registry: &Registry, name: &str) -> Result<Arc<dyn Factory>, anyhow::Error> {
+    registry
+        .get(name)
+        .ok_or_else(|| anyhow::format_err!("codec {:?} is not available", name))
 }
 
-impl std::str::FromStr for CodecToUse {
-    type Err = anyhow::Error;
+/// The decoder of the stream with the `params`. We don't resample
+/// the streams, so they have to match the `stream_config` we play at.
+pub fn decoder(
+    registry: &Registry,
+    params: &handshake::Params,
+    stream_config: pcm::StreamConfig<f32>,
+    settings: &Settings,
+) -> Result<Box<DynDecoder>, anyhow::Error> {
+    let sample_rate = usize::try_from(params.sample_rate)?;
+    if sample_rate != stream_config.sample_rate().as_usize()
+        || usize::from(params.channels) != stream_config.channels()
+    {
+        return Err(anyhow::format_err!(
+            "the stream doesn't match the playback at {} Hz, {} channels",
+            stream_config.sample_rate().as_usize(),
+            stream_config.channels()
+        ));
+    }
+    find(registry, &params.codec)?.decoder(stream_config, settings)
+}
 
-    fn from_str(name: &str) -> Result<Self, Self::Err> {
-        Ok(match name {
-            "opus" => CodecToUse::Opus,
-            "raw" => CodecToUse::Raw,
-            name => return Err(anyhow::format_err!("codec {:?} is not available", name)),
-        })
+/// Print the codecs of the `registry`, one per line.
+pub fn print(registry: &Registry) {
+    for factory in registry.iter() {
+        let capabilities = factory.capabilities();
+        let sample_rates: Vec<_> = capabilities
+            .sample_rates
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        println!(
+            "{}: {} Hz, up to {} channels",
+            factory.name(),
+            sample_rates.join(", "),
+            capabilities.max_channels
+        );
     }
 }
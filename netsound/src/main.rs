@@ -4,20 +4,28 @@
 #![allow(incomplete_features)]
 #![feature(adt_const_params)]
 
-use futures::{future::select, FutureExt};
+use futures::FutureExt;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use tokio::{net::UdpSocket, runtime::Runtime};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    runtime::Runtime,
+};
 
 use netsound_core::{
-    audio_backend, buf, codec, io, log, net, pcm, transcode, transcode_service, Error,
+    audio_backend, buf, codec, control, io, log, metrics, net, pcm, shutdown::Shutdown, transcode,
+    transcode_service, Error,
 };
 
 mod audio_backend_config;
 mod audio_params;
 mod cli;
 mod codec_config;
+mod relay;
+mod shutdown;
 
 use audio_backend::Backend;
 use log::{info, logger, o, slog_info, warn, LogScopeFutureExt};
@@ -34,22 +42,77 @@ fn errmain() -> Result<(), Error> {
     let command = cli::Command::from_args();
     let params = match command {
         cli::Command::Run(params) => params,
+        cli::Command::Relay(params) => return relay::run(params),
         cli::Command::ListAudioBackends => {
             for variant in audio_backend_config::AnyAudioBackendVariant::all() {
                 println!("{variant}");
             }
             return Ok(());
         }
+        cli::Command::ListCodecs => {
+            codec_config::print(&codec_config::registry(
+                netsound_codec_opus::Settings::default(),
+            )?);
+            return Ok(());
+        }
     };
     let cli::RunParams {
         bind_addr,
         send_addrs,
         audio_backend_variant,
-        codec_to_use,
+        audio_channels,
+        codecs_to_use,
+        fec_expected_packet_loss,
+        adaptive_bitrate,
+        min_bitrate,
+        max_bitrate,
+        start_bitrate,
+        opus,
+        psk,
+        max_payload_size,
+        handshake_timeout,
+        rtp,
+        rtp_payload_type,
+        print_sdp,
+        metrics_addr,
+        multicast_groups,
+        multicast_interface,
+        multicast_ttl,
+        multicast_loopback,
+        control_addr,
+        auto_join,
+        auto_join_ttl,
+        drift_compensation,
+        drift_target_ms,
     } = params;
 
+    if let Some(bitrate) = opus.bitrate {
+        check_bitrate("opus", bitrate)?;
+    }
+    if let Some(expected_packet_loss) = fec_expected_packet_loss {
+        if expected_packet_loss > 100 {
+            return Err(anyhow::format_err!(
+                "the expected packet loss is a percentage, {} is above 100",
+                expected_packet_loss
+            ));
+        }
+    }
+    let codecs = codec_config::registry(opus.into())?;
+    let codecs_to_use = codecs_to_use
+        .iter()
+        .map(|name| codec_config::find(&codecs, name))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let auto_join_ttl = Duration::from_secs(auto_join_ttl);
+    if auto_join && Instant::now().checked_add(auto_join_ttl).is_none() {
+        return Err(anyhow::format_err!(
+            "the auto-join ttl of {}s is too long",
+            auto_join_ttl.as_secs()
+        ));
+    }
+
     let send_addrs = {
-        if send_addrs.is_empty() {
+        if send_addrs.is_empty() && !auto_join {
             vec![bind_addr]
         } else {
             send_addrs
@@ -58,29 +121,193 @@ fn errmain() -> Result<(), Error> {
 
     let rt = Runtime::new()?;
 
-    let socket = rt.block_on(UdpSocket::bind(&bind_addr))?;
+    let uses_multicast = !multicast_groups.is_empty()
+        || send_addrs
+            .iter()
+            .any(|send_addr| send_addr.ip().is_multicast());
+    let socket = if uses_multicast {
+        let multicast_config = net::multicast::Config {
+            groups: multicast_groups,
+            interface: multicast_interface.unwrap_or_default(),
+            ttl: multicast_ttl,
+            loopback: multicast_loopback,
+        };
+        info!("Using multicast: {:?}", multicast_config);
+        let _guard = rt.enter();
+        net::multicast::bind(bind_addr, &multicast_config)?
+    } else {
+        rt.block_on(UdpSocket::bind(&bind_addr))?
+    };
     slog_info!(logger(), "Listening on: {}", socket.local_addr()?);
     info!("Sending to: {:?}", &send_addrs);
 
-    info!("Using codec: {:?}", codec_to_use);
+    let metrics_listener = match metrics_addr {
+        Some(metrics_addr) => {
+            let listener = rt.block_on(TcpListener::bind(metrics_addr))?;
+            let local_addr = listener.local_addr()?;
+            info!("Serving metrics on: {}", local_addr);
+            Some(listener)
+        }
+        None => None,
+    };
+    let metrics = metrics::Registry::new();
+
+    let control_listener = match control_addr {
+        Some(control_addr) => {
+            if !control_addr.ip().is_loopback() {
+                return Err(anyhow::format_err!(
+                    "the control interface has no authentication, so it only \
+                     listens on the loopback addresses, not on {}",
+                    control_addr
+                ));
+            }
+            let listener = rt.block_on(TcpListener::bind(control_addr))?;
+            let local_addr = listener.local_addr()?;
+            info!("Serving control interface on: {}", local_addr);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    if psk.is_some() {
+        info!("Using packet encryption");
+    }
+    let audio_payload_size = net::mtu::audio_payload_size(max_payload_size, psk.is_some())?;
+    info!("Sending up to {} bytes per packet", max_payload_size);
     info!("Using audio backend: {:?}", audio_backend_variant);
 
+    if let Some(audio_channels) = audio_channels {
+        if !(1..=usize::from(codec::registry::MAX_CHANNELS)).contains(&audio_channels) {
+            return Err(anyhow::format_err!(
+                "can't use {} audio channels, up to {} are supported",
+                audio_channels,
+                codec::registry::MAX_CHANNELS
+            ));
+        }
+    }
+    let request_capture_params = audio_params::input(audio_channels);
+    let request_playback_params = audio_params::output(audio_channels);
     let audio_backend_build_params = audio_backend_config::BuildParams {
-        request_capture_params: audio_params::input(),
-        request_playback_params: audio_params::output(),
+        request_capture_params: &request_capture_params,
+        request_playback_params: &request_playback_params,
         logger: logger().new(o!("logger" => "audio")),
     };
     let (negotiated_stream_configs, continuation) =
         audio_backend_config::Factory::build(&audio_backend_variant, audio_backend_build_params)?;
 
-    let net_capture_stream_config = pcm::StreamConfig::new(
-        48000.into(),
-        std::cmp::min(2, negotiated_stream_configs.capture.channels()),
-    );
-    let net_playback_stream_config = pcm::StreamConfig::new(
-        48000.into(),
-        std::cmp::min(2, negotiated_stream_configs.playback.channels()),
+    let framing = if rtp {
+        if !codecs_to_use
+            .iter()
+            .any(|codec| codec.name() == netsound_codec_opus::NAME)
+        {
+            return Err(anyhow::format_err!("RTP mode requires the opus codec"));
+        }
+        if psk.is_some() {
+            return Err(anyhow::format_err!(
+                "RTP mode does not support packet encryption"
+            ));
+        }
+        if rtp_payload_type >= 0x80 {
+            return Err(anyhow::format_err!(
+                "RTP payload type {} does not fit 7 bits",
+                rtp_payload_type
+            ));
+        }
+        net::Framing::Rtp(net::rtp::Config {
+            payload_type: rtp_payload_type,
+            channels: std::cmp::min(
+                net::rtp::MAX_CHANNELS,
+                negotiated_stream_configs.capture.channels(),
+            ),
+        })
+    } else {
+        net::Framing::Native
+    };
+    info!("Using framing: {:?}", framing);
+
+    // The RTP streams are described by the SDP instead of the handshake.
+    let session = match framing {
+        net::Framing::Native => {
+            let device_channels = std::cmp::max(
+                negotiated_stream_configs.capture.channels(),
+                negotiated_stream_configs.playback.channels(),
+            );
+            let device_channels = device_channels.try_into().unwrap_or(u16::MAX);
+            let offer = net::handshake::Offer {
+                codecs: codecs_to_use
+                    .iter()
+                    .map(|codec| codec.offer(device_channels))
+                    .collect(),
+            };
+            // We can't tell who listens to a multicast group, so we only
+            // negotiate with the unicast peers.
+            let peers: Vec<_> = send_addrs
+                .iter()
+                .filter(|send_addr| !send_addr.ip().is_multicast())
+                .copied()
+                .collect();
+            info!("Offering: {}", offer);
+            let params = rt.block_on(net::handshake::negotiate(
+                &socket,
+                &peers,
+                &offer,
+                psk.as_ref(),
+                Duration::from_secs(handshake_timeout),
+            ))?;
+            info!("Agreed on: {}", params);
+            Some(params)
+        }
+        net::Framing::Rtp(_) => None,
+    };
+
+    let (codec, net_capture_stream_config, net_playback_stream_config) = match &session {
+        Some(params) => {
+            let sample_rate: usize = params.sample_rate.try_into()?;
+            let stream_config = pcm::StreamConfig::new(sample_rate.into(), params.channels.into());
+            (
+                codec_config::find(&codecs, &params.codec)?,
+                stream_config,
+                stream_config,
+            )
+        }
+        None => (
+            codec_config::find(&codecs, netsound_codec_opus::NAME)?,
+            pcm::StreamConfig::new(
+                48000.into(),
+                std::cmp::min(
+                    net::rtp::MAX_CHANNELS,
+                    negotiated_stream_configs.capture.channels(),
+                ),
+            ),
+            pcm::StreamConfig::new(
+                48000.into(),
+                std::cmp::min(
+                    net::rtp::MAX_CHANNELS,
+                    negotiated_stream_configs.playback.channels(),
+                ),
+            ),
+        ),
+    };
+    info!("Using codec: {}", codec.name());
+    metrics.add_stream_config("capture_audio", &negotiated_stream_configs.capture);
+    metrics.add_stream_config("capture_net", &net_capture_stream_config);
+    metrics.add_stream_config("playback_net", &net_playback_stream_config);
+    metrics.add_stream_config("playback_audio", &negotiated_stream_configs.playback);
+
+    if let (true, net::Framing::Rtp(rtp_config)) = (print_sdp, framing) {
+        let origin = socket.local_addr()?.ip();
+        for send_addr in &send_addrs {
+            println!("{}", net::rtp::opus_sdp(origin, *send_addr, &rtp_config));
+        }
+    }
+
+    let mut jitter_buffer_config = net::JitterBufferConfig::new(
+        net_playback_stream_config.sample_rate(),
+        net_playback_stream_config.channels(),
     );
+    if let net::Framing::Rtp(_) = framing {
+        jitter_buffer_config.clock_rate = f64::from(net::rtp::OPUS_CLOCK_RATE);
+    }
 
     let (capture_transcoder, capture_data_writer, capture_data_reader) = {
         let audio_stream_config = &negotiated_stream_configs.capture;
@@ -93,6 +320,7 @@ fn errmain() -> Result<(), Error> {
             );
 
             let (audio_writer, net_reader) = buf::vec_deque_buffer_with_capacity(30_000_000);
+            metrics.add_buffer("capture", net_reader.fill_level());
             (
                 Box::new(transcode::noop::Noop) as DynTranscoder,
                 audio_writer,
@@ -117,6 +345,8 @@ fn errmain() -> Result<(), Error> {
 
             let (audio_writer, transcoder_reader) = buf::vec_deque_buffer_with_capacity(30_000_000);
             let (transcoder_writer, net_reader) = buf::vec_deque_buffer_with_capacity(30_000_000);
+            metrics.add_buffer("capture_audio", audio_writer.fill_level());
+            metrics.add_buffer("capture_net", net_reader.fill_level());
 
             (
                 Box::new(transcode::resampler::Resampler::new(
@@ -136,13 +366,16 @@ fn errmain() -> Result<(), Error> {
         let net_stream_config = &net_playback_stream_config;
         let audio_stream_config = &negotiated_stream_configs.playback;
 
-        if net_stream_config == audio_stream_config {
+        // The drift is corrected by the resampler, so we need one even if
+        // there's nothing to convert.
+        if net_stream_config == audio_stream_config && !drift_compensation {
             info!(
                 "playback transcoder is noop: {} => {}",
                 net_stream_config, audio_stream_config,
             );
 
             let (net_writer, audio_reader) = buf::vec_deque_buffer_with_capacity(30_000_000);
+            metrics.add_buffer("playback", net_writer.fill_level());
             (
                 Box::new(transcode::noop::Noop) as DynTranscoder,
                 net_writer,
@@ -167,93 +400,245 @@ fn errmain() -> Result<(), Error> {
 
             let (net_writer, transcoder_reader) = buf::vec_deque_buffer_with_capacity(30_000_000);
             let (transcoder_writer, audio_reader) = buf::vec_deque_buffer_with_capacity(30_000_000);
+            metrics.add_buffer("playback_net", net_writer.fill_level());
+            metrics.add_buffer("playback_audio", audio_reader.fill_level());
+
+            let mut resampler = transcode::resampler::Resampler::new(
+                net_channels,
+                audio_channels,
+                net_sample_rate,
+                audio_sample_rate,
+                transcoder_reader,
+                transcoder_writer,
+            );
+            if drift_compensation {
+                let target = drift_target_ms.map(|drift_target_ms| {
+                    audio_stream_config.sample_rate().as_usize()
+                        * audio_stream_config.channels()
+                        * drift_target_ms
+                        / 1000
+                });
+                info!(
+                    "Compensating the clock drift, keeping {:?} samples buffered",
+                    target
+                );
+                let mut drift =
+                    transcode::drift::DriftEstimator::new(audio_reader.fill_level(), target);
+                drift.metrics = metrics_listener.as_ref().map(|_| metrics.clone());
+                resampler = resampler.with_drift(drift);
+            }
 
             (
-                Box::new(transcode::resampler::Resampler::new(
-                    net_channels,
-                    audio_channels,
-                    net_sample_rate,
-                    audio_sample_rate,
-                    transcoder_reader,
-                    transcoder_writer,
-                )) as DynTranscoder,
+                Box::new(resampler) as DynTranscoder,
                 net_writer,
                 audio_reader,
             )
         }
     };
 
-    let mut encoder: Box<dyn codec::Encoder<f32, _> + Send>;
-    let mut decoder: Box<dyn codec::Decoder<f32, _> + Send>;
-
-    match codec_to_use {
-        codec_config::CodecToUse::Opus => {
-            let opus_encoder_buf: Box<[f32]> =
-                buffer(netsound_codec_opus::compute_required_buf_size(
-                    net_capture_stream_config.channels(),
-                    net_capture_stream_config.sample_rate(),
-                ));
-            let opus_decoder_buf: Box<[f32]> =
-                buffer(netsound_codec_opus::compute_required_buf_size(
-                    net_playback_stream_config.channels(),
-                    net_playback_stream_config.sample_rate(),
-                ));
-
-            encoder = Box::new(netsound_codec_opus::Encoder::new(
-                net_capture_stream_config,
-                opus_encoder_buf,
-            )?);
-            decoder = Box::new(netsound_codec_opus::Decoder::new(
-                net_playback_stream_config,
-                opus_decoder_buf,
-            )?);
-        }
-        codec_config::CodecToUse::Raw => {
-            encoder = Box::new(codec::raw::Encoder);
-            decoder = Box::new(codec::raw::Decoder);
+    let codec_settings = codec::registry::Settings {
+        max_packet_size: audio_payload_size,
+        // The frames must fit the packets even at the highest bitrate we
+        // may go up to.
+        max_bitrate: (adaptive_bitrate && framing == net::Framing::Native).then_some(max_bitrate),
+        fec_expected_packet_loss,
+    };
+    if let Some(expected_packet_loss) = fec_expected_packet_loss {
+        if codec.supports_fec() {
+            info!(
+                "Using {} FEC for {}% expected packet loss",
+                codec.name(),
+                expected_packet_loss
+            );
+        } else {
+            warn!("FEC is not supported by {}, ignoring", codec.name());
         }
+    }
+    info!(
+        "Encoding up to {} samples per packet",
+        codec.frame_samples(net_capture_stream_config, &codec_settings)
+    );
+    let encoder = codec.encoder(net_capture_stream_config, &codec_settings)?;
+    // Only the streams that describe themselves can switch the codec, to
+    // any of the ones we'd offer.
+    let codec_switch = session.is_some().then(|| {
+        net::stream::CodecSwitch::new(
+            codecs_to_use
+                .iter()
+                .map(|codec| codec.name().to_owned())
+                .collect(),
+        )
+    });
+    let encoder_factory: net::SendEncoderFactory<'_, codec::registry::DynEncoder> = {
+        let codecs = codecs.clone();
+        Box::new(move |params| {
+            codec_config::find(&codecs, &params.codec)?
+                .encoder(net_capture_stream_config, &codec_settings)
+        })
+    };
+    let decoder_factory: net::DecoderFactory<'_, codec::registry::DynDecoder> = {
+        let codecs = codecs.clone();
+        Box::new(move |params| {
+            codec_config::decoder(&codecs, params, net_playback_stream_config, &codec_settings)
+        })
+    };
+    // The streams of the native peers describe themselves, the other ones
+    // are what we agreed on or what the SDP says.
+    let stream_params = match &session {
+        Some(params) => params.clone(),
+        None => net::handshake::Params {
+            codec: codec.name().to_owned(),
+            sample_rate: net_playback_stream_config
+                .sample_rate()
+                .as_usize()
+                .try_into()?,
+            channels: net_playback_stream_config.channels().try_into()?,
+        },
     };
 
     let audio_backend = continuation(capture_data_writer, playback_data_reader)?;
-    run_audio_backend(audio_backend);
+    let audio_backend = run_audio_backend(audio_backend);
 
     let mut transcode_service = transcode_service::TranscodeService {
         capture_transcoder,
         playback_transcoder,
     };
 
+    let peers: net::PeerRegistry<_> = send_addrs.iter().copied().collect();
+
+    // The receivers always report back, so that the senders can adapt if
+    // they want to. Only the native framing can carry the reports.
+    let feedback_enabled = framing == net::Framing::Native;
+    let feedback_reports = net::feedback::Reports::new();
+    let bitrate_controller = if adaptive_bitrate && feedback_enabled {
+        check_bitrate("min", min_bitrate)?;
+        check_bitrate("max", max_bitrate)?;
+        check_bitrate("start", start_bitrate)?;
+        if min_bitrate > max_bitrate {
+            return Err(anyhow::format_err!(
+                "the min bitrate is above the max bitrate"
+            ));
+        }
+        if !(min_bitrate..=max_bitrate).contains(&start_bitrate) {
+            return Err(anyhow::format_err!(
+                "the start bitrate is outside of the min and max bitrates"
+            ));
+        }
+        info!(
+            "Adapting the bitrate within {}..={} bps",
+            min_bitrate, max_bitrate
+        );
+        Some(net::feedback::Controller::new(
+            feedback_reports.clone(),
+            net::feedback::ControllerConfig {
+                min_bitrate,
+                max_bitrate,
+                start_bitrate,
+                min_expected_packet_loss_perc: fec_expected_packet_loss.unwrap_or(0),
+            },
+        ))
+    } else {
+        if adaptive_bitrate {
+            warn!("The bitrate adaptation is not supported with RTP, ignoring");
+        }
+        None
+    };
+
     let mut net_service = net::NetService {
         send_service: net::SendService {
             capture_sample: PhantomData,
             capture_data_reader,
-            encoder: &mut *encoder,
+            encoder,
+            encoder_factory: Some(encoder_factory),
+            codec_switch: codec_switch.clone(),
+            cipher: psk.as_ref().map(net::crypto::Sealer::new),
+            framing,
+            max_payload_size,
             stats: net::SendStats::default(),
+            peer_stats: HashMap::new(),
+            bitrate_controller,
+            announcer: session.clone().map(net::stream::Announcer::new),
+            metrics: metrics_listener.as_ref().map(|_| metrics.clone()),
         },
         recv_service: net::RecvService {
             playback_sample: PhantomData,
             playback_data_writer,
-            decoder: &mut *decoder,
+            decoder_factory,
+            stream_params,
+            jitter_buffer_config,
+            playback_stream_config: net_playback_stream_config,
+            framing,
+            max_payload_size,
+            cipher: psk.as_ref().map(net::crypto::Opener::new),
+            auto_join: auto_join.then(|| net::AutoJoin {
+                peers: peers.clone(),
+                ttl: auto_join_ttl,
+            }),
+            handshake: session.map(|params| net::handshake::Responder {
+                params,
+                cipher: psk.as_ref().map(net::crypto::Sealer::new),
+            }),
+            feedback: feedback_enabled
+                .then(|| net::feedback::Reporter::new(psk.as_ref().map(net::crypto::Sealer::new))),
+            feedback_reports: feedback_enabled.then_some(feedback_reports),
             stats: net::RecvStats::default(),
+            peer_stats: HashMap::new(),
+            metrics: metrics_listener.as_ref().map(|_| metrics.clone()),
         },
     };
 
-    rt.block_on(async {
-        select(
+    let shutdown = Shutdown::new();
+    let result = rt.block_on(async {
+        let mut loops = vec![
             net_service
-                .net_loop(socket, send_addrs)
+                .net_loop(socket, peers.clone())
                 .with_logger(logger().new(o!("logger" => "net")))
                 .boxed(),
             transcode_service
                 .transcode_loop()
                 .with_logger(logger().new(o!("logger" => "transcode")))
                 .boxed(),
-        )
-        .await
-        .factor_first()
-        .0
-    })?;
+        ];
+        if let Some(control_listener) = control_listener {
+            loops.push(
+                control::serve(control_listener, peers, codec_switch, shutdown.clone())
+                    .with_logger(logger().new(o!("logger" => "control")))
+                    .boxed(),
+            );
+        }
+        if let Some(metrics_listener) = metrics_listener {
+            loops.push(
+                metrics::serve(metrics_listener, metrics)
+                    .with_logger(logger().new(o!("logger" => "metrics")))
+                    .boxed(),
+            );
+        }
+        shutdown::run_until_stopped(loops, &shutdown).await
+    });
 
-    unreachable!();
+    // The net and transcode loops are cancelled by now, so we only have
+    // to stop the devices.
+    audio_backend.stop();
+    net_service.flush_stats();
+    result?;
+    info!("Stopped");
+    Ok(())
+}
+
+/// Make sure opus can encode at the `bitrate`, given with the `--<name>-bitrate`
+/// option.
+fn check_bitrate(name: &str, bitrate: u32) -> Result<(), Error> {
+    let supported = netsound_codec_opus::MIN_BITRATE..=netsound_codec_opus::MAX_BITRATE;
+    if !supported.contains(&bitrate) {
+        return Err(anyhow::format_err!(
+            "the {} bitrate of {} bps is outside of the {}..={} bps opus supports",
+            name,
+            bitrate,
+            supported.start(),
+            supported.end()
+        ));
+    }
+    Ok(())
 }
 
 fn main() {
@@ -263,16 +648,32 @@ fn main() {
     }
 }
 
-fn run_audio_backend(audio_backend: Box<dyn Backend + 'static>) {
-    std::thread::spawn(move || {
-        let mut local = audio_backend;
-        futures::executor::block_on(local.run());
-    });
+/// The audio backend running at its own thread.
+#[derive(Debug)]
+struct RunningAudioBackend {
+    stop_tx: futures::channel::oneshot::Sender<()>,
+    thread: std::thread::JoinHandle<()>,
 }
 
-fn buffer<T: Default + Clone>(size: usize) -> Box<[T]> {
-    let mut vec = Vec::with_capacity(size);
-    let cap = vec.capacity();
-    vec.resize(cap, T::default());
-    vec.into()
+impl RunningAudioBackend {
+    /// Stop the backend, and wait until its streams are stopped.
+    fn stop(self) {
+        // The backend has already stopped by itself if this fails.
+        let _ = self.stop_tx.send(());
+        if self.thread.join().is_err() {
+            warn!("The audio backend thread panicked");
+        }
+    }
+}
+
+fn run_audio_backend(audio_backend: Box<dyn Backend + 'static>) -> RunningAudioBackend {
+    let (stop_tx, stop_rx) = futures::channel::oneshot::channel();
+    let thread = std::thread::spawn(move || {
+        let mut local = audio_backend;
+        futures::executor::block_on(async {
+            futures::future::select(local.run(), stop_rx).await;
+        });
+        local.stop();
+    });
+    RunningAudioBackend { stop_tx, thread }
 }
@@ -0,0 +1,136 @@
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    runtime::Runtime,
+};
+
+use netsound_core::{codec, log, metrics, net, pcm, shutdown::Shutdown, Error};
+
+use crate::{cli, codec_config, shutdown};
+use log::{info, logger, o, LogScopeFutureExt};
+
+/// Run the relay until it fails or is asked to stop.
+#[allow(clippy::too_many_lines)]
+pub fn run(params: cli::RelayParams) -> Result<(), Error> {
+    let cli::RelayParams {
+        bind_addr,
+        codec_to_use,
+        sample_rate,
+        channels,
+        psk,
+        max_payload_size,
+        client_timeout,
+        metrics_addr,
+    } = params;
+
+    let codec_to_use = codec_config::find(
+        &codec_config::registry(netsound_codec_opus::Settings::default())?,
+        &codec_to_use,
+    )?;
+    let session = net::handshake::Params {
+        codec: codec_to_use.name().to_owned(),
+        sample_rate,
+        channels,
+    };
+    let offer = net::handshake::Offer {
+        codecs: vec![codec_to_use.offer(u16::MAX)],
+    };
+    if !offer.supports(&session) {
+        return Err(anyhow::format_err!(
+            "the codec doesn't support {}, it supports {}",
+            session,
+            offer
+        ));
+    }
+    info!("Relaying: {}", session);
+
+    let rt = Runtime::new()?;
+    let socket = rt.block_on(UdpSocket::bind(&bind_addr))?;
+    let local_addr = socket.local_addr()?;
+    info!("Listening on: {}", local_addr);
+
+    let metrics_listener = match metrics_addr {
+        Some(metrics_addr) => {
+            let listener = rt.block_on(TcpListener::bind(metrics_addr))?;
+            let local_addr = listener.local_addr()?;
+            info!("Serving metrics on: {}", local_addr);
+            Some(listener)
+        }
+        None => None,
+    };
+    let metrics = metrics::Registry::new();
+
+    if psk.is_some() {
+        info!("Using packet encryption");
+    }
+    let audio_payload_size = net::mtu::audio_payload_size(max_payload_size, psk.is_some())?;
+    info!("Sending up to {} bytes per packet", max_payload_size);
+
+    let sample_rate: usize = sample_rate.try_into()?;
+    let stream_config = pcm::StreamConfig::<f32>::new(sample_rate.into(), channels.into());
+    metrics.add_stream_config("relay", &stream_config);
+
+    let codec_settings = codec::registry::Settings {
+        max_packet_size: audio_payload_size,
+        max_bitrate: None,
+        fec_expected_packet_loss: None,
+    };
+    let frame_samples = codec_to_use.frame_samples(stream_config, &codec_settings);
+    info!("Encoding up to {} samples per packet", frame_samples);
+    let encoder_factory: net::EncoderFactory<'static, codec::registry::DynEncoder> = {
+        let codec_to_use = Arc::clone(&codec_to_use);
+        Box::new(move || codec_to_use.encoder(stream_config, &codec_settings))
+    };
+    // The clients may switch their streams, but only to the ones we mix.
+    let decoder_factory: net::DecoderFactory<'static, codec::registry::DynDecoder> = {
+        let session = session.clone();
+        Box::new(move |params| {
+            if *params != session {
+                return Err(anyhow::format_err!("the relay only mixes {}", session));
+            }
+            codec_to_use.decoder(stream_config, &codec_settings)
+        })
+    };
+
+    let mut relay_service = net::RelayService {
+        params: session,
+        encoder_factory,
+        decoder_factory,
+        jitter_buffer_config: net::JitterBufferConfig::new(
+            stream_config.sample_rate(),
+            stream_config.channels(),
+        ),
+        frame_samples,
+        client_timeout: Duration::from_secs(client_timeout),
+        max_payload_size,
+        sealer: psk.as_ref().map(net::crypto::Sealer::new),
+        opener: psk.as_ref().map(net::crypto::Opener::new),
+        stats: net::RelayStats::default(),
+        client_stats: HashMap::new(),
+        metrics: metrics_listener.as_ref().map(|_| metrics.clone()),
+    };
+
+    let result = rt.block_on(async {
+        let mut loops = vec![relay_service
+            .relay_loop(Arc::new(socket))
+            .with_logger(logger().new(o!("logger" => "relay")))
+            .boxed()];
+        if let Some(metrics_listener) = metrics_listener {
+            loops.push(
+                metrics::serve(metrics_listener, metrics)
+                    .with_logger(logger().new(o!("logger" => "metrics")))
+                    .boxed(),
+            );
+        }
+        shutdown::run_until_stopped(loops, &Shutdown::new()).await
+    });
+
+    relay_service.flush_stats();
+    result?;
+    info!("Stopped");
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use futures::{
+    future::{select, select_all, BoxFuture, Either},
+    never::Never,
+    FutureExt,
+};
+use tokio::signal;
+
+use netsound_core::{log, shutdown::Shutdown, Error};
+
+use log::info;
+
+/// Run the `loops` until either of them fails, the `shutdown` is triggered,
+/// or we're asked to stop by a signal. The loops are cancelled when this
+/// returns.
+pub async fn run_until_stopped(
+    loops: Vec<BoxFuture<'_, Result<Never, Error>>>,
+    shutdown: &Shutdown,
+) -> Result<(), Error> {
+    let stop = async {
+        futures::select! {
+            signal = wait_for_signal().fuse() => {
+                let signal = signal?;
+                info!("Received {}, shutting down", signal);
+            },
+            () = shutdown.triggered().fuse() => info!("Shutting down"),
+        }
+        Ok(())
+    };
+    match select(select_all(loops), stop.boxed()).await {
+        Either::Left(((result, _, _), _)) => result.map(|never| match never {}),
+        Either::Right((result, _)) => result,
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> Result<&'static str, Error> {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    let name = futures::select! {
+        result = signal::ctrl_c().fuse() => result.map(|()| "SIGINT")?,
+        _ = terminate.recv().fuse() => "SIGTERM",
+    };
+    Ok(name)
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> Result<&'static str, Error> {
+    signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}